@@ -0,0 +1,55 @@
+//! Text formats for maps and edit scripts.
+
+use std::{error::Error, path::Path};
+
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Json,
+    Ron,
+    Toml,
+}
+
+impl Format {
+    /// Format of the file at the given path, based on its extension.
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("ron") => Ok(Self::Ron),
+            Some("toml") => Ok(Self::Toml),
+            _ => Err(format!("unknown format of {}", path.display()).into()),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String, Box<dyn Error>> {
+        match self {
+            Self::Json => Ok(serde_json::to_string_pretty(value)?),
+            Self::Ron => Ok(ron::ser::to_string_pretty(value, Default::default())?),
+            Self::Toml => Ok(toml::to_string_pretty(value)?),
+        }
+    }
+
+    /// Like [`Format::serialize`], but on a single line.
+    pub fn serialize_line<T: Serialize>(self, value: &T) -> Result<String, Box<dyn Error>> {
+        match self {
+            Self::Json => Ok(serde_json::to_string(value)?),
+            Self::Ron => Ok(ron::to_string(value)?),
+            Self::Toml => {
+                let mut line = String::new();
+                value.serialize(toml::ser::ValueSerializer::new(&mut line))?;
+
+                Ok(line)
+            }
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(self, s: &str) -> Result<T, Box<dyn Error>> {
+        match self {
+            Self::Json => Ok(serde_json::from_str(s)?),
+            Self::Ron => Ok(ron::from_str(s)?),
+            Self::Toml => Ok(toml::from_str(s)?),
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! Load generator simulating many clients editing a room at once.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    error::Error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::stream;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use shared::{
+    map::MapState, ClientMessage, Edit, Hash, MapDesc, ModelId, NotNan, ObjectDesc, ServerMessage,
+};
+use tokio::{select, task::JoinSet, time};
+
+use crate::session::{edit_key, get_stats, Session};
+
+/// Time connections keep receiving after they stopped editing, so the last edits arrive.
+const DRAIN_DURATION: Duration = Duration::from_secs(2);
+
+/// Half the extent of the area around sampled objects in which copies are placed, in world
+/// units.
+const SPREAD: f32 = 256.0;
+
+pub struct LoadTestParams {
+    pub host: String,
+    pub port: u16,
+    pub room: String,
+    pub connections: usize,
+    /// Edits per second sent by every connection.
+    pub rate: f64,
+    pub duration: Duration,
+    /// Map to sample edits from instead of the current map of the room.
+    pub map_desc: Option<MapDesc>,
+}
+
+/// Edits that were not received by every connection yet, by their key.
+///
+/// Connections never send an edit while the same edit is pending, so every echo belongs to a
+/// single sender. Edits the server rejected are never received and dropped after
+/// [`DRAIN_DURATION`].
+type PendingEdits = Arc<Mutex<HashMap<Hash, PendingEdit>>>;
+
+struct PendingEdit {
+    sender: usize,
+    send_time: Instant,
+    receivers_left: usize,
+}
+
+/// What a single connection observed.
+#[derive(Default)]
+struct ConnectionReport {
+    sent: u64,
+    received: u64,
+    /// Time from sending an edit until this connection received its broadcast.
+    latencies: Vec<Duration>,
+}
+
+pub async fn run(params: LoadTestParams) -> Result<(), Box<dyn Error>> {
+    let idle_stats = get_stats(&params.host, params.port).await?;
+
+    let (mut sessions, samples) = connect(&params).await?;
+
+    if samples.is_empty() {
+        return Err("map has no objects to sample edits from".into());
+    }
+
+    let connected_stats = get_stats(&params.host, params.port).await?;
+
+    eprintln!(
+        "{} connections open, editing for {:?}",
+        sessions.len(),
+        params.duration
+    );
+
+    let samples = Arc::new(samples);
+    let pending_edits = PendingEdits::default();
+    let start = Instant::now();
+    let deadline = start + params.duration;
+
+    let mut tasks = JoinSet::new();
+
+    for (index, session) in sessions.drain(..).enumerate() {
+        tasks.spawn(run_connection(
+            session,
+            index,
+            params.connections,
+            params.rate,
+            Arc::clone(&samples),
+            Arc::clone(&pending_edits),
+            deadline,
+        ));
+    }
+
+    let mut reports = vec![];
+
+    while let Some(result) = tasks.join_next().await {
+        reports.push(result?.map_err(|error| format!("connection failed: {error}"))?);
+    }
+
+    let loaded_stats = get_stats(&params.host, params.port).await?;
+
+    let seconds = params.duration.as_secs_f64();
+    let sent: u64 = reports.iter().map(|report| report.sent).sum();
+    let received: u64 = reports.iter().map(|report| report.received).sum();
+
+    let mut latencies: Vec<_> = reports
+        .into_iter()
+        .flat_map(|report| report.latencies)
+        .collect();
+
+    latencies.sort();
+
+    println!("connections:        {}", params.connections);
+    println!(
+        "edits sent:         {sent} ({:.1}/s)",
+        sent as f64 / seconds
+    );
+    println!(
+        "edits broadcast:    {} ({:.1}/s)",
+        received / params.connections as u64,
+        received as f64 / params.connections as f64 / seconds
+    );
+    println!(
+        "edits received:     {received} ({:.1}/s)",
+        received as f64 / seconds
+    );
+
+    if !latencies.is_empty() {
+        for (name, percentile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            let index = ((latencies.len() - 1) as f64 * percentile).round() as usize;
+
+            println!("fan-out latency {name}: {:?}", latencies[index]);
+        }
+    }
+
+    match (
+        idle_stats.resident_memory,
+        connected_stats.resident_memory,
+        loaded_stats.resident_memory,
+    ) {
+        (Some(idle), Some(connected), Some(loaded)) => {
+            let per_connection = connected.saturating_sub(idle) / params.connections as u64;
+
+            println!("server memory idle:      {} KiB", idle / 1024);
+            println!("server memory connected: {} KiB", connected / 1024);
+            println!("server memory loaded:    {} KiB", loaded / 1024);
+            println!(
+                "server memory per connection: {} KiB",
+                per_connection / 1024
+            );
+        }
+        _ => println!("server memory: not reported by the server"),
+    }
+
+    Ok(())
+}
+
+/// Open all connections and collect the objects to sample edits from.
+async fn connect(
+    params: &LoadTestParams,
+) -> Result<(Vec<Session>, Vec<ObjectDesc>), Box<dyn Error>> {
+    let mut sessions = vec![];
+    let mut room_map_desc = None;
+
+    for index in 0..params.connections {
+        let user_name = format!("load-test-{index}");
+
+        let (session, _, map_desc) =
+            Session::join(&params.host, params.port, &params.room, &user_name).await?;
+
+        sessions.push(session);
+        room_map_desc.get_or_insert(map_desc);
+    }
+
+    let samples = match &params.map_desc {
+        // Custom models of another map are not available in the room.
+        Some(map_desc) => MapState::from_desc(map_desc.clone())
+            .objects()
+            .filter(|object| matches!(object.model_id(), ModelId::Game { .. }))
+            .cloned()
+            .collect(),
+        None => match room_map_desc {
+            Some(map_desc) => MapState::from_desc(map_desc).objects().cloned().collect(),
+            None => vec![],
+        },
+    };
+
+    Ok((sessions, samples))
+}
+
+/// Edit until the deadline, then remove the objects this connection placed.
+async fn run_connection(
+    mut session: Session,
+    index: usize,
+    connections: usize,
+    rate: f64,
+    samples: Arc<Vec<ObjectDesc>>,
+    pending_edits: PendingEdits,
+    deadline: Instant,
+) -> Result<ConnectionReport, String> {
+    let mut rng = StdRng::seed_from_u64(index as u64);
+    let mut report = ConnectionReport::default();
+    // Objects whose placement by this connection was accepted, and not removed since.
+    let mut placed = vec![];
+
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / rate));
+    let drain_deadline = deadline + DRAIN_DURATION;
+
+    loop {
+        select! {
+            _ = interval.tick(), if Instant::now() < deadline => {
+                // Keep the number of placed objects around a few, like an editor trying things out.
+                let edit = if placed.len() > 4 || (!placed.is_empty() && rng.gen_bool(0.4)) {
+                    let index = rng.gen_range(0..placed.len());
+
+                    Edit::Remove(placed.swap_remove(index))
+                } else {
+                    Edit::Place(sample_object(&samples, &mut rng))
+                };
+
+                if !add_pending_edit(&pending_edits, &edit, index, connections) {
+                    // The same edit is pending, so its echo could not be told apart.
+                    if let Edit::Remove(object) = edit {
+                        placed.push(object);
+                    }
+
+                    continue;
+                }
+
+                session
+                    .send(&ClientMessage::Edit(edit))
+                    .await
+                    .map_err(|error| error.to_string())?;
+
+                report.sent += 1;
+            }
+            // Errors are not `Send`, so they must not be part of the output of `select!`.
+            message = async { session.receive().await.map_err(|error| error.to_string()) } => {
+                match message? {
+                    Some(ServerMessage::Edit(edit)) => {
+                        let now = Instant::now();
+                        let key = edit_key(&edit);
+                        let mut pending_edits = pending_edits.lock().unwrap();
+
+                        if let Entry::Occupied(mut entry) = pending_edits.entry(key) {
+                            let pending = entry.get_mut();
+
+                            report.latencies.push(now - pending.send_time);
+
+                            if pending.sender == index {
+                                if let Edit::Place(object) = edit {
+                                    placed.push(object);
+                                }
+                            }
+
+                            pending.receivers_left -= 1;
+
+                            if pending.receivers_left == 0 {
+                                entry.remove();
+                            }
+                        }
+
+                        report.received += 1;
+                    }
+                    Some(_) => {}
+                    None => return Err("connection closed".to_owned()),
+                }
+            }
+            _ = time::sleep_until(drain_deadline.into()) => break,
+        }
+    }
+
+    // Objects only placed by this connection are removed, so objects of the map stay untouched.
+    session
+        .send_all(
+            stream::iter(
+                placed
+                    .into_iter()
+                    .map(|object| ClientMessage::Edit(Edit::Remove(object))),
+            ),
+            |_| {},
+        )
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(report)
+}
+
+/// Record the given edit as sent now, unless the same edit is pending.
+fn add_pending_edit(
+    pending_edits: &PendingEdits,
+    edit: &Edit,
+    sender: usize,
+    connections: usize,
+) -> bool {
+    let now = Instant::now();
+    let mut pending_edits = pending_edits.lock().unwrap();
+
+    pending_edits.retain(|_, pending| now - pending.send_time < DRAIN_DURATION);
+
+    match pending_edits.entry(edit_key(edit)) {
+        Entry::Vacant(entry) => {
+            entry.insert(PendingEdit {
+                sender,
+                send_time: now,
+                receivers_left: connections,
+            });
+
+            true
+        }
+        Entry::Occupied(_) => false,
+    }
+}
+
+/// A copy of a random object of the map, moved to a random nearby location.
+fn sample_object(samples: &[ObjectDesc], rng: &mut impl Rng) -> ObjectDesc {
+    let mut object = samples.choose(rng).expect("samples are not empty").clone();
+
+    match &mut object {
+        ObjectDesc::Block(block) => {
+            block.coord.x = rng.gen_range(1..=48);
+            block.coord.z = rng.gen_range(1..=48);
+        }
+        ObjectDesc::GhostBlock(ghost_block) => {
+            ghost_block.coord.x = rng.gen_range(1..=48);
+            ghost_block.coord.z = rng.gen_range(1..=48);
+        }
+        ObjectDesc::FreeBlock(free_block) => {
+            free_block.position.x += NotNan::new(rng.gen_range(-SPREAD..SPREAD)).unwrap();
+            free_block.position.z += NotNan::new(rng.gen_range(-SPREAD..SPREAD)).unwrap();
+        }
+        ObjectDesc::Item(item) => {
+            item.position.x += NotNan::new(rng.gen_range(-SPREAD..SPREAD)).unwrap();
+            item.position.z += NotNan::new(rng.gen_range(-SPREAD..SPREAD)).unwrap();
+        }
+    }
+
+    object
+}
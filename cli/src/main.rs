@@ -0,0 +1,928 @@
+//! Headless client for scripting sync edit sessions without the game.
+
+mod format;
+mod load_test;
+mod map_text;
+mod patch;
+mod playback;
+mod report;
+mod session;
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use format::Format;
+use futures_util::stream;
+use load_test::LoadTestParams;
+use report::ReportFormat;
+use serde::Serialize;
+use session::{edit_key, get_passthrough, list_rooms, Session};
+use shared::{
+    diff::MapDiff,
+    gbx::{read_macroblock, read_map, read_map_params, restore_passthrough, write_map_params},
+    macroblock::{extract, Selection},
+    map::MapState,
+    merge::merge,
+    patch::{BlockSizes, Patch, Transform},
+    replay::{PlaybackSpeed, Replay},
+    AabbDesc, ClientMessage, Edit, Hash, MapDesc, MapParamsDesc, MedalTimesDesc, Mood,
+    ServerMessage, Vec3,
+};
+use tm_sync_edit_server::{read_log, read_snapshot};
+use tokio::runtime;
+
+#[derive(Parser)]
+#[command(name = "tm-sync-edit-cli")]
+struct Cli {
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    #[arg(long, default_value_t = 8369)]
+    port: u16,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the rooms hosted by the server.
+    Rooms {
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+    },
+    /// Print the current map of a room.
+    Dump {
+        #[command(flatten)]
+        room: RoomArgs,
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+        /// Write the map to this text file instead of printing it, as `export` does.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Send the edits of a JSON or RON script, which is a list of edits, to a room.
+    Apply {
+        #[command(flatten)]
+        room: RoomArgs,
+        script: PathBuf,
+    },
+    /// Print the edits, chat messages and changes to the medal times or validation state of a room
+    /// as they happen, one per line.
+    Tail {
+        #[command(flatten)]
+        room: RoomArgs,
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+    },
+    /// Send a chat message to a room.
+    Say {
+        #[command(flatten)]
+        room: RoomArgs,
+        text: String,
+    },
+    /// Insert a macroblock into a room, turned and moved so that its smallest block coordinate is
+    /// at the given coordinate.
+    ///
+    /// Besides `.Macroblock.Gbx` files, any map `diff` accepts can be inserted as a macroblock.
+    /// Either all of its objects are placed, or none if any of them does not fit, in which case
+    /// the edits that do not apply are reported.
+    Macroblock {
+        #[command(flatten)]
+        room: RoomArgs,
+        macroblock: PathBuf,
+        /// Block coordinate as `x,y,z`.
+        #[arg(long, value_parser = parse_vec3::<i32>)]
+        coord: Vec3<i32>,
+        /// Number of clockwise quarter turns around the vertical axis.
+        #[arg(long, default_value_t = 0)]
+        rotate: u8,
+        #[arg(long, value_enum, default_value = "human")]
+        format: ReportFormat,
+    },
+    /// Extract objects of a room into a macroblock text file, moved so that the given origin
+    /// becomes block coordinate `0,0,0`.
+    ///
+    /// Objects are selected by a box in world units or by their ids, along with the items
+    /// attached to them, and written with the custom objects they use. The result can be inserted
+    /// into other rooms with `macroblock`.
+    Extract {
+        #[command(flatten)]
+        room: RoomArgs,
+        /// Smallest corner of the box to select in world units as `x,y,z`.
+        #[arg(
+            long,
+            value_parser = parse_vec3::<f32>,
+            requires = "max",
+            required_unless_present = "ids"
+        )]
+        min: Option<Vec3<f32>>,
+        /// Largest corner of the box to select in world units as `x,y,z`.
+        #[arg(long, value_parser = parse_vec3::<f32>, requires = "min")]
+        max: Option<Vec3<f32>>,
+        /// Hex ids of the objects to select, separated by commas.
+        #[arg(long, value_delimiter = ',', conflicts_with = "min")]
+        ids: Vec<String>,
+        /// Block coordinate as `x,y,z` to move to the origin, defaults to the smallest coordinate
+        /// covered by the selected objects.
+        #[arg(long, value_parser = parse_vec3::<i32>)]
+        origin: Option<Vec3<i32>>,
+        /// `.json`, `.ron` or `.toml` file to write the macroblock to, see `export`.
+        output: PathBuf,
+    },
+    /// Print the medal times and validation state of a room, after setting the given medal times.
+    ///
+    /// Times are in milliseconds. Setting them does not change whether the map is validated.
+    Medals {
+        #[command(flatten)]
+        room: RoomArgs,
+        #[arg(long)]
+        author: Option<u32>,
+        #[arg(long)]
+        gold: Option<u32>,
+        #[arg(long)]
+        silver: Option<u32>,
+        #[arg(long)]
+        bronze: Option<u32>,
+        #[arg(long, value_enum, default_value = "json")]
+        format: Format,
+    },
+    /// Convert a map to a sorted, line-oriented text file for version control.
+    ///
+    /// The format is given by the extension of the output, which is `json`, `ron` or `toml`.
+    /// Custom blocks and items are written to a folder next to it, named after their hashes.
+    Export {
+        /// `.Map.Gbx` file or text file to convert.
+        map: PathBuf,
+        output: PathBuf,
+    },
+    /// Create a new room from a map, for example an exported text file, so that it can be opened
+    /// and saved in the game.
+    ///
+    /// The medal times and validation state of a `.Map.Gbx` file are kept.
+    Import {
+        #[command(flatten)]
+        room: RoomArgs,
+        map: PathBuf,
+        #[arg(long, value_enum, default_value = "day")]
+        mood: MoodArg,
+    },
+    /// Show the objects added, removed and changed between two maps.
+    ///
+    /// Maps are `.Map.Gbx` files, exported text files or room folders of the server, of which the
+    /// latest snapshot is used.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        #[arg(long, value_enum, default_value = "human")]
+        format: ReportFormat,
+    },
+    /// Combine the changes two maps made to a common base map.
+    ///
+    /// Conflicting changes are resolved in favour of `ours` and reported, in which case the
+    /// command fails after writing the result. The result is written as a text file or hosted in a
+    /// room, which takes the medal times of `ours`. It stays validated only if the merge left the
+    /// objects of `ours` unchanged.
+    Merge {
+        base: PathBuf,
+        ours: PathBuf,
+        theirs: PathBuf,
+        /// Text file to write the merged map to, see `export`.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Create a new room with this name from the merged map.
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, default_value = "cli")]
+        user_name: String,
+        #[arg(long, value_enum, default_value = "day")]
+        mood: MoodArg,
+        #[arg(long, value_enum, default_value = "human")]
+        format: ReportFormat,
+    },
+    /// Put the parts of the source map of a room that the room does not sync, such as the
+    /// thumbnail, back into a map saved from the game.
+    ///
+    /// Only rooms hosted from a `.Map.Gbx` file have these parts. What could not be kept is
+    /// reported. The medal times and validation state of the room are written into the map.
+    Restore {
+        #[arg(long, default_value = "default")]
+        room: String,
+        /// `.Map.Gbx` file saved from the game.
+        saved: PathBuf,
+        output: PathBuf,
+    },
+    /// Record edits as a patch file, or apply one to a map or room.
+    Patch {
+        #[command(subcommand)]
+        command: PatchCommand,
+    },
+    /// Create a new room from the map of a replay recorded by the server, and play back the
+    /// recorded edits into it as a timelapse.
+    ///
+    /// Multiple replays of the same room, one for every time the server hosted it, are played
+    /// back one after the other. The edits are sent as `--user-name`, to keep the original
+    /// authors, play the replay back on the server instead.
+    Replay {
+        #[arg(required = true)]
+        replays: Vec<PathBuf>,
+        #[command(flatten)]
+        room: RoomArgs,
+        /// Factor by which playback is faster than the recording.
+        #[arg(long, default_value_t = 10.0)]
+        speed: f64,
+        /// Longest pause between two edits in seconds, so that breaks are skipped.
+        #[arg(long, default_value_t = 5.0)]
+        max_delay: f64,
+    },
+    /// Simulate many clients editing a room and report how the server copes.
+    LoadTest {
+        /// Room to edit. Objects placed by the load test are removed again at the end, but the
+        /// room is best not in use meanwhile.
+        #[arg(long)]
+        room: String,
+        /// Number of simulated clients.
+        #[arg(long, default_value_t = 16)]
+        connections: usize,
+        /// Edits per second sent by every client.
+        #[arg(long, default_value_t = 2.0)]
+        rate: f64,
+        /// Seconds to keep editing for.
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+        /// Map file to sample edits from instead of the current map of the room.
+        #[arg(long)]
+        map: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PatchCommand {
+    /// Record the edits that turn one map into another.
+    ///
+    /// The format of the patch is given by the extension of the output, which is `json`, `ron` or
+    /// `toml`.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        output: PathBuf,
+    },
+    /// Record a range of edits from the log in a room folder of the server.
+    ///
+    /// The log only reaches back to the latest snapshot of the room.
+    Log {
+        folder: PathBuf,
+        /// Sequence number of the first edit to record.
+        #[arg(long)]
+        from: Option<u64>,
+        /// Sequence number of the first edit to no longer record.
+        #[arg(long)]
+        to: Option<u64>,
+        output: PathBuf,
+    },
+    /// Apply a patch to a map file or a room, moving and rotating its edits first.
+    ///
+    /// Edits that do not apply are skipped and reported, in which case the command fails after
+    /// applying the rest.
+    Apply {
+        patch: PathBuf,
+        /// Map file to apply the patch to, which requires `--output`.
+        #[arg(long, conflicts_with = "room", requires = "output")]
+        map: Option<PathBuf>,
+        /// Text file to write the patched map to.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Room to apply the patch to.
+        #[arg(long, required_unless_present = "map")]
+        room: Option<String>,
+        #[arg(long, default_value = "cli")]
+        user_name: String,
+        /// Offset in blocks as `x,y,z`.
+        #[arg(long, value_parser = parse_vec3::<i32>, default_value = "0,0,0")]
+        offset: Vec3<i32>,
+        /// Number of clockwise quarter turns around the vertical axis.
+        #[arg(long, default_value_t = 0)]
+        rotate: u8,
+        /// Block coordinate as `x,y,z` whose smallest corner to rotate around.
+        #[arg(long, value_parser = parse_vec3::<i32>, default_value = "0,0,0")]
+        pivot: Vec3<i32>,
+        /// Text file with the sizes of blocks larger than a single coordinate when facing north,
+        /// as a table of `x`, `y` and `z` by game block id or custom block hash. Rotated blocks
+        /// not listed are taken to cover a single coordinate.
+        #[arg(long)]
+        block_sizes: Option<PathBuf>,
+        #[arg(long, value_enum, default_value = "human")]
+        format: ReportFormat,
+    },
+}
+
+#[derive(Args)]
+struct RoomArgs {
+    #[arg(long, default_value = "default")]
+    room: String,
+    #[arg(long, default_value = "cli")]
+    user_name: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MoodArg {
+    Day,
+    Sunset,
+    Night,
+    Sunrise,
+}
+
+impl From<MoodArg> for Mood {
+    fn from(mood: MoodArg) -> Self {
+        match mood {
+            MoodArg::Day => Self::Day,
+            MoodArg::Sunset => Self::Sunset,
+            MoodArg::Night => Self::Night,
+            MoodArg::Sunrise => Self::Sunrise,
+        }
+    }
+}
+
+/// A line printed by [`Command::Tail`].
+#[derive(Serialize)]
+enum TailLine<'a> {
+    Edit(&'a Edit),
+    Chat { user_name: &'a str, text: &'a str },
+    MapParams(&'a MapParamsDesc),
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+
+    runtime.block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let Cli {
+        host,
+        port,
+        command,
+    } = cli;
+
+    match command {
+        Command::Rooms { format } => {
+            let rooms = list_rooms(&host, port).await?;
+
+            println!("{}", format.serialize(&rooms)?);
+        }
+        Command::Dump {
+            room,
+            format,
+            output,
+        } => {
+            let (_, _, map_desc) = Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            match output {
+                Some(path) => map_text::write(&path, map_desc)?,
+                None => println!("{}", format.serialize(&map_desc)?),
+            }
+        }
+        Command::Apply { room, script } => {
+            let format = Format::from_path(&script)?;
+            let edits: Vec<Edit> = format.deserialize(&fs::read_to_string(&script)?)?;
+
+            let (mut session, _, _) =
+                Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            let sent: HashSet<_> = edits.iter().map(edit_key).collect();
+            let mut accepted = 0;
+
+            session
+                .send_all(
+                    stream::iter(edits.iter().cloned().map(ClientMessage::Edit)),
+                    |message| {
+                        // Edits that do not apply to the map are dropped by the server. The same
+                        // edit made by another member meanwhile is counted as well.
+                        if let ServerMessage::Edit(edit) = message {
+                            if sent.contains(&edit_key(&edit)) {
+                                accepted += 1;
+                            }
+                        }
+                    },
+                )
+                .await?;
+
+            eprintln!("sent {} edits, {accepted} of them accepted", edits.len());
+        }
+        Command::Tail { room, format } => {
+            let (mut session, _, _) =
+                Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            while let Some(message) = session.receive().await? {
+                let line = match &message {
+                    ServerMessage::Edit(edit) => TailLine::Edit(edit),
+                    ServerMessage::Chat { user_name, text } => TailLine::Chat { user_name, text },
+                    ServerMessage::MapParams(map_params_desc) => {
+                        TailLine::MapParams(map_params_desc)
+                    }
+                    _ => continue,
+                };
+
+                println!("{}", format.serialize_line(&line)?);
+            }
+        }
+        Command::Say { room, text } => {
+            let (mut session, _, _) =
+                Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            session.send(&ClientMessage::Chat { text }).await?;
+
+            // Make sure the message is handled before disconnecting.
+            session.barrier().await?;
+        }
+        Command::Macroblock {
+            room,
+            macroblock,
+            coord,
+            rotate,
+            format,
+        } => {
+            let macroblock = read_macroblock_file(&macroblock)?;
+
+            let coord = Vec3 {
+                x: u8::try_from(coord.x)?,
+                y: u8::try_from(coord.y)?,
+                z: u8::try_from(coord.z)?,
+            };
+
+            let (mut session, _, _) =
+                Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            let mut reply = None;
+
+            session
+                .send_all(
+                    stream::once(async {
+                        ClientMessage::InsertMacroblock {
+                            macroblock: Box::new(macroblock),
+                            coord,
+                            quarter_turns: rotate,
+                        }
+                    }),
+                    |message| {
+                        if let ServerMessage::InsertMacroblock { inserted, failures } = message {
+                            reply = Some((inserted, failures));
+                        }
+                    },
+                )
+                .await?;
+
+            let (inserted, failures) = reply.ok_or("the server did not answer the insert")?;
+
+            report::print_patch_failures(&failures, format)?;
+
+            if !failures.is_empty() {
+                return Err(format!("{} edits of the macroblock fail", failures.len()).into());
+            }
+
+            if !inserted {
+                return Err("the server failed to insert the macroblock".into());
+            }
+
+            eprintln!("inserted macroblock");
+        }
+        Command::Extract {
+            room,
+            min,
+            max,
+            ids,
+            origin,
+            output,
+        } => {
+            check_text_output(&output)?;
+
+            let selection = match (min, max) {
+                (Some(min), Some(max)) => Selection::Region(AabbDesc { min, max }),
+                _ => Selection::Objects(
+                    ids.iter()
+                        .map(Hash::from_hex)
+                        .collect::<Result<HashSet<_>, _>>()?,
+                ),
+            };
+
+            let (_, _, map_desc) = Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            let macroblock = match extract(&MapState::from_desc(map_desc), &selection, origin) {
+                Ok(macroblock) => macroblock,
+                Err(failures) => {
+                    return Err(format!(
+                        "{} selected objects would be below the origin",
+                        failures.len()
+                    )
+                    .into())
+                }
+            };
+
+            let objects = macroblock.blocks.len()
+                + macroblock.ghost_blocks.len()
+                + macroblock.free_blocks.len()
+                + macroblock.items.len();
+
+            map_text::write(&output, macroblock)?;
+
+            eprintln!("extracted {objects} objects");
+        }
+        Command::Medals {
+            room,
+            author,
+            gold,
+            silver,
+            bronze,
+            format,
+        } => {
+            let (mut session, mut map_params_desc, _) =
+                Session::join(&host, port, &room.room, &room.user_name).await?;
+
+            let current = map_params_desc.medal_times;
+
+            let medal_times = MedalTimesDesc {
+                author: author.or(current.author),
+                gold: gold.or(current.gold),
+                silver: silver.or(current.silver),
+                bronze: bronze.or(current.bronze),
+            };
+
+            if medal_times != current {
+                session
+                    .send(&ClientMessage::SetMedalTimes(medal_times))
+                    .await?;
+
+                // Make sure the message is handled before disconnecting.
+                session.barrier().await?;
+
+                map_params_desc.medal_times = medal_times;
+            }
+
+            println!("{}", format.serialize(&map_params_desc)?);
+        }
+        Command::Export { map, output } => {
+            map_text::write(&output, read_map_file(&map)?)?;
+        }
+        Command::Import { room, map, mood } => {
+            let map_desc = read_map_file(&map)?;
+            let map_params_desc = read_map_params_file(&map, mood)?;
+
+            host_room(
+                &host,
+                port,
+                &room.room,
+                &room.user_name,
+                map_params_desc,
+                map_desc,
+            )
+            .await?;
+        }
+        Command::Diff { old, new, format } => {
+            let diff = MapDiff::new(&read_map_file(&old)?, &read_map_file(&new)?);
+
+            report::print_diff(&diff, format)?;
+        }
+        Command::Merge {
+            base,
+            ours,
+            theirs,
+            output,
+            room,
+            user_name,
+            mood,
+            format,
+        } => {
+            if output.is_none() && room.is_none() {
+                return Err("expected --output or --room".into());
+            }
+
+            if let Some(output) = &output {
+                check_text_output(output)?;
+            }
+
+            let ours_desc = read_map_file(&ours)?;
+            let merge = merge(&read_map_file(&base)?, &ours_desc, &read_map_file(&theirs)?);
+
+            report::print_conflicts(&merge.conflicts, format)?;
+
+            if let Some(output) = output {
+                map_text::write(&output, merge.map_desc.clone())?;
+            }
+
+            if let Some(room) = room {
+                let mut map_params_desc = read_map_params_file(&ours, mood)?;
+                map_params_desc.validated &= MapDiff::new(&ours_desc, &merge.map_desc).is_empty();
+
+                host_room(
+                    &host,
+                    port,
+                    &room,
+                    &user_name,
+                    map_params_desc,
+                    merge.map_desc,
+                )
+                .await?;
+            }
+
+            if !merge.conflicts.is_empty() {
+                return Err(format!(
+                    "{} conflicts, resolved in favour of ours",
+                    merge.conflicts.len()
+                )
+                .into());
+            }
+        }
+        Command::Restore {
+            room,
+            saved,
+            output,
+        } => {
+            let (map_params_desc, passthrough) = get_passthrough(&host, port, &room).await?;
+
+            let passthrough = passthrough
+                .ok_or_else(|| format!("room {room:?} was not created from a map file"))?;
+
+            let bytes = write_map_params(&fs::read(&saved)?, &map_params_desc)?;
+            let (bytes, report) = restore_passthrough(&bytes, &passthrough)?;
+
+            eprintln!("{report}");
+
+            fs::write(&output, bytes)?;
+        }
+        Command::Patch { command } => run_patch(&host, port, command).await?,
+        Command::Replay {
+            replays,
+            room,
+            speed,
+            max_delay,
+        } => {
+            if !(speed > 0.0 && speed.is_finite() && max_delay >= 0.0 && max_delay.is_finite()) {
+                return Err(
+                    "speed must be positive and max delay must not be negative, both finite".into(),
+                );
+            }
+
+            let speed = PlaybackSpeed {
+                speed,
+                max_delay: Duration::from_secs_f64(max_delay),
+            };
+
+            let replays = replays
+                .iter()
+                .map(Replay::read)
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let header = replays[0].header.clone();
+
+            let mut session = Session::host(
+                &host,
+                port,
+                &room.room,
+                &room.user_name,
+                header.map_params_desc,
+                header.map_desc,
+            )
+            .await?;
+
+            eprintln!("created room {:?}", room.room);
+
+            for replay in replays {
+                eprintln!(
+                    "playing back {} edits recorded over {:?}",
+                    replay.edits.len(),
+                    replay.duration()
+                );
+
+                playback::play(&mut session, replay.edits, speed).await?;
+            }
+        }
+        Command::LoadTest {
+            room,
+            connections,
+            rate,
+            duration,
+            map,
+        } => {
+            if connections == 0 || rate <= 0.0 {
+                return Err("connections and rate must be positive".into());
+            }
+
+            let map_desc = match map {
+                Some(path) => Some(read_map_file(&path)?),
+                None => None,
+            };
+
+            load_test::run(LoadTestParams {
+                host,
+                port,
+                room,
+                connections,
+                rate,
+                duration: Duration::from_secs(duration),
+                map_desc,
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_patch(host: &str, port: u16, command: PatchCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        PatchCommand::Diff { old, new, output } => {
+            let diff = MapDiff::new(&read_map_file(&old)?, &read_map_file(&new)?);
+
+            write_patch(&output, &Patch::from_diff(&diff))?;
+        }
+        PatchCommand::Log {
+            folder,
+            from,
+            to,
+            output,
+        } => {
+            let edits = read_log(&folder)?
+                .into_iter()
+                .filter(|entry| from.is_none_or(|from| entry.seq >= from))
+                .filter(|entry| to.is_none_or(|to| entry.seq < to))
+                .map(|entry| entry.edit)
+                .collect();
+
+            write_patch(&output, &Patch { edits })?;
+        }
+        PatchCommand::Apply {
+            patch,
+            map,
+            output,
+            room,
+            user_name,
+            offset,
+            rotate,
+            pivot,
+            block_sizes,
+            format,
+        } => {
+            let patch: Patch =
+                Format::from_path(&patch)?.deserialize(&fs::read_to_string(&patch)?)?;
+
+            let block_sizes = match block_sizes {
+                Some(path) => Format::from_path(&path)?.deserialize(&fs::read_to_string(&path)?)?,
+                None => BlockSizes::new(),
+            };
+
+            let transform = Transform {
+                offset,
+                quarter_turns: rotate % 4,
+                pivot,
+                block_sizes,
+            };
+
+            let failures = match (map, output, room) {
+                (Some(map), Some(output), _) => {
+                    check_text_output(&output)?;
+
+                    let mut map = MapState::from_desc(read_map_file(&map)?);
+
+                    let outcome = patch.apply(&mut map, &transform);
+
+                    map_text::write(&output, map.to_desc())?;
+
+                    outcome.failures
+                }
+                (_, _, Some(room)) => {
+                    let (mut session, _, map_desc) =
+                        Session::join(host, port, &room, &user_name).await?;
+
+                    patch::apply_to_room(&mut session, map_desc, &patch, &transform).await?
+                }
+                _ => return Err("expected --map and --output, or --room".into()),
+            };
+
+            report::print_patch_failures(&failures, format)?;
+
+            if !failures.is_empty() {
+                return Err(
+                    format!("{} of {} edits failed", failures.len(), patch.edits.len()).into(),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_patch(path: &Path, patch: &Patch) -> Result<(), Box<dyn Error>> {
+    fs::write(path, Format::from_path(path)?.serialize(patch)?)?;
+
+    eprintln!("wrote {} edits", patch.edits.len());
+
+    Ok(())
+}
+
+/// Parse a vector written as `x,y,z`.
+fn parse_vec3<T: FromStr>(text: &str) -> Result<Vec3<T>, String>
+where
+    T::Err: Display,
+{
+    let components = text
+        .split(',')
+        .map(|component| component.trim().parse::<T>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| error.to_string())?;
+
+    match <[T; 3]>::try_from(components) {
+        Ok([x, y, z]) => Ok(Vec3 { x, y, z }),
+        Err(_) => Err("expected three components as x,y,z".into()),
+    }
+}
+
+/// Create a new room from the given map.
+async fn host_room(
+    host: &str,
+    port: u16,
+    room: &str,
+    user_name: &str,
+    map_params_desc: MapParamsDesc,
+    map_desc: MapDesc,
+) -> Result<(), Box<dyn Error>> {
+    Session::host(host, port, room, user_name, map_params_desc, map_desc).await?;
+
+    eprintln!("created room {room:?}");
+
+    Ok(())
+}
+
+/// Read the parameters of a map from a `.Map.Gbx` file with the given mood. Other maps have no
+/// medal times and are not validated.
+fn read_map_params_file(path: &Path, mood: MoodArg) -> Result<MapParamsDesc, Box<dyn Error>> {
+    let mut map_params_desc = if is_gbx(path) && !path.is_dir() {
+        read_map_params(path)?
+    } else {
+        MapParamsDesc {
+            mood: Mood::Day,
+            medal_times: MedalTimesDesc::default(),
+            validated: false,
+        }
+    };
+
+    map_params_desc.mood = mood.into();
+
+    Ok(map_params_desc)
+}
+
+/// Read a map from a `.Gbx` file, a text file written by [`Command::Export`] or the snapshot in a
+/// room folder.
+fn read_map_file(path: &Path) -> Result<MapDesc, Box<dyn Error>> {
+    if path.is_dir() {
+        return Ok(read_snapshot(path)?);
+    }
+
+    if is_gbx(path) {
+        return read_map(path);
+    }
+
+    map_text::read(path)
+}
+
+/// Fail unless the given path is a text file that [`map_text::write`] can write, which `.Gbx`
+/// files are not.
+fn check_text_output(path: &Path) -> Result<(), Box<dyn Error>> {
+    if is_gbx(path) {
+        return Err(format!(
+            "cannot write {}, .Gbx files cannot be written, use a .json, .ron or .toml file",
+            path.display()
+        )
+        .into());
+    }
+
+    Format::from_path(path)?;
+
+    Ok(())
+}
+
+fn is_gbx(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("gbx"))
+}
+
+/// Read a macroblock from a `.Macroblock.Gbx` file, or any map [`read_map_file`] reads.
+fn read_macroblock_file(path: &Path) -> Result<MapDesc, Box<dyn Error>> {
+    let is_macroblock = path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .is_some_and(|file_name| file_name.to_lowercase().ends_with(".macroblock.gbx"));
+
+    if is_macroblock {
+        return read_macroblock(path);
+    }
+
+    read_map_file(path)
+}
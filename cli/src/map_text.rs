@@ -0,0 +1,602 @@
+//! Stable, line-oriented text representation of maps, meant for keeping maps in version control.
+//!
+//! Objects are sorted and written one per line, so that moving or recolouring an object changes a
+//! single line. Custom blocks, items and skins and the files they depend on are referenced by hash,
+//! with their contents stored in side files in a folder next to the text file.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use shared::{
+    hash, offzone, serialize, BlockDesc, CustomBlockDesc, CustomItemDesc, DependencyDesc,
+    EmbeddedFileDesc, FreeBlockDesc, GhostBlockDesc, Hash, ItemDesc, MapDesc, ModelId, NotNan,
+    ObjectDesc, OffzoneDesc,
+};
+
+use crate::format::Format;
+
+/// Text document of a map, with custom objects replaced by their hashes.
+#[derive(Serialize, Deserialize)]
+struct MapText {
+    #[serde(default)]
+    custom_blocks: Vec<CustomObjectText>,
+    #[serde(default)]
+    custom_items: Vec<CustomObjectText>,
+    #[serde(default)]
+    custom_skins: Vec<CustomObjectText>,
+    #[serde(default)]
+    embedded_files: Vec<CustomObjectText>,
+    #[serde(default)]
+    blocks: Vec<BlockDesc>,
+    #[serde(default)]
+    ghost_blocks: Vec<GhostBlockDesc>,
+    #[serde(default)]
+    free_blocks: Vec<FreeBlockDesc>,
+    #[serde(default)]
+    items: Vec<ItemDesc>,
+    #[serde(default)]
+    offzones: Vec<OffzoneDesc>,
+}
+
+/// Custom object or embedded file in a text document, with its contents replaced by its hash.
+#[derive(Serialize, Deserialize)]
+struct CustomObjectText {
+    hash: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dependencies: Vec<CustomObjectText>,
+}
+
+/// Write the given map to the given text file, in the format given by its extension.
+///
+/// Side files that are no longer used by the map are removed.
+pub fn write(path: &Path, mut map_desc: MapDesc) -> Result<(), Box<dyn Error>> {
+    let format = Format::from_path(path)?;
+
+    sort(&mut map_desc);
+
+    let mut side_files = SideFiles {
+        folder: custom_folder(path),
+        used: HashSet::new(),
+    };
+
+    let custom_blocks = map_desc
+        .custom_blocks
+        .iter()
+        .map(|custom_block| {
+            side_files.write_object(
+                &custom_block.path,
+                &custom_block.bytes,
+                &custom_block.dependencies,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let custom_items = map_desc
+        .custom_items
+        .iter()
+        .map(|custom_item| {
+            side_files.write_object(
+                &custom_item.path,
+                &custom_item.bytes,
+                &custom_item.dependencies,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let custom_skins = map_desc
+        .custom_skins
+        .iter()
+        .map(|custom_skin| side_files.write_object(&custom_skin.path, &custom_skin.bytes, &[]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let embedded_files = map_desc
+        .embedded_files
+        .iter()
+        .map(|embedded_file| {
+            side_files.write_object(&embedded_file.path, &embedded_file.bytes, &[])
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    side_files.remove_unused()?;
+
+    let mut text = Text::new(format);
+
+    text.section("custom_blocks", &custom_blocks)?;
+    text.section("custom_items", &custom_items)?;
+    text.section("custom_skins", &custom_skins)?;
+    text.section("embedded_files", &embedded_files)?;
+    text.section("blocks", &map_desc.blocks)?;
+    text.section("ghost_blocks", &map_desc.ghost_blocks)?;
+    text.section("free_blocks", &map_desc.free_blocks)?;
+    text.section("items", &map_desc.items)?;
+    text.section("offzones", &map_desc.offzones)?;
+
+    fs::write(path, text.finish())?;
+
+    Ok(())
+}
+
+/// Read a map from the given text file, in the format given by its extension.
+pub fn read(path: &Path) -> Result<MapDesc, Box<dyn Error>> {
+    let format = Format::from_path(path)?;
+    let map_text: MapText = format.deserialize(&fs::read_to_string(path)?)?;
+
+    let folder = custom_folder(path);
+
+    let custom_blocks = map_text
+        .custom_blocks
+        .iter()
+        .map(|custom_block| {
+            let (bytes, dependencies) = read_object(&folder, custom_block)?;
+
+            Ok(CustomBlockDesc {
+                path: custom_block.path.clone(),
+                bytes,
+                dependencies,
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    let custom_items = map_text
+        .custom_items
+        .iter()
+        .map(|custom_item| {
+            let (bytes, dependencies) = read_object(&folder, custom_item)?;
+
+            Ok(CustomItemDesc {
+                path: custom_item.path.clone(),
+                bytes,
+                dependencies,
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    let custom_skins = map_text
+        .custom_skins
+        .iter()
+        .map(|custom_skin| {
+            Ok(EmbeddedFileDesc {
+                path: custom_skin.path.clone(),
+                bytes: read_object(&folder, custom_skin)?.0,
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    let embedded_files = map_text
+        .embedded_files
+        .iter()
+        .map(|embedded_file| {
+            Ok(EmbeddedFileDesc {
+                path: embedded_file.path.clone(),
+                bytes: read_object(&folder, embedded_file)?.0,
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    Ok(MapDesc {
+        custom_blocks,
+        custom_items,
+        custom_skins,
+        embedded_files,
+        blocks: map_text.blocks,
+        ghost_blocks: map_text.ghost_blocks,
+        free_blocks: map_text.free_blocks,
+        items: map_text.items,
+        offzones: map_text.offzones,
+    })
+}
+
+/// Sort all parts of the given map into a stable order.
+///
+/// Objects are grouped by model and then ordered by position, so that related objects end up
+/// close to each other.
+pub fn sort(map_desc: &mut MapDesc) {
+    map_desc
+        .custom_blocks
+        .sort_by_cached_key(|custom_block| custom_block.hash().to_hex());
+    map_desc
+        .custom_items
+        .sort_by_cached_key(|custom_item| custom_item.hash().to_hex());
+    map_desc
+        .custom_skins
+        .sort_by_cached_key(|custom_skin| custom_skin.hash().to_hex());
+    map_desc
+        .embedded_files
+        .sort_by_cached_key(|embedded_file| embedded_file.hash().to_hex());
+
+    sort_objects(&mut map_desc.blocks, ObjectDesc::Block);
+    sort_objects(&mut map_desc.ghost_blocks, ObjectDesc::GhostBlock);
+    sort_objects(&mut map_desc.free_blocks, ObjectDesc::FreeBlock);
+    sort_objects(&mut map_desc.items, ObjectDesc::Item);
+
+    map_desc.offzones = offzone::normalize(&map_desc.offzones);
+}
+
+fn sort_objects<T: Clone>(objects: &mut [T], to_object: fn(T) -> ObjectDesc) {
+    objects.sort_by_cached_key(|object| {
+        let object = to_object(object.clone());
+        let position = object.position();
+
+        let model = match object.model_id() {
+            ModelId::Game { id } => id.clone(),
+            ModelId::Custom { hash } => hash.to_hex().to_string(),
+        };
+
+        let position = [position.x, position.y, position.z]
+            .map(|x| NotNan::new(x).expect("positions are never NaN"));
+
+        // The serialized object breaks ties between objects at the same position.
+        let bytes = serialize(&object).expect("object descriptions are always serializable");
+
+        (model, position, bytes)
+    });
+}
+
+/// Folder containing the side files of the text file at the given path.
+fn custom_folder(path: &Path) -> PathBuf {
+    path.with_extension("custom")
+}
+
+/// Side files of a text file that are written or still in use.
+struct SideFiles {
+    folder: PathBuf,
+    /// Names of the side files used by the map.
+    used: HashSet<String>,
+}
+
+impl SideFiles {
+    /// Write the given custom object unless it is stored already.
+    ///
+    /// Its dependencies are only referenced, as they are written as embedded files of the map.
+    fn write_object(
+        &mut self,
+        path: &str,
+        bytes: &[u8],
+        dependencies: &[DependencyDesc],
+    ) -> Result<CustomObjectText, Box<dyn Error>> {
+        let dependencies = dependencies
+            .iter()
+            .map(|dependency| CustomObjectText {
+                hash: dependency.hash.to_hex().to_string(),
+                path: dependency.path.clone(),
+                dependencies: vec![],
+            })
+            .collect();
+
+        let hash = hash(bytes).to_hex().to_string();
+        let file_name = side_file_name(&hash, path);
+        let file_path = self.folder.join(&file_name);
+
+        if !file_path.exists() {
+            fs::create_dir_all(&self.folder)?;
+            fs::write(&file_path, bytes)?;
+        }
+
+        self.used.insert(file_name);
+
+        Ok(CustomObjectText {
+            hash,
+            path: path.to_owned(),
+            dependencies,
+        })
+    }
+
+    fn remove_unused(&self) -> Result<(), Box<dyn Error>> {
+        if !self.folder.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&self.folder)? {
+            let entry = entry?;
+
+            let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+
+            // Files not named after a hash are not side files, so they are left alone.
+            let is_side_file = file_name
+                .get(..64)
+                .is_some_and(|hex| Hash::from_hex(hex).is_ok());
+
+            if is_side_file && !self.used.contains(&file_name) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read a custom object from its side file, along with the references to its dependencies.
+fn read_object(
+    folder: &Path,
+    custom_object: &CustomObjectText,
+) -> Result<(Vec<u8>, Vec<DependencyDesc>), Box<dyn Error>> {
+    let dependencies = custom_object
+        .dependencies
+        .iter()
+        .map(|dependency| {
+            Ok(DependencyDesc {
+                path: dependency.path.clone(),
+                hash: Hash::from_hex(&dependency.hash)?,
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    let bytes = read_side_file(folder, &custom_object.hash, &custom_object.path)?;
+
+    Ok((bytes, dependencies))
+}
+
+fn read_side_file(folder: &Path, hex: &str, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let expected_hash = Hash::from_hex(hex)?;
+    let path = folder.join(side_file_name(hex, path));
+
+    let bytes =
+        fs::read(&path).map_err(|error| format!("failed to read {}: {error}", path.display()))?;
+
+    if hash(&bytes) != expected_hash {
+        return Err(format!("{} does not match its hash", path.display()).into());
+    }
+
+    Ok(bytes)
+}
+
+/// Name of the side file of an embedded file with the given hash and path, which keeps the
+/// extension of the file, such as `.Item.Gbx`.
+fn side_file_name(hex: &str, path: &str) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+
+    match file_name.find('.') {
+        Some(index) => format!("{hex}{}", &file_name[index..]),
+        None => hex.to_owned(),
+    }
+}
+
+/// Writer of a text document consisting of named lists, with one list element per line.
+struct Text {
+    format: Format,
+    text: String,
+    is_empty: bool,
+}
+
+impl Text {
+    fn new(format: Format) -> Self {
+        let text = match format {
+            Format::Json => "{\n",
+            Format::Ron => "(\n",
+            Format::Toml => "",
+        };
+
+        Self {
+            format,
+            text: text.to_owned(),
+            is_empty: true,
+        }
+    }
+
+    fn section<T: Serialize>(&mut self, name: &str, elements: &[T]) -> Result<(), Box<dyn Error>> {
+        let (section_indent, element_indent) = match self.format {
+            Format::Json => ("  ", "    "),
+            Format::Ron => ("    ", "        "),
+            Format::Toml => ("", "    "),
+        };
+
+        match self.format {
+            Format::Json => {
+                // JSON forbids trailing commas, so separators are written between sections.
+                if !self.is_empty {
+                    self.text.push_str(",\n");
+                }
+
+                self.text
+                    .push_str(&format!("{section_indent}\"{name}\": ["));
+            }
+            Format::Ron => self.text.push_str(&format!("{section_indent}{name}: [")),
+            Format::Toml => self.text.push_str(&format!("{name} = [")),
+        }
+
+        for (index, element) in elements.iter().enumerate() {
+            self.text.push('\n');
+            self.text.push_str(element_indent);
+            self.text.push_str(&self.format.serialize_line(element)?);
+
+            if index + 1 < elements.len() || !matches!(self.format, Format::Json) {
+                self.text.push(',');
+            }
+        }
+
+        if !elements.is_empty() {
+            self.text.push('\n');
+            self.text.push_str(section_indent);
+        }
+
+        match self.format {
+            Format::Json => self.text.push(']'),
+            Format::Ron => self.text.push_str("],\n"),
+            Format::Toml => self.text.push_str("]\n"),
+        }
+
+        self.is_empty = false;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> String {
+        match self.format {
+            Format::Json => self.text.push_str("\n}\n"),
+            Format::Ron => self.text.push_str(")\n"),
+            Format::Toml => {}
+        }
+
+        self.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gamebox::engines::game::map::{ElemColor, PhaseOffset};
+    use shared::{map::MapState, Vec3};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn texture(bytes: &[u8]) -> EmbeddedFileDesc {
+        EmbeddedFileDesc {
+            path: "Items/Texture.dds".to_owned(),
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    fn custom_item(bytes: &[u8], texture: &EmbeddedFileDesc) -> CustomItemDesc {
+        CustomItemDesc {
+            path: "Items/Custom.Item.Gbx".to_owned(),
+            bytes: bytes.to_vec(),
+            dependencies: vec![DependencyDesc {
+                path: texture.path.clone(),
+                hash: texture.hash(),
+            }],
+        }
+    }
+
+    fn item(model_id: ModelId, x: f32) -> ItemDesc {
+        let position = Vec3 {
+            x: NotNan::new(x).unwrap(),
+            y: NotNan::default(),
+            z: NotNan::default(),
+        };
+
+        ItemDesc {
+            item_model_id: model_id,
+            position,
+            yaw: NotNan::default(),
+            pitch: NotNan::default(),
+            roll: NotNan::default(),
+            pivot_position: position,
+            elem_color: ElemColor::Default,
+            anim_offset: PhaseOffset::None,
+            waypoint: None,
+            skin: None,
+            attachment: None,
+        }
+    }
+
+    fn test_map() -> MapDesc {
+        let texture = texture(b"texture");
+        let custom_item = custom_item(b"item", &texture);
+
+        MapDesc {
+            items: vec![
+                item(
+                    ModelId::Custom {
+                        hash: custom_item.hash(),
+                    },
+                    64.0,
+                ),
+                item(
+                    ModelId::Game {
+                        id: "Flag".to_owned(),
+                    },
+                    32.0,
+                ),
+            ],
+            custom_items: vec![custom_item],
+            embedded_files: vec![texture],
+            offzones: vec![
+                OffzoneDesc {
+                    min: Vec3 { x: 2, y: 0, z: 0 },
+                    max: Vec3 { x: 3, y: 0, z: 0 },
+                },
+                OffzoneDesc {
+                    min: Vec3 { x: 0, y: 0, z: 0 },
+                    max: Vec3 { x: 1, y: 0, z: 0 },
+                },
+            ],
+            ..MapDesc::default()
+        }
+    }
+
+    #[test]
+    fn maps_survive_a_round_trip_in_every_format() {
+        let folder = TempDir::new().unwrap();
+
+        for extension in ["json", "ron", "toml"] {
+            let path = folder.path().join(format!("map.{extension}"));
+
+            write(&path, test_map()).unwrap();
+
+            let map_desc = read(&path).unwrap();
+
+            assert!(MapState::from_desc(map_desc) == MapState::from_desc(test_map()));
+        }
+    }
+
+    #[test]
+    fn writing_is_stable() {
+        let folder = TempDir::new().unwrap();
+        let path = folder.path().join("map.json");
+
+        let mut reversed = test_map();
+        reversed.items.reverse();
+        reversed.offzones.reverse();
+
+        write(&path, test_map()).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+
+        write(&path, reversed).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+    }
+
+    #[test]
+    fn unused_side_files_are_removed() {
+        let folder = TempDir::new().unwrap();
+        let path = folder.path().join("map.json");
+
+        write(&path, test_map()).unwrap();
+
+        let mut map_desc = test_map();
+        let texture = texture(b"other texture");
+        map_desc.custom_items = vec![custom_item(b"item", &texture)];
+        map_desc.embedded_files = vec![texture];
+
+        write(&path, map_desc).unwrap();
+
+        let mut side_files: Vec<_> = fs::read_dir(custom_folder(&path))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+
+        side_files.sort();
+
+        let mut expected = vec![
+            side_file_name(&hash(b"item").to_hex(), "Custom.Item.Gbx"),
+            side_file_name(&hash(b"other texture").to_hex(), "Texture.dds"),
+        ];
+
+        expected.sort();
+
+        assert_eq!(side_files, expected);
+    }
+
+    #[test]
+    fn side_files_that_do_not_match_their_hash_are_refused() {
+        let folder = TempDir::new().unwrap();
+        let path = folder.path().join("map.json");
+
+        write(&path, test_map()).unwrap();
+
+        let side_file =
+            custom_folder(&path).join(side_file_name(&hash(b"item").to_hex(), "Custom.Item.Gbx"));
+
+        fs::write(side_file, b"changed").unwrap();
+
+        assert!(read(&path).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+//! Applying patches to live rooms.
+
+use std::{collections::HashSet, error::Error};
+
+use futures_util::stream;
+use shared::{
+    map::MapState,
+    patch::{Patch, PatchFailure, PatchFailureReason, Transform},
+    ClientMessage, MapDesc, ServerMessage,
+};
+
+use crate::session::{edit_key, Session};
+
+/// Apply the given patch to the room of the given session, whose map is `map_desc`.
+///
+/// Edits are checked against the map first, so that failing edits are not sent at all. Edits the
+/// server drops anyway, because another member changed the map at the same time, are reported as
+/// well.
+pub async fn apply_to_room(
+    session: &mut Session,
+    map_desc: MapDesc,
+    patch: &Patch,
+    transform: &Transform,
+) -> Result<Vec<PatchFailure>, Box<dyn Error>> {
+    let mut map = MapState::from_desc(map_desc);
+
+    let mut outcome = patch.apply(&mut map, transform);
+
+    let mut accepted = HashSet::new();
+
+    // The server echoes every edit it accepts.
+    session
+        .send_all(
+            stream::iter(
+                outcome
+                    .applied
+                    .iter()
+                    .map(|(_, edit)| ClientMessage::Edit(edit.clone())),
+            ),
+            |message| {
+                if let ServerMessage::Edit(edit) = message {
+                    accepted.insert(edit_key(&edit));
+                }
+            },
+        )
+        .await?;
+
+    for (index, edit) in outcome.applied {
+        if !accepted.contains(&edit_key(&edit)) {
+            outcome.failures.push(PatchFailure {
+                index,
+                edit: patch.edits[index].clone(),
+                reason: PatchFailureReason::NotAccepted,
+            });
+        }
+    }
+
+    outcome.failures.sort_by_key(|failure| failure.index);
+
+    Ok(outcome.failures)
+}
@@ -0,0 +1,41 @@
+//! Playing back replays into rooms from a client.
+
+use std::error::Error;
+
+use futures_util::{stream, StreamExt};
+use shared::{
+    replay::{PlaybackSpeed, ReplayEdit},
+    ClientMessage,
+};
+use tokio::time::{self, Instant};
+
+use crate::session::Session;
+
+/// Send the given recorded edits to the room of the given session at the given speed, and wait
+/// until the server handled all of them.
+pub async fn play(
+    session: &mut Session,
+    edits: Vec<ReplayEdit>,
+    speed: PlaybackSpeed,
+) -> Result<(), Box<dyn Error>> {
+    // Deadlines are accumulated rather than sleeping for every delay, so that time spent sending
+    // edits does not slow down playback.
+    let mut deadline = Instant::now();
+
+    let edits = edits
+        .into_iter()
+        .map(move |ReplayEdit { delay, edit, .. }| {
+            deadline += speed.delay(delay);
+
+            (deadline, edit)
+        });
+
+    let messages = stream::iter(edits).then(|(deadline, edit)| async move {
+        time::sleep_until(deadline).await;
+
+        ClientMessage::Edit(edit)
+    });
+
+    // The echoes of the edits are not needed, but have to be received.
+    session.send_all(messages, |_| {}).await
+}
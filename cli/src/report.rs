@@ -0,0 +1,283 @@
+//! Printing of map differences, merge conflicts and patch failures.
+
+use std::error::Error;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use shared::{
+    diff::{MapDiff, ObjectChange, ObjectModification},
+    merge::{ConflictKind, MergeConflict},
+    patch::PatchFailure,
+    Edit, Hash, ModelId, ObjectDesc, OffzoneDesc,
+};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    Human,
+    Json,
+}
+
+/// JSON representation of a [`MapDiff`], with custom objects replaced by their hashes.
+#[derive(Serialize)]
+struct DiffReport<'a> {
+    custom_blocks_added: Vec<String>,
+    custom_blocks_removed: Vec<String>,
+    custom_items_added: Vec<String>,
+    custom_items_removed: Vec<String>,
+    custom_skins_added: Vec<String>,
+    custom_skins_removed: Vec<String>,
+    embedded_files_added: Vec<String>,
+    embedded_files_removed: Vec<String>,
+    added: &'a [ObjectDesc],
+    removed: &'a [ObjectDesc],
+    modified: &'a [ObjectModification],
+    offzones_added: &'a [OffzoneDesc],
+    offzones_removed: &'a [OffzoneDesc],
+}
+
+pub fn print_diff(diff: &MapDiff, format: ReportFormat) -> Result<(), Box<dyn Error>> {
+    match format {
+        ReportFormat::Human => print_human(diff),
+        ReportFormat::Json => {
+            let report = DiffReport {
+                custom_blocks_added: hashes(diff.custom_blocks_added.iter().map(|c| c.hash())),
+                custom_blocks_removed: hashes(diff.custom_blocks_removed.iter().map(|c| c.hash())),
+                custom_items_added: hashes(diff.custom_items_added.iter().map(|c| c.hash())),
+                custom_items_removed: hashes(diff.custom_items_removed.iter().map(|c| c.hash())),
+                custom_skins_added: hashes(diff.custom_skins_added.iter().map(|c| c.hash())),
+                custom_skins_removed: hashes(diff.custom_skins_removed.iter().map(|c| c.hash())),
+                embedded_files_added: hashes(diff.embedded_files_added.iter().map(|f| f.hash())),
+                embedded_files_removed: hashes(
+                    diff.embedded_files_removed.iter().map(|f| f.hash()),
+                ),
+                added: &diff.added,
+                removed: &diff.removed,
+                modified: &diff.modified,
+                offzones_added: &diff.offzones_added,
+                offzones_removed: &diff.offzones_removed,
+            };
+
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn hashes(hashes: impl Iterator<Item = Hash>) -> Vec<String> {
+    hashes.map(|hash| hash.to_hex().to_string()).collect()
+}
+
+fn print_human(diff: &MapDiff) {
+    for custom_block in &diff.custom_blocks_added {
+        println!("+ custom block {}", custom_block.hash());
+    }
+
+    for custom_block in &diff.custom_blocks_removed {
+        println!("- custom block {}", custom_block.hash());
+    }
+
+    for custom_item in &diff.custom_items_added {
+        println!("+ custom item {}", custom_item.hash());
+    }
+
+    for custom_item in &diff.custom_items_removed {
+        println!("- custom item {}", custom_item.hash());
+    }
+
+    for custom_skin in &diff.custom_skins_added {
+        println!(
+            "+ custom skin {} ({})",
+            custom_skin.hash(),
+            custom_skin.path
+        );
+    }
+
+    for custom_skin in &diff.custom_skins_removed {
+        println!(
+            "- custom skin {} ({})",
+            custom_skin.hash(),
+            custom_skin.path
+        );
+    }
+
+    for embedded_file in &diff.embedded_files_added {
+        println!(
+            "+ embedded file {} ({})",
+            embedded_file.hash(),
+            embedded_file.path
+        );
+    }
+
+    for embedded_file in &diff.embedded_files_removed {
+        println!(
+            "- embedded file {} ({})",
+            embedded_file.hash(),
+            embedded_file.path
+        );
+    }
+
+    for offzone in &diff.offzones_removed {
+        println!("- {}", describe_offzone(offzone));
+    }
+
+    for offzone in &diff.offzones_added {
+        println!("+ {}", describe_offzone(offzone));
+    }
+
+    for object in &diff.removed {
+        println!("- {}", describe(object));
+    }
+
+    for object in &diff.added {
+        println!("+ {}", describe(object));
+    }
+
+    for modification in &diff.modified {
+        let changes: Vec<_> = modification
+            .changes
+            .iter()
+            .map(|change| match change {
+                ObjectChange::Moved => {
+                    format!("moved to {}", location(&modification.new))
+                }
+                ObjectChange::Rotated => "rotated".to_owned(),
+                ObjectChange::Recoloured => "recoloured".to_owned(),
+                ObjectChange::Waypoint => "waypoint changed".to_owned(),
+                ObjectChange::Reskinned => "reskinned".to_owned(),
+                ObjectChange::Reanimated => "re-animated".to_owned(),
+                ObjectChange::Reattached => "reattached".to_owned(),
+                ObjectChange::Other => "changed".to_owned(),
+            })
+            .collect();
+
+        println!("~ {}: {}", describe(&modification.old), changes.join(", "));
+    }
+
+    println!(
+        "{} added, {} removed, {} modified",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len()
+    );
+}
+
+pub fn print_conflicts(
+    conflicts: &[MergeConflict],
+    format: ReportFormat,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        ReportFormat::Human => {
+            let describe_side = |object: &Option<ObjectDesc>| match object {
+                Some(object) => describe(object),
+                None => "removed".to_owned(),
+            };
+
+            for conflict in conflicts {
+                let kind = match conflict.kind {
+                    ConflictKind::ModifiedDifferently => "modified differently",
+                    ConflictKind::ModifiedAndRemoved => "modified and removed",
+                    ConflictKind::SameCoord => "same coord",
+                };
+
+                println!("! conflict ({kind})");
+
+                if let Some(base) = &conflict.base {
+                    println!("    base:   {}", describe(base));
+                }
+
+                println!("    ours:   {}", describe_side(&conflict.ours));
+                println!("    theirs: {}", describe_side(&conflict.theirs));
+
+                for item in &conflict.removed_items {
+                    println!("    removed with it: {}", describe(item));
+                }
+            }
+        }
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(conflicts)?),
+    }
+
+    Ok(())
+}
+
+pub fn print_patch_failures(
+    failures: &[PatchFailure],
+    format: ReportFormat,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        ReportFormat::Human => {
+            for failure in failures {
+                println!(
+                    "! edit {} ({}): {}",
+                    failure.index,
+                    describe_edit(&failure.edit),
+                    failure.reason
+                );
+            }
+        }
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(failures)?),
+    }
+
+    Ok(())
+}
+
+pub fn describe_edit(edit: &Edit) -> String {
+    match edit {
+        Edit::AddCustomBlock(custom_block) => format!("add custom block {}", custom_block.hash()),
+        Edit::AddCustomItem(custom_item) => format!("add custom item {}", custom_item.hash()),
+        Edit::AddCustomSkin(custom_skin) => format!("add custom skin {}", custom_skin.hash()),
+        Edit::AddEmbeddedFile(embedded_file) => {
+            format!("add embedded file {}", embedded_file.hash())
+        }
+        Edit::Place(object) => format!("place {}", describe(object)),
+        Edit::Remove(object) => format!("remove {}", describe(object)),
+        Edit::Modify { old, new } => format!("change {} into {}", describe(old), describe(new)),
+        Edit::AddOffzone(offzone) => format!("add {}", describe_offzone(offzone)),
+        Edit::RemoveOffzone(offzone) => format!("remove {}", describe_offzone(offzone)),
+    }
+}
+
+/// Short description of an offzone box, such as `offzone from (1, 9, 3) to (2, 10, 3)`.
+pub fn describe_offzone(offzone: &OffzoneDesc) -> String {
+    let OffzoneDesc { min, max } = offzone;
+
+    format!(
+        "offzone from ({}, {}, {}) to ({}, {}, {})",
+        min.x, min.y, min.z, max.x, max.y, max.z
+    )
+}
+
+/// Short description of an object, such as `block RoadTechStraight at (1, 9, 3)`.
+pub fn describe(object: &ObjectDesc) -> String {
+    let kind = match object {
+        ObjectDesc::Block(_) => "block",
+        ObjectDesc::GhostBlock(_) => "ghost block",
+        ObjectDesc::FreeBlock(_) => "free block",
+        ObjectDesc::Item(_) => "item",
+    };
+
+    let model = match object.model_id() {
+        ModelId::Game { id } => id.clone(),
+        ModelId::Custom { hash } => format!("custom {}", &hash.to_hex()[..8]),
+    };
+
+    format!("{kind} {model} at {}", location(object))
+}
+
+/// Block coordinate or world position of an object.
+fn location(object: &ObjectDesc) -> String {
+    match object {
+        ObjectDesc::Block(block) => {
+            format!("({}, {}, {})", block.coord.x, block.coord.y, block.coord.z)
+        }
+        ObjectDesc::GhostBlock(ghost_block) => format!(
+            "({}, {}, {})",
+            ghost_block.coord.x, ghost_block.coord.y, ghost_block.coord.z
+        ),
+        ObjectDesc::FreeBlock(_) | ObjectDesc::Item(_) => {
+            let position = object.position();
+
+            format!("({}, {}, {})", position.x, position.y, position.z)
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Connection to a room on a sync edit server.
+
+use std::error::Error;
+
+use futures_util::{future, stream, SinkExt, Stream, StreamExt, TryStreamExt};
+use shared::{
+    deserialize, framed_tcp_stream, hash, serialize, Bytes, ClientMessage, Edit, FramedTcpStream,
+    Handshake, HandshakeResponse, Hash, MapDesc, MapParamsDesc, PassthroughDesc, RoomDesc,
+    ServerMessage, ServerStatsDesc,
+};
+use tokio::net::TcpStream;
+
+/// A client that joined a room.
+pub struct Session {
+    framed_tcp_stream: FramedTcpStream,
+}
+
+impl Session {
+    /// Join the given room, returning the session together with the current map of the room.
+    pub async fn join(
+        host: &str,
+        port: u16,
+        room: &str,
+        user_name: &str,
+    ) -> Result<(Self, MapParamsDesc, MapDesc), Box<dyn Error>> {
+        let mut framed_tcp_stream = connect(host, port).await?;
+
+        let handshake = Handshake::Join {
+            room: room.to_owned(),
+            user_name: user_name.to_owned(),
+        };
+
+        framed_tcp_stream
+            .send(Bytes::from(serialize(&handshake)?))
+            .await?;
+
+        let frame = framed_tcp_stream
+            .try_next()
+            .await?
+            .ok_or("connection closed")?;
+
+        let map_params_desc = match deserialize(&frame)? {
+            HandshakeResponse::Joined(map_params_desc) => map_params_desc,
+            HandshakeResponse::UnknownRoom => return Err(format!("unknown room {room:?}").into()),
+            _ => return Err("unexpected handshake response".into()),
+        };
+
+        let frame = framed_tcp_stream
+            .try_next()
+            .await?
+            .ok_or("connection closed")?;
+
+        let map_desc = deserialize(&frame)?;
+
+        Ok((Self { framed_tcp_stream }, map_params_desc, map_desc))
+    }
+
+    /// Create a new room from the given map and join it.
+    pub async fn host(
+        host: &str,
+        port: u16,
+        room: &str,
+        user_name: &str,
+        map_params_desc: MapParamsDesc,
+        map_desc: MapDesc,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut framed_tcp_stream = connect(host, port).await?;
+
+        let handshake = Handshake::Host {
+            room: room.to_owned(),
+            user_name: user_name.to_owned(),
+            map_params_desc,
+            map_desc: Box::new(map_desc),
+            passthrough: None,
+        };
+
+        framed_tcp_stream
+            .send(Bytes::from(serialize(&handshake)?))
+            .await?;
+
+        let frame = framed_tcp_stream
+            .try_next()
+            .await?
+            .ok_or("connection closed")?;
+
+        match deserialize(&frame)? {
+            HandshakeResponse::Hosted => Ok(Self { framed_tcp_stream }),
+            HandshakeResponse::RoomExists => Err(format!("room {room:?} already exists").into()),
+            HandshakeResponse::InvalidRoomName => Err(format!("invalid room name {room:?}").into()),
+            HandshakeResponse::HostFailed => Err("server failed to create the room".into()),
+            _ => Err("unexpected handshake response".into()),
+        }
+    }
+
+    pub async fn send(&mut self, message: &ClientMessage) -> Result<(), Box<dyn Error>> {
+        self.framed_tcp_stream
+            .send(Bytes::from(serialize(message)?))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Wait for the next message, or `None` if the server closed the connection.
+    pub async fn receive(&mut self) -> Result<Option<ServerMessage>, Box<dyn Error>> {
+        match self.framed_tcp_stream.try_next().await? {
+            Some(frame) => Ok(Some(deserialize(&frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Send the given messages, passing every message received meanwhile to `receive`, and wait
+    /// until the server handled all of them.
+    ///
+    /// Receiving while sending keeps the server from blocking on a socket filled with echoed edits
+    /// that this client does not read, which would keep it from reading further messages as well.
+    pub async fn send_all(
+        &mut self,
+        messages: impl Stream<Item = ClientMessage>,
+        mut receive: impl FnMut(ServerMessage),
+    ) -> Result<(), Box<dyn Error>> {
+        let (mut sink, mut frames) = (&mut self.framed_tcp_stream).split();
+
+        let send = async {
+            let mut messages =
+                Box::pin(messages.chain(stream::once(async { ClientMessage::Barrier })));
+
+            while let Some(message) = messages.next().await {
+                sink.send(Bytes::from(serialize(&message)?)).await?;
+            }
+
+            Ok::<_, Box<dyn Error>>(())
+        };
+
+        let receive = async {
+            loop {
+                let frame = frames.try_next().await?.ok_or("connection closed")?;
+
+                match deserialize(&frame)? {
+                    ServerMessage::Barrier => return Ok::<_, Box<dyn Error>>(()),
+                    message => receive(message),
+                }
+            }
+        };
+
+        future::try_join(send, receive).await?;
+
+        Ok(())
+    }
+
+    /// Wait until the server handled all messages sent before, skipping any received messages.
+    pub async fn barrier(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send_all(stream::empty(), |_| {}).await
+    }
+}
+
+/// Key identifying an edit, to match edits sent with their broadcasts.
+pub fn edit_key(edit: &Edit) -> Hash {
+    hash(&serialize(edit).expect("edits are always serializable"))
+}
+
+/// Request the rooms hosted by the server.
+pub async fn list_rooms(host: &str, port: u16) -> Result<Vec<RoomDesc>, Box<dyn Error>> {
+    match request(host, port, &Handshake::ListRooms).await? {
+        HandshakeResponse::Rooms(rooms) => Ok(rooms),
+        _ => Err("unexpected handshake response".into()),
+    }
+}
+
+/// Request the resource usage of the server.
+pub async fn get_stats(host: &str, port: u16) -> Result<ServerStatsDesc, Box<dyn Error>> {
+    match request(host, port, &Handshake::GetStats).await? {
+        HandshakeResponse::Stats(stats) => Ok(stats),
+        _ => Err("unexpected handshake response".into()),
+    }
+}
+
+/// Request the map parameters of a room and the parts of its source map file that the room does
+/// not model.
+pub async fn get_passthrough(
+    host: &str,
+    port: u16,
+    room: &str,
+) -> Result<(MapParamsDesc, Option<PassthroughDesc>), Box<dyn Error>> {
+    let handshake = Handshake::GetPassthrough {
+        room: room.to_owned(),
+    };
+
+    match request(host, port, &handshake).await? {
+        HandshakeResponse::Passthrough {
+            map_params_desc,
+            passthrough,
+        } => Ok((map_params_desc, passthrough)),
+        HandshakeResponse::UnknownRoom => Err(format!("unknown room {room:?}").into()),
+        _ => Err("unexpected handshake response".into()),
+    }
+}
+
+/// Send a handshake that the server answers without joining a room.
+async fn request(
+    host: &str,
+    port: u16,
+    handshake: &Handshake,
+) -> Result<HandshakeResponse, Box<dyn Error>> {
+    let mut framed_tcp_stream = connect(host, port).await?;
+
+    framed_tcp_stream
+        .send(Bytes::from(serialize(handshake)?))
+        .await?;
+
+    let frame = framed_tcp_stream
+        .try_next()
+        .await?
+        .ok_or("connection closed")?;
+
+    Ok(deserialize(&frame)?)
+}
+
+async fn connect(host: &str, port: u16) -> Result<FramedTcpStream, Box<dyn Error>> {
+    let tcp_stream = TcpStream::connect((host, port)).await?;
+
+    Ok(framed_tcp_stream(tcp_stream))
+}
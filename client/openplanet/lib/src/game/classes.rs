@@ -34,6 +34,10 @@ impl<T: Inherits<Parent = Nod>> NodRef<T> {
         Self { ptr }
     }
 
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
     pub fn cast_mut<U: Class + Inherits<Parent = Nod>>(&mut self) -> Option<&mut NodRef<U>> {
         if self.parent().is_instance_of::<U>() {
             unsafe { Some(&mut *(self as *mut Self as *mut NodRef<U>)) }
@@ -390,8 +394,8 @@ autopad! {
             param_9: u32,
             param_10: u32,
             param_11: u32,
-            param_12: u32,
-            param_13: u32,
+            variant_index: u32,
+            subvariant_index: u32,
             is_air_variant: u32,
             param_15: u32,
             param_16: usize,
@@ -427,6 +431,8 @@ impl EditorCommon {
         coord: Vec3<u8>,
         dir: Direction,
         is_air_variant: bool,
+        variant_index: u8,
+        subvariant_index: u8,
         elem_color: ElemColor,
     ) -> Option<NodRef<Block>> {
         if self.can_place_block(block_info, coord, dir) {
@@ -445,8 +451,8 @@ impl EditorCommon {
                     0xffffffff,
                     1,
                     1,
-                    0,
-                    0,
+                    variant_index as u32,
+                    subvariant_index as u32,
                     is_air_variant as u32,
                     1,
                     0,
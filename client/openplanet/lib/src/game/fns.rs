@@ -148,8 +148,8 @@ type PlaceBlockFnType = unsafe extern "system" fn(
     param_9: u32,
     param_10: u32,
     param_11: u32,
-    param_12: u32,
-    param_13: u32,
+    variant_index: u32,
+    subvariant_index: u32,
     param_14: u32,
     param_15: u32,
     param_16: u32,
@@ -169,6 +169,7 @@ impl PlaceBlockFn {
         Some(Self(f))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub unsafe fn call(
         &self,
         this: &mut EditorCommon,
@@ -176,6 +177,8 @@ impl PlaceBlockFn {
         coord: Vec3<u8>,
         dir: Direction,
         is_air_variant: bool,
+        variant_index: u8,
+        subvariant_index: u8,
         elem_color: ElemColor,
     ) -> Option<NodRef<Block>> {
         let mut coord = [coord.x as u32, coord.y as u32, coord.z as u32];
@@ -191,8 +194,8 @@ impl PlaceBlockFn {
             0,
             0xffffffff,
             0,
-            0,
-            0,
+            variant_index as u32,
+            subvariant_index as u32,
             0,
             0,
             0,
@@ -14,23 +14,23 @@ mod game {
 use std::{
     collections::HashMap,
     error::Error,
-    ffi::{c_char, CStr},
+    ffi::{c_char, CStr, CString},
     fs,
     future::{poll_fn, Future},
     mem,
     net::{IpAddr, SocketAddr},
     panic,
-    path::Path,
+    path::{Component, Path, PathBuf},
     pin::Pin,
     str::FromStr,
     task::Poll,
 };
 
 use async_compat::CompatExt;
-use futures::{executor::block_on, poll, TryStreamExt};
+use futures::{executor::block_on, poll, SinkExt, TryStreamExt};
 use game::{
-    BackToMainMenuFn, Block, BlockInfo, EditNewMap2Fn, EditorCommon, FidsFolder,
-    GenerateBlockInfoFn, ItemModel, LoadFidFileFn, ManiaPlanet, Menus, NodRef, PlaceBlockFn,
+    BackToMainMenuFn, Block, BlockInfo, EditNewMap2Fn, EditorCommon, FidFile, FidsFolder,
+    GenerateBlockInfoFn, ItemModel, LoadFidFileFn, ManiaPlanet, Menus, Nod, NodRef, PlaceBlockFn,
     PlaceItemFn,
 };
 use gamebox::{
@@ -39,8 +39,11 @@ use gamebox::{
 };
 use process::Process;
 use shared::{
-    deserialize, framed_tcp_stream, hash, FramedTcpStream, Hash, MapDesc, MapParamsDesc, ModelId,
-    Mood,
+    deserialize, framed_tcp_stream,
+    gbx::{read_map, read_map_params, read_passthrough},
+    serialize, Bytes, DependencyDesc, EmbeddedFileDesc, FramedTcpStream, Handshake,
+    HandshakeResponse, Hash, MapDesc, MapParamsDesc, ModelId, Mood, SkinDesc, SkinFileDesc,
+    WaypointDesc,
 };
 use tokio::net::TcpStream;
 
@@ -48,6 +51,7 @@ use tokio::net::TcpStream;
 extern "system" fn Init(
     mania_planet: NodRef<ManiaPlanet>,
     program_data_folder: NodRef<FidsFolder>,
+    user_folder: *const c_char,
 ) -> *mut Context {
     panic::set_hook(Box::new(|panic_info| {
         let _ = native_dialog::MessageDialog::new()
@@ -57,7 +61,9 @@ extern "system" fn Init(
             .show_alert();
     }));
 
-    let context = Context::new(mania_planet, program_data_folder);
+    let user_folder = unsafe { CStr::from_ptr(user_folder).to_str().unwrap().into() };
+
+    let context = Context::new(mania_planet, program_data_folder, user_folder);
 
     Box::into_raw(Box::new(context))
 }
@@ -67,25 +73,83 @@ unsafe extern "system" fn Destroy(context: *mut Context) {
     drop(Box::from_raw(context));
 }
 
+/// Poll the connection, returning whether the map was built since the last update, in which case
+/// the plugin applies the properties of the placed objects that the library cannot set itself.
 #[no_mangle]
-extern "system" fn Update(context: &mut Context) {
+extern "system" fn Update(context: &mut Context) -> bool {
     block_on(async {
         if let Some(connection_future) = &mut context.connection_future {
             if poll!(connection_future).is_ready() {
                 context.connection_future = None;
             }
         }
-    })
+    });
+
+    mem::take(&mut context.map_built)
+}
+
+/// Waypoint property of the given block or item of the built map, as its tag and order separated
+/// by a tab, or an empty string if it has none.
+#[no_mangle]
+extern "system" fn Waypoint(context: &mut Context, nod: *const Nod) -> *const c_char {
+    match context.object_properties.get(&(nod as usize)) {
+        Some(properties) => properties.waypoint.as_ptr(),
+        None => c"".as_ptr(),
+    }
 }
 
+/// Skin of the given block or item of the built map, as the paths of its foreground and background
+/// files separated by a tab, or an empty string if it has none.
 #[no_mangle]
-extern "system" fn Join(context: &mut Context, host: *const c_char, port: *const c_char) {
+extern "system" fn Skin(context: &mut Context, nod: *const Nod) -> *const c_char {
+    match context.object_properties.get(&(nod as usize)) {
+        Some(properties) => properties.skin.as_ptr(),
+        None => c"".as_ptr(),
+    }
+}
+
+#[no_mangle]
+extern "system" fn Join(
+    context: &mut Context,
+    host: *const c_char,
+    port: *const c_char,
+    room: *const c_char,
+    user_name: *const c_char,
+) {
     let context_ref = unsafe { &mut *(context as *mut Context) };
 
     let host = unsafe { CStr::from_ptr(host).to_str().unwrap().to_owned() };
     let port = unsafe { CStr::from_ptr(port).to_str().unwrap().to_owned() };
+    let room = unsafe { CStr::from_ptr(room).to_str().unwrap().to_owned() };
+    let user_name = unsafe { CStr::from_ptr(user_name).to_str().unwrap().to_owned() };
+
+    context.connection_future = Some(Box::pin(connection(
+        context_ref,
+        host,
+        port,
+        room,
+        user_name,
+    )));
+}
 
-    context.connection_future = Some(Box::pin(connection(context_ref, host, port)));
+#[no_mangle]
+extern "system" fn Host(
+    context: &mut Context,
+    host: *const c_char,
+    port: *const c_char,
+    room: *const c_char,
+    user_name: *const c_char,
+    map_path: *const c_char,
+) {
+    let host = unsafe { CStr::from_ptr(host).to_str().unwrap().to_owned() };
+    let port = unsafe { CStr::from_ptr(port).to_str().unwrap().to_owned() };
+    let room = unsafe { CStr::from_ptr(room).to_str().unwrap().to_owned() };
+    let user_name = unsafe { CStr::from_ptr(user_name).to_str().unwrap().to_owned() };
+    let map_path = unsafe { CStr::from_ptr(map_path).to_str().unwrap().to_owned() };
+
+    context.connection_future = Some(Box::pin(host_connection(
+        host, port, room, user_name, map_path,
+    )));
 }
 
 type ConnectionFuture = dyn Future<Output = Result<(), Box<dyn Error>>>;
@@ -93,17 +157,78 @@ type ConnectionFuture = dyn Future<Output = Result<(), Box<dyn Error>>>;
 struct Context {
     mania_planet: NodRef<ManiaPlanet>,
     program_data_folder: NodRef<FidsFolder>,
+    /// Folder of the game in the documents of the user, which contains the skins.
+    user_folder: PathBuf,
     connection_future: Option<Pin<Box<ConnectionFuture>>>,
     framed_tcp_stream: Option<FramedTcpStream>,
+    /// Whether the map was built since the last update.
+    map_built: bool,
+    /// Properties of the objects of the built map by their address.
+    object_properties: HashMap<usize, ObjectProperties>,
 }
 
 impl Context {
-    fn new(mania_planet: NodRef<ManiaPlanet>, program_data_folder: NodRef<FidsFolder>) -> Self {
+    fn new(
+        mania_planet: NodRef<ManiaPlanet>,
+        program_data_folder: NodRef<FidsFolder>,
+        user_folder: PathBuf,
+    ) -> Self {
         Self {
             mania_planet,
             program_data_folder,
+            user_folder,
             connection_future: None,
             framed_tcp_stream: None,
+            map_built: false,
+            object_properties: HashMap::new(),
+        }
+    }
+}
+
+/// Properties of a placed object that the plugin sets through the game API, as their layout is not
+/// known here.
+struct ObjectProperties {
+    /// See [`Waypoint`].
+    waypoint: CString,
+    /// See [`Skin`].
+    skin: CString,
+}
+
+impl ObjectProperties {
+    /// Properties of an object with the given waypoint and skin, referring to the custom skin files
+    /// of the map by the given paths.
+    fn new(
+        waypoint: &Option<WaypointDesc>,
+        skin: &Option<Box<SkinDesc>>,
+        custom_skin_paths: &HashMap<Hash, String>,
+    ) -> Self {
+        let waypoint = waypoint
+            .as_ref()
+            .map(|waypoint| format!("{}\t{}", waypoint.tag, waypoint.order))
+            .unwrap_or_default();
+
+        let skin_file_path = |skin_file: &Option<SkinFileDesc>| match skin_file {
+            Some(SkinFileDesc::External { path, .. }) => path.clone(),
+            Some(SkinFileDesc::Custom { hash }) => {
+                custom_skin_paths.get(hash).cloned().unwrap_or_default()
+            }
+            None => String::new(),
+        };
+
+        let skin = skin
+            .as_ref()
+            .map(|skin| {
+                format!(
+                    "{}\t{}",
+                    skin_file_path(&skin.foreground),
+                    skin_file_path(&skin.background)
+                )
+            })
+            .unwrap_or_default();
+
+        Self {
+            waypoint: CString::new(waypoint).unwrap_or_default(),
+            skin: CString::new(skin).unwrap_or_default(),
         }
     }
 }
@@ -112,17 +237,21 @@ async fn connection(
     context: &mut Context,
     host: String,
     port: String,
+    room: String,
+    user_name: String,
 ) -> Result<(), Box<dyn Error>> {
-    let ip_addr = IpAddr::from_str(&host)?;
-    let port = u16::from_str(&port)?;
-    let socket_addr = SocketAddr::new(ip_addr, port);
+    let mut framed_tcp_stream = connect(&host, &port).await?;
 
-    let tcp_stream = TcpStream::connect(socket_addr).compat().await?;
-
-    let mut framed_tcp_stream = framed_tcp_stream(tcp_stream);
+    let frame = serialize(&Handshake::Join { room, user_name })?;
+    framed_tcp_stream.send(Bytes::from(frame)).await?;
 
     let frame = framed_tcp_stream.try_next().await?.unwrap();
-    let map_params_desc: MapParamsDesc = deserialize(&frame)?;
+
+    let map_params_desc = match deserialize(&frame)? {
+        HandshakeResponse::Joined(map_params_desc) => map_params_desc,
+        HandshakeResponse::UnknownRoom => return Err("Unknown room".into()),
+        _ => return Err("Unexpected handshake response".into()),
+    };
 
     open_map_editor(context, map_params_desc).await?;
 
@@ -176,6 +305,8 @@ async fn connection(
     )
     .unwrap();
 
+    let custom_skin_paths = write_custom_skins(&context.user_folder, &map_desc.custom_skins)?;
+
     let editor_common = get_map_editor(context).unwrap();
 
     let place_block_fn = PlaceBlockFn::find(&main_module_memory).unwrap();
@@ -201,6 +332,8 @@ async fn connection(
             block_desc.coord,
             block_desc.dir,
             block_desc.is_air_variant,
+            block_desc.variant_index,
+            block_desc.subvariant_index,
             block_desc.elem_color,
             place_block_fn,
         );
@@ -223,6 +356,8 @@ async fn connection(
             ghost_block_desc.coord,
             ghost_block_desc.dir,
             ghost_block_desc.is_air_variant,
+            ghost_block_desc.variant_index,
+            ghost_block_desc.subvariant_index,
             ghost_block_desc.elem_color,
         );
 
@@ -290,11 +425,98 @@ async fn connection(
         }
     }
 
+    let properties = |waypoint, skin| ObjectProperties::new(waypoint, skin, &custom_skin_paths);
+
+    let block_properties = blocks
+        .iter()
+        .map(|(block_desc, block)| {
+            let properties = properties(&block_desc.waypoint, &block_desc.skin);
+            (block.as_ptr() as usize, properties)
+        })
+        .chain(ghost_blocks.iter().map(|(ghost_block_desc, block)| {
+            let properties = properties(&ghost_block_desc.waypoint, &ghost_block_desc.skin);
+            (block.as_ptr() as usize, properties)
+        }))
+        .chain(free_blocks.iter().map(|(free_block_desc, block)| {
+            let properties = properties(&free_block_desc.waypoint, &free_block_desc.skin);
+            (block.as_ptr() as usize, properties)
+        }));
+    let item_properties = items.iter().map(|(item_desc, item)| {
+        let properties = properties(&item_desc.waypoint, &item_desc.skin);
+        (item.as_ptr() as usize, properties)
+    });
+
+    context.object_properties = block_properties.chain(item_properties).collect();
+    context.map_built = true;
+
+    while framed_tcp_stream.try_next().await?.is_some() {}
+
+    Ok(())
+}
+
+/// Create a room from the map open in the editor, which the plugin saved to the given file right
+/// before.
+async fn host_connection(
+    host: String,
+    port: String,
+    room: String,
+    user_name: String,
+    map_path: String,
+) -> Result<(), Box<dyn Error>> {
+    let map_desc = read_map(&map_path)?;
+    let map_params_desc = read_map_params(&map_path)?;
+
+    // The room is still worth hosting without the parts of the map that are not described.
+    let passthrough = read_passthrough(&map_path)
+        .ok()
+        .map(|(passthrough, _)| passthrough);
+
+    let mut framed_tcp_stream = connect(&host, &port).await?;
+
+    let frame = serialize(&Handshake::Host {
+        room,
+        user_name,
+        map_params_desc,
+        map_desc: Box::new(map_desc),
+        passthrough,
+    })?;
+
+    framed_tcp_stream.send(Bytes::from(frame)).await?;
+
+    let frame = framed_tcp_stream.try_next().await?.unwrap();
+
+    match deserialize(&frame)? {
+        HandshakeResponse::Hosted => {}
+        HandshakeResponse::RoomExists => return Err("Room already exists".into()),
+        HandshakeResponse::InvalidRoomName => return Err("Invalid room name".into()),
+        HandshakeResponse::HostFailed => return Err("Server failed to create room".into()),
+        _ => return Err("Unexpected handshake response".into()),
+    }
+
     while framed_tcp_stream.try_next().await?.is_some() {}
 
     Ok(())
 }
 
+async fn connect(host: &str, port: &str) -> Result<FramedTcpStream, Box<dyn Error>> {
+    let ip_addr = IpAddr::from_str(host)?;
+    let port = u16::from_str(port)?;
+    let socket_addr = SocketAddr::new(ip_addr, port);
+
+    let tcp_stream = TcpStream::connect(socket_addr).compat().await?;
+
+    Ok(framed_tcp_stream(tcp_stream))
+}
+
+fn mood_name(mood: Mood) -> &'static str {
+    match mood {
+        Mood::Day => "Day",
+        Mood::Sunset => "Sunset",
+        Mood::Night => "Night",
+        Mood::Sunrise => "Sunrise",
+    }
+}
+
 async fn open_map_editor(
     context: &mut Context,
     params: MapParamsDesc,
@@ -318,14 +540,7 @@ async fn open_map_editor(
 
         if let Some(current_module) = module_stack.last() {
             if current_module.is_instance_of::<Menus>() {
-                let mood_name = match params.mood {
-                    Mood::Day => "Day",
-                    Mood::Sunset => "Sunset",
-                    Mood::Night => "Night",
-                    Mood::Sunrise => "Sunrise",
-                };
-
-                let decoration_id = format!("48x48Screen155{mood_name}");
+                let decoration_id = format!("48x48Screen155{}", mood_name(params.mood));
 
                 unsafe {
                     edit_new_map_2_fn.call(
@@ -441,32 +656,44 @@ fn load_custom_objects_using_folder(
     generate_block_info_fn: GenerateBlockInfoFn,
     folder: &Path,
 ) -> Result<(), Box<dyn Error>> {
-    let mut custom_block_hashes = vec![];
-
-    for custom_block in &map_desc.custom_blocks {
-        let hash = hash(&custom_block.bytes);
-
-        let mut file_path = folder.to_owned();
-        file_path.push(hash.to_hex().as_str());
-        file_path.set_extension("Block.Gbx");
+    let embedded_files: HashMap<Hash, &[u8]> = map_desc
+        .embedded_files
+        .iter()
+        .map(|embedded_file| (embedded_file.hash(), embedded_file.bytes.as_slice()))
+        .collect();
 
-        fs::write(&file_path, &custom_block.bytes)?;
+    let mut custom_block_files = vec![];
 
-        custom_block_hashes.push(hash);
+    for custom_block in &map_desc.custom_blocks {
+        let hash = custom_block.hash();
+
+        let file_path = write_custom_object(
+            folder,
+            hash,
+            &custom_block.path,
+            &custom_block.bytes,
+            &custom_block.dependencies,
+            &embedded_files,
+        )?;
+
+        custom_block_files.push((hash, file_path));
     }
 
-    let mut custom_item_hashes = vec![];
+    let mut custom_item_files = vec![];
 
     for custom_item in &map_desc.custom_items {
-        let hash = hash(&custom_item.bytes);
-
-        let mut file_path = folder.to_owned();
-        file_path.push(hash.to_hex().as_str());
-        file_path.set_extension("Item.Gbx");
-
-        fs::write(&file_path, &custom_item.bytes)?;
-
-        custom_item_hashes.push(hash);
+        let hash = custom_item.hash();
+
+        let file_path = write_custom_object(
+            folder,
+            hash,
+            &custom_item.path,
+            &custom_item.bytes,
+            &custom_item.dependencies,
+            &embedded_files,
+        )?;
+
+        custom_item_files.push((hash, file_path));
     }
 
     context.program_data_folder.update_tree(false);
@@ -478,16 +705,11 @@ fn load_custom_objects_using_folder(
         .find(|folder| &*folder.path == "SyncEdit")
         .unwrap();
 
-    sync_edit_folder.update_tree(false);
+    sync_edit_folder.update_tree(true);
 
-    for hash in custom_block_hashes {
-        let file_name = format!("{}.Block.Gbx", hash.to_hex());
-
-        let file = sync_edit_folder
-            .leaves
-            .iter_mut()
-            .find(|file| *file.name == *file_name)
-            .unwrap();
+    for (hash, file_path) in custom_block_files {
+        let file =
+            find_fid_file(sync_edit_folder, &file_path).ok_or("missing custom block file")?;
 
         let mut nod = load_fid_file_fn
             .call(file)
@@ -507,14 +729,8 @@ fn load_custom_objects_using_folder(
         custom_block_infos.insert(hash, NodRef::clone(block_info));
     }
 
-    for hash in custom_item_hashes {
-        let file_name = format!("{}.Item.Gbx", hash.to_hex());
-
-        let file = sync_edit_folder
-            .leaves
-            .iter_mut()
-            .find(|file| *file.name == *file_name)
-            .unwrap();
+    for (hash, file_path) in custom_item_files {
+        let file = find_fid_file(sync_edit_folder, &file_path).ok_or("missing custom item file")?;
 
         let mut nod = load_fid_file_fn
             .call(file)
@@ -528,12 +744,116 @@ fn load_custom_objects_using_folder(
     Ok(())
 }
 
+/// Write a custom object together with the files it depends on into a folder of its own, keeping
+/// their relative paths so that the game finds the dependencies when loading the object.
+///
+/// The bytes of the dependencies are looked up among the given embedded files of the map by hash.
+/// Returns the path of the object relative to the given folder, using `/` as separator.
+fn write_custom_object(
+    folder: &Path,
+    hash: Hash,
+    path: &str,
+    bytes: &[u8],
+    dependencies: &[DependencyDesc],
+    embedded_files: &HashMap<Hash, &[u8]>,
+) -> Result<String, Box<dyn Error>> {
+    let object_folder_name = hash.to_hex().to_string();
+
+    let mut files = vec![(path, bytes)];
+
+    for dependency in dependencies {
+        let bytes = embedded_files
+            .get(&dependency.hash)
+            .ok_or_else(|| format!("missing embedded file {}", dependency.path))?;
+
+        files.push((dependency.path.as_str(), *bytes));
+    }
+
+    for (path, bytes) in files {
+        // Paths come from the server, so they must not point outside of the folder.
+        if !Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("invalid embedded file path {path:?}").into());
+        }
+
+        let file_path = folder.join(&object_folder_name).join(path);
+
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&file_path, bytes)?;
+    }
+
+    Ok(format!("{object_folder_name}/{path}"))
+}
+
+/// Write the given custom skin files into the given user folder, where the game looks for skins,
+/// returning the path the game knows every file by.
+///
+/// Files that exist already are left alone, as they may belong to the user.
+fn write_custom_skins(
+    user_folder: &Path,
+    custom_skins: &[EmbeddedFileDesc],
+) -> Result<HashMap<Hash, String>, Box<dyn Error>> {
+    let mut paths = HashMap::new();
+
+    for custom_skin in custom_skins {
+        // Paths come from the server, so they must not point outside of the folder.
+        if !Path::new(&custom_skin.path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("invalid embedded file path {:?}", custom_skin.path).into());
+        }
+
+        let file_path = user_folder.join(&custom_skin.path);
+
+        if !file_path.exists() {
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::write(&file_path, &custom_skin.bytes)?;
+        }
+
+        paths.insert(custom_skin.hash(), custom_skin.path.replace('/', "\\"));
+    }
+
+    Ok(paths)
+}
+
+/// Find the file at the given path relative to the given folder, using `/` as separator.
+fn find_fid_file<'a>(
+    mut folder: &'a mut NodRef<FidsFolder>,
+    path: &str,
+) -> Option<&'a mut NodRef<FidFile>> {
+    let (folder_path, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    for folder_name in folder_path.split('/').filter(|name| !name.is_empty()) {
+        folder = folder
+            .trees
+            .iter_mut()
+            .find(|folder| &*folder.path == folder_name)?;
+    }
+
+    folder
+        .leaves
+        .iter_mut()
+        .find(|file| *file.name == *file_name)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn place_block(
     editor_common: &mut EditorCommon,
     block_info: &BlockInfo,
     coord: Vec3<u8>,
     dir: Direction,
     is_air_variant: bool,
+    variant_index: u8,
+    subvariant_index: u8,
     elem_color: ElemColor,
     place_block_fn: PlaceBlockFn,
 ) -> Option<NodRef<Block>> {
@@ -545,6 +865,8 @@ fn place_block(
                 coord,
                 dir,
                 is_air_variant,
+                variant_index,
+                subvariant_index,
                 elem_color,
             )
         }
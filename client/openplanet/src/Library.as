@@ -31,19 +31,41 @@ Library@ LoadLibrary() {
         return null;
     }
 
+    auto hostFunc = library.GetFunction("Host");
+
+    if (hostFunc is null) {
+        return null;
+    }
+
+    auto waypointFunc = library.GetFunction("Waypoint");
+
+    if (waypointFunc is null) {
+        return null;
+    }
+
+    auto skinFunc = library.GetFunction("Skin");
+
+    if (skinFunc is null) {
+        return null;
+    }
+
     auto maniaPlanet = cast<CGameManiaPlanet>(GetApp());
 
     if (maniaPlanet is null) {
         return null;
     }
 
-    auto context = initFunc.CallPointer(maniaPlanet, Fids::GetProgramDataFolder(""));
+    auto context = initFunc.CallPointer(
+        maniaPlanet, Fids::GetProgramDataFolder(""), IO::FromUserGameFolder("")
+    );
 
     if (context == 0) {
         return null;
     }
 
-    return Library(library, destroyFunc, updateFunc, joinFunc, context);
+    return Library(
+        library, destroyFunc, updateFunc, joinFunc, hostFunc, waypointFunc, skinFunc, context
+    );
 }
 
 class Library {
@@ -51,6 +73,9 @@ class Library {
     private Import::Function@ m_destroyFunc;
     private Import::Function@ m_updateFunc;
     private Import::Function@ m_joinFunc;
+    private Import::Function@ m_hostFunc;
+    private Import::Function@ m_waypointFunc;
+    private Import::Function@ m_skinFunc;
     private uint64 m_context;
 
     Library(
@@ -58,12 +83,18 @@ class Library {
         Import::Function@ destroyFunc,
         Import::Function@ updateFunc, 
         Import::Function@ joinFunc, 
+        Import::Function@ hostFunc, 
+        Import::Function@ waypointFunc, 
+        Import::Function@ skinFunc, 
         uint64 context
     ) {
         @m_library = library;
         @m_destroyFunc = destroyFunc;
         @m_updateFunc = updateFunc;
         @m_joinFunc = joinFunc;
+        @m_hostFunc = hostFunc;
+        @m_waypointFunc = waypointFunc;
+        @m_skinFunc = skinFunc;
         m_context = context;
     }
 
@@ -72,10 +103,90 @@ class Library {
     }
 
     void Update() {
-        m_updateFunc.Call(m_context);
+        if (m_updateFunc.CallBool(m_context)) {
+            ApplyProperties();
+        }
     }
 
-    void Join(const string&in host, const string&in port) {
-        m_joinFunc.Call(m_context, host, port);
+    void Join(const string&in host, const string&in port, const string&in room, const string&in userName) {
+        m_joinFunc.Call(m_context, host, port, room, userName);
+    }
+
+    void Host(
+        const string&in host, 
+        const string&in port, 
+        const string&in room, 
+        const string&in userName, 
+        const string&in mapPath
+    ) {
+        m_hostFunc.Call(m_context, host, port, room, userName, mapPath);
+    }
+
+    /// Set the waypoint properties and skins of the objects of the map the library built, which it
+    /// cannot set itself.
+    private void ApplyProperties() {
+        auto editor = cast<CGameCtnEditorFree>(GetApp().Editor);
+
+        if (editor is null) {
+            return;
+        }
+
+        auto map = editor.Challenge;
+        auto pluginMapType = editor.PluginMapType;
+
+        for (uint i = 0; i < map.Blocks.Length; i++) {
+            auto block = map.Blocks[i];
+
+            ApplyWaypoint(block, block.WaypointSpecialProperty);
+
+            auto skin = Skin(block);
+
+            if (skin.Length == 2) {
+                pluginMapType.SetBlockSkins(block, skin[0], skin[1]);
+            }
+        }
+
+        for (uint i = 0; i < map.AnchoredObjects.Length; i++) {
+            auto item = map.AnchoredObjects[i];
+
+            ApplyWaypoint(item, item.WaypointSpecialProperty);
+
+            auto skin = Skin(item);
+
+            if (skin.Length == 2) {
+                pluginMapType.SetItemSkins(item, skin[0], skin[1]);
+            }
+        }
+    }
+
+    /// Paths of the foreground and background skin files of the given object, or nothing if it
+    /// has no skin.
+    private array<string>@ Skin(CMwNod@ object) {
+        auto skin = m_skinFunc.CallString(m_context, object);
+
+        if (skin == "") {
+            return array<string>();
+        }
+
+        return skin.Split("\t");
+    }
+
+    private void ApplyWaypoint(CMwNod@ object, CGameWaypointSpecialProperty@ waypoint) {
+        // The tag and order separated by a tab, or nothing if the object has no waypoint.
+        auto property = m_waypointFunc.CallString(m_context, object);
+
+        if (property == "" || waypoint is null) {
+            return;
+        }
+
+        // The tag is free text, while the order never contains the separator.
+        auto separator = property.LastIndexOf("\t");
+
+        if (separator < 0) {
+            return;
+        }
+
+        waypoint.Tag = property.SubStr(0, separator);
+        waypoint.Order = Text::ParseUInt(property.SubStr(separator + 1));
     }
 }
@@ -9,8 +9,16 @@ string Setting_Host = "127.0.0.1";
 [Setting hidden]
 string Setting_Port = "8369";
 
+[Setting hidden]
+string Setting_Room = "default";
+
+[Setting hidden]
+string Setting_UserName = "";
+
 Library@ g_library = null;
 
+string g_hostError = "";
+
 void Main() {
     @g_library = LoadLibrary();
 }
@@ -33,14 +41,73 @@ void RenderInterface() {
 
         Setting_Port = UI::InputText("Port", Setting_Port, UI::InputTextFlags::CharsDecimal);
 
+        Setting_Room = UI::InputText("Room", Setting_Room, UI::InputTextFlags::CharsNoBlank);
+
+        Setting_UserName = UI::InputText("User name", Setting_UserName);
+
         if (UI::Button("Join")) {
-           g_library.Join(Setting_Host, Setting_Port);
+           g_library.Join(Setting_Host, Setting_Port, Setting_Room, Setting_UserName);
+        }
+
+        UI::SameLine();
+
+        auto editor = cast<CGameCtnEditorFree>(GetApp().Editor);
+
+        if (editor is null) {
+            UI::BeginDisabled();
+            UI::Button("Host");
+            UI::EndDisabled();
+        } else if (UI::Button("Host")) {
+            startnew(HostEditorMap);
+        }
+
+        if (g_hostError != "") {
+            UI::PushStyleColor(UI::Col::Text, vec4(1, 0, 0, 1));
+            UI::Text("Error: " + g_hostError);
+            UI::PopStyleColor();
         }
     }
 
     UI::End();
 }
 
+/// Save the map open in the editor to its file and create a room from that file, so that the room
+/// starts from the current state of the editor rather than from the last save.
+void HostEditorMap() {
+    g_hostError = "";
+
+    auto editor = cast<CGameCtnEditorFree>(GetApp().Editor);
+
+    if (editor is null) {
+        g_hostError = "No map is open in the editor";
+        return;
+    }
+
+    auto fileName = editor.Challenge.MapInfo.FileName;
+
+    // A map that was never saved has no file to save it to.
+    if (fileName == "") {
+        g_hostError = "Save the map once before hosting it";
+        return;
+    }
+
+    auto pluginMapType = editor.PluginMapType;
+
+    while (!pluginMapType.IsEditorReadyForRequest) {
+        yield();
+    }
+
+    pluginMapType.SaveMap(fileName);
+
+    while (!pluginMapType.IsEditorReadyForRequest) {
+        yield();
+    }
+
+    auto mapPath = IO::FromUserGameFolder("Maps/" + fileName);
+
+    g_library.Host(Setting_Host, Setting_Port, Setting_Room, Setting_UserName, mapPath);
+}
+
 void RenderMenu() {
     if (UI::MenuItem(c_pluginTitle, "", Setting_InterfaceVisible)) {
         Setting_InterfaceVisible = !Setting_InterfaceVisible;
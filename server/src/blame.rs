@@ -0,0 +1,107 @@
+//! Tracking of who changed which objects in the current session.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use shared::{
+    map::MapState, AabbDesc, Edit, EditInfoDesc, ObjectBlameDesc, ObjectDesc, ObjectId,
+    UserEditSummaryDesc,
+};
+
+use crate::op_log::LogEntry;
+
+/// Last edit of every object in the map, and edit counts per user.
+///
+/// This is stored with every snapshot of the map, so that it survives restarts.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Blame {
+    objects: HashMap<ObjectId, EditInfoDesc>,
+    summaries: HashMap<String, UserEditSummaryDesc>,
+}
+
+impl Blame {
+    /// Record an accepted edit, which is already applied to the given map.
+    pub fn record(&mut self, entry: &LogEntry, map: &MapState) {
+        let summary = self
+            .summaries
+            .entry(entry.author.clone())
+            .or_insert_with(|| UserEditSummaryDesc {
+                user_name: entry.author.clone(),
+                ..Default::default()
+            });
+
+        summary.last_timestamp = entry.timestamp;
+
+        match &entry.edit {
+            Edit::AddCustomBlock(_)
+            | Edit::AddCustomItem(_)
+            | Edit::AddCustomSkin(_)
+            | Edit::AddEmbeddedFile(_) => {
+                summary.custom_objects_added += 1;
+            }
+            Edit::Place(object) => {
+                summary.placed += 1;
+
+                self.objects.insert(object.id(), edit_info(entry));
+            }
+            Edit::Remove(object) => {
+                summary.removed += 1;
+
+                self.objects.remove(&object.id());
+            }
+            Edit::Modify { old, new } => {
+                summary.modified += 1;
+
+                self.objects.remove(&old.id());
+                self.objects.insert(new.id(), edit_info(entry));
+
+                // The items attached to the object keep their blame on the new version of it.
+                for item in map.attached_items(new.id()) {
+                    let mut old_item = item.clone();
+                    old_item.reattach(old.id());
+
+                    if let Some(edit) = self.objects.remove(&old_item.id()) {
+                        self.objects.insert(item.id(), edit);
+                    }
+                }
+            }
+            Edit::AddOffzone(_) => summary.placed += 1,
+            Edit::RemoveOffzone(_) => summary.removed += 1,
+        }
+    }
+
+    /// Blame of the object with the given id, if it is in the map.
+    pub fn blame_object(&self, map: &MapState, id: ObjectId) -> Option<ObjectBlameDesc> {
+        map.object(id).map(|object| self.blame(object))
+    }
+
+    /// Blame of all objects in the map positioned within the given box.
+    pub fn blame_region(&self, map: &MapState, aabb: AabbDesc) -> Vec<ObjectBlameDesc> {
+        map.objects()
+            .filter(|object| aabb.contains(object.position()))
+            .map(|object| self.blame(object))
+            .collect()
+    }
+
+    /// Edit counts of all users that made an edit in the room.
+    pub fn summaries(&self) -> Vec<UserEditSummaryDesc> {
+        let mut summaries: Vec<_> = self.summaries.values().cloned().collect();
+        summaries.sort_by(|a, b| a.user_name.cmp(&b.user_name));
+        summaries
+    }
+
+    fn blame(&self, object: &ObjectDesc) -> ObjectBlameDesc {
+        ObjectBlameDesc {
+            object: object.clone(),
+            edit: self.objects.get(&object.id()).cloned(),
+        }
+    }
+}
+
+fn edit_info(entry: &LogEntry) -> EditInfoDesc {
+    EditInfoDesc {
+        author: entry.author.clone(),
+        timestamp: entry.timestamp,
+        seq: entry.seq,
+    }
+}
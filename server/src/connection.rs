@@ -0,0 +1,521 @@
+//! Handling of a single client connection.
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use futures_util::{SinkExt, TryStreamExt};
+use shared::{
+    deserialize, serialize, ClientMessage, FramedStream, Handshake, HandshakeResponse, MapDesc,
+    MapParamsDesc, PassthroughDesc, SerdeError, ServerMessage, ServerStatsDesc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select,
+    sync::{mpsc, Mutex},
+    task,
+};
+use tokio_util::{bytes::Bytes, sync::CancellationToken};
+
+use crate::{
+    room::{is_valid_room_name, Member, Room},
+    ServerEvent, State,
+};
+
+/// Error that terminates a client connection.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// Reading from or writing to the socket failed.
+    Io(io::Error),
+    /// A message could not be serialized.
+    Serialize(SerdeError),
+    /// A message received from the client could not be deserialized.
+    Deserialize(SerdeError),
+}
+
+impl ConnectionError {
+    /// Whether this error is the peer going away rather than something going wrong.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Self::Io(error) => matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            Self::Serialize(_) | Self::Deserialize(_) => false,
+        }
+    }
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "io error: {error}"),
+            Self::Serialize(error) => write!(f, "failed to serialize message: {error}"),
+            Self::Deserialize(error) => write!(f, "received invalid message: {error}"),
+        }
+    }
+}
+
+impl Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Serialize(error) | Self::Deserialize(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<SerdeError> for ConnectionError {
+    fn from(error: SerdeError) -> Self {
+        Self::Serialize(error)
+    }
+}
+
+/// Serve a single connected client until it disconnects or the server shuts down.
+pub async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin>(
+    framed_stream: &mut FramedStream<T>,
+    socket_addr: SocketAddr,
+    state: &Mutex<State>,
+    shutdown: &CancellationToken,
+) -> Result<(), ConnectionError> {
+    let frame = select! {
+        frame = framed_stream.try_next() => frame?,
+        _ = shutdown.cancelled() => return Ok(()),
+    };
+
+    let Some(frame) = frame else {
+        return Ok(());
+    };
+
+    let handshake = deserialize(&frame).map_err(ConnectionError::Deserialize)?;
+
+    let (room_name, user_name, is_host) = match handshake {
+        Handshake::Join { room, user_name } => (room, user_name, false),
+        Handshake::ListRooms => {
+            let rooms = state
+                .lock()
+                .await
+                .rooms
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let mut room_descs = vec![];
+
+            for room in rooms {
+                room_descs.push(room.lock().await.to_desc());
+            }
+
+            room_descs.sort_by(|a, b| a.name.cmp(&b.name));
+
+            let frame = serialize(&HandshakeResponse::Rooms(room_descs))?;
+            framed_stream.send(Bytes::from(frame)).await?;
+
+            return Ok(());
+        }
+        Handshake::GetStats => {
+            let stats = ServerStatsDesc {
+                connections: state.lock().await.connections as u64,
+                resident_memory: resident_memory(),
+            };
+
+            let frame = serialize(&HandshakeResponse::Stats(stats))?;
+            framed_stream.send(Bytes::from(frame)).await?;
+
+            return Ok(());
+        }
+        Handshake::GetPassthrough { room } => {
+            let room = state.lock().await.rooms.get(&room).cloned();
+
+            let response = match room {
+                Some(room) => {
+                    let room = room.lock().await;
+
+                    HandshakeResponse::Passthrough {
+                        map_params_desc: room.map_params_desc.clone(),
+                        passthrough: room.passthrough.clone(),
+                    }
+                }
+                None => HandshakeResponse::UnknownRoom,
+            };
+
+            let frame = serialize(&response)?;
+            framed_stream.send(Bytes::from(frame)).await?;
+
+            return Ok(());
+        }
+        Handshake::Host {
+            room,
+            user_name,
+            map_params_desc,
+            map_desc,
+            passthrough,
+        } => {
+            if let Err(response) =
+                host_room(&room, map_params_desc, *map_desc, passthrough, state).await
+            {
+                log::info!("{socket_addr} failed to host room {room:?}");
+
+                let frame = serialize(&response)?;
+                framed_stream.send(Bytes::from(frame)).await?;
+
+                return Ok(());
+            }
+
+            log::info!("{socket_addr} created room {room:?}");
+
+            let events = state.lock().await.events.clone();
+            let _ = events.send(ServerEvent::RoomCreated { room: room.clone() });
+
+            (room, user_name, true)
+        }
+    };
+
+    let Some(room) = state.lock().await.rooms.get(&room_name).cloned() else {
+        log::info!("{socket_addr} tried to join unknown room {room_name:?}");
+
+        let frame = serialize(&HandshakeResponse::UnknownRoom)?;
+        framed_stream.send(Bytes::from(frame)).await?;
+
+        return Ok(());
+    };
+
+    log::info!("{socket_addr} joined room {room_name:?} as {user_name:?}");
+
+    let events = state.lock().await.events.clone();
+
+    // Having no event receivers is fine.
+    let _ = events.send(ServerEvent::Joined {
+        room: room_name.clone(),
+        user_name: user_name.clone(),
+        socket_addr,
+    });
+
+    let result = handle_member(
+        framed_stream,
+        socket_addr,
+        &user_name,
+        is_host,
+        &room,
+        shutdown,
+    )
+    .await;
+
+    room.lock().await.members.remove(&socket_addr);
+
+    log::info!("{socket_addr} left room {room_name:?}");
+
+    let _ = events.send(ServerEvent::Left {
+        room: room_name,
+        user_name,
+        socket_addr,
+    });
+
+    result
+}
+
+/// Resident memory of this process in bytes.
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory() -> Option<u64> {
+    None
+}
+
+/// Create a new room from a map uploaded by a client.
+async fn host_room(
+    room_name: &str,
+    map_params_desc: MapParamsDesc,
+    map_desc: MapDesc,
+    passthrough: Option<PassthroughDesc>,
+    state: &Mutex<State>,
+) -> Result<(), HandshakeResponse> {
+    if !is_valid_room_name(room_name) {
+        return Err(HandshakeResponse::InvalidRoomName);
+    }
+
+    let (folder, events, record_replays) = {
+        let mut state = state.lock().await;
+
+        let folder = state.data_folder.join(room_name);
+
+        // A folder without a hosted room is left over from a room that failed to recover, which
+        // must not be overwritten.
+        if state.rooms.contains_key(room_name)
+            || state.hosting.contains(room_name)
+            || folder.exists()
+        {
+            return Err(HandshakeResponse::RoomExists);
+        }
+
+        // The name is reserved while the room is written to disk without holding the lock.
+        state.hosting.insert(room_name.to_owned());
+
+        (folder, state.events.clone(), state.record_replays)
+    };
+
+    let name = room_name.to_owned();
+
+    let result = task::spawn_blocking(move || {
+        let mut room = Room::open(name.clone(), &folder, events, || {
+            Ok((map_params_desc, map_desc))
+        })
+        .map_err(|error| error.to_string())?;
+
+        if let Some(passthrough) = passthrough {
+            room.keep_passthrough(&folder, passthrough)
+                .map_err(|error| error.to_string())?;
+        }
+
+        if record_replays {
+            if let Err(error) = room.start_recording(&folder) {
+                log::warn!("failed to record room {name:?}: {error}");
+            }
+        }
+
+        Ok::<_, String>(room)
+    })
+    .await
+    .unwrap_or_else(|error| Err(error.to_string()));
+
+    let mut state = state.lock().await;
+
+    state.hosting.remove(room_name);
+
+    match result {
+        Ok(room) => {
+            state
+                .rooms
+                .insert(room_name.to_owned(), Arc::new(Mutex::new(room)));
+
+            Ok(())
+        }
+        Err(error) => {
+            log::error!("failed to create room {room_name:?}: {error}");
+
+            Err(HandshakeResponse::HostFailed)
+        }
+    }
+}
+
+/// Serve a client that joined the given room.
+///
+/// The client is registered as a member of the room as part of this, but the caller is
+/// responsible for removing it again, whatever the outcome. A client that hosts the room already
+/// has the map, so it is only sent to other clients.
+async fn handle_member<T: AsyncRead + AsyncWrite + Unpin>(
+    framed_stream: &mut FramedStream<T>,
+    socket_addr: SocketAddr,
+    user_name: &str,
+    is_host: bool,
+    room: &Mutex<Room>,
+    shutdown: &CancellationToken,
+) -> Result<(), ConnectionError> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    // Register the member while holding the lock, so that it receives exactly the edits made
+    // after the map it is sent.
+    let (response_frame, map_frame) = {
+        let mut room = room.lock().await;
+
+        let joined_seq = room.history.next_seq();
+
+        room.members.insert(
+            socket_addr,
+            Member {
+                user_name: user_name.to_owned(),
+                sender,
+                joined_seq,
+            },
+        );
+
+        if is_host {
+            (serialize(&HandshakeResponse::Hosted)?, None)
+        } else {
+            let response = HandshakeResponse::Joined(room.map_params_desc.clone());
+
+            (serialize(&response)?, Some(serialize(&room.map.to_desc())?))
+        }
+    };
+
+    framed_stream.send(Bytes::from(response_frame)).await?;
+
+    if let Some(map_frame) = map_frame {
+        framed_stream.send(Bytes::from(map_frame)).await?;
+    }
+
+    loop {
+        // Forwarding pending messages first makes sure a member receives the echo of its own edit
+        // before the reply to any message it sent after that edit.
+        select! {
+            biased;
+
+            _ = shutdown.cancelled() => return Ok(()),
+            Some(frame) = receiver.recv() => {
+                framed_stream.send(frame).await?;
+            }
+            frame = framed_stream.try_next() => {
+                let Some(frame) = frame? else {
+                    return Ok(());
+                };
+
+                let message = deserialize(&frame).map_err(ConnectionError::Deserialize)?;
+
+                if let Some(reply) = handle_message(message, socket_addr, user_name, room).await {
+                    // Edits caused by the message, such as those of a rollback, arrive before the
+                    // reply.
+                    while let Ok(frame) = receiver.try_recv() {
+                        framed_stream.send(frame).await?;
+                    }
+
+                    let frame = serialize(&reply)?;
+                    framed_stream.send(Bytes::from(frame)).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Handle a message from a member, returning the reply to send back, if any.
+async fn handle_message(
+    message: ClientMessage,
+    socket_addr: SocketAddr,
+    user_name: &str,
+    room: &Mutex<Room>,
+) -> Option<ServerMessage> {
+    let mut room = room.lock().await;
+
+    match message {
+        ClientMessage::Edit(edit) => {
+            if let Err(error) = room.accept_edit(edit, user_name).await {
+                log::error!("failed to accept edit from {socket_addr}: {error}");
+            }
+
+            None
+        }
+        ClientMessage::GetHistory => Some(ServerMessage::History(room.history.to_desc())),
+        ClientMessage::PreviewHistory { seq } => {
+            let map_desc = room.history.map_at(seq).map(|map| map.to_desc());
+
+            Some(ServerMessage::Preview { seq, map_desc })
+        }
+        ClientMessage::Rollback { seq } => {
+            let rolled_back = match room.rollback(seq, user_name).await {
+                Ok(true) => {
+                    log::info!(
+                        "{socket_addr} rolled back room {:?} to edit {seq}",
+                        room.name
+                    );
+
+                    true
+                }
+                Ok(false) => {
+                    log::warn!("{socket_addr} tried to roll back to unknown edit {seq}");
+
+                    false
+                }
+                Err(error) => {
+                    log::error!("failed to roll back to edit {seq}: {error}");
+
+                    false
+                }
+            };
+
+            Some(ServerMessage::Rollback { seq, rolled_back })
+        }
+        ClientMessage::BlameObject { id } => {
+            let blame = room.blame.blame_object(&room.map, id);
+
+            Some(ServerMessage::Blame(blame.into_iter().collect()))
+        }
+        ClientMessage::BlameRegion { aabb } => Some(ServerMessage::Blame(
+            room.blame.blame_region(&room.map, aabb),
+        )),
+        ClientMessage::GetEditSummary => Some(ServerMessage::EditSummary(room.blame.summaries())),
+        ClientMessage::Barrier => Some(ServerMessage::Barrier),
+        ClientMessage::Chat { text } => {
+            room.chat(user_name, text);
+
+            None
+        }
+        ClientMessage::InsertMacroblock {
+            macroblock,
+            coord,
+            quarter_turns,
+        } => {
+            let failures = match room
+                .insert_macroblock(&macroblock, coord, quarter_turns, user_name)
+                .await
+            {
+                Ok(failures) => {
+                    if let Some(failure) = failures.first() {
+                        log::info!(
+                            "{socket_addr} failed to insert a macroblock, {} of its edits fail, \
+                             the first because {}",
+                            failures.len(),
+                            failure.reason
+                        );
+                    }
+
+                    Some(failures)
+                }
+                Err(error) => {
+                    log::error!("failed to insert macroblock from {socket_addr}: {error}");
+
+                    None
+                }
+            };
+
+            Some(ServerMessage::InsertMacroblock {
+                inserted: failures.as_ref().is_some_and(Vec::is_empty),
+                failures: failures.unwrap_or_default(),
+            })
+        }
+        ClientMessage::SetMedalTimes(medal_times) => {
+            if let Err(error) = room.set_medal_times(medal_times) {
+                log::error!("failed to set medal times from {socket_addr}: {error}");
+            }
+
+            None
+        }
+        ClientMessage::MarkValidated {
+            medal_times,
+            received_edits,
+        } => {
+            match room.mark_validated(socket_addr, medal_times, received_edits) {
+                Ok(true) => log::info!("{socket_addr} validated room {:?}", room.name),
+                Ok(false) => log::info!("{socket_addr} validated an outdated map, ignoring it"),
+                Err(error) => log::error!("failed to mark map as validated: {error}"),
+            }
+
+            None
+        }
+    }
+}
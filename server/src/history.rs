@@ -0,0 +1,100 @@
+//! Edit history of a room.
+
+use std::collections::VecDeque;
+
+use shared::{map::MapState, Edit, HistoryDesc, SavePointDesc};
+
+use crate::op_log::LogEntry;
+
+/// Largest number of edits kept in the history.
+const MAX_EDITS: usize = 4096;
+
+/// Largest total size of the custom objects, skins and embedded files added by the edits kept in
+/// the history.
+const MAX_CUSTOM_BYTES: usize = 64 * 1024 * 1024;
+
+/// The latest accepted edits, on top of the map before the first of them.
+///
+/// Once the history grows beyond [`MAX_EDITS`] or [`MAX_CUSTOM_BYTES`], its oldest edits are
+/// applied to the base map and dropped.
+pub struct History {
+    base_seq: u64,
+    base: MapState,
+    entries: VecDeque<LogEntry>,
+    custom_bytes: usize,
+    save_points: Vec<SavePointDesc>,
+}
+
+impl History {
+    /// Start a history at the given map, which contains all edits before the given save point.
+    pub fn new(save_point: SavePointDesc, map: MapState) -> Self {
+        Self {
+            base_seq: save_point.seq,
+            base: map,
+            entries: VecDeque::new(),
+            custom_bytes: 0,
+            save_points: vec![save_point],
+        }
+    }
+
+    pub fn push(&mut self, entry: LogEntry) {
+        self.custom_bytes += custom_bytes(&entry.edit);
+        self.entries.push_back(entry);
+
+        while self.entries.len() > MAX_EDITS || self.custom_bytes > MAX_CUSTOM_BYTES {
+            let Some(entry) = self.entries.pop_front() else {
+                break;
+            };
+
+            self.custom_bytes -= custom_bytes(&entry.edit);
+            self.base_seq += 1;
+            self.base.apply(entry.edit);
+        }
+
+        let base_seq = self.base_seq;
+
+        self.save_points
+            .retain(|save_point| save_point.seq >= base_seq);
+    }
+
+    pub fn add_save_point(&mut self, save_point: SavePointDesc) {
+        self.save_points.push(save_point);
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.base_seq + self.entries.len() as u64
+    }
+
+    pub fn to_desc(&self) -> HistoryDesc {
+        HistoryDesc {
+            first_seq: self.base_seq,
+            next_seq: self.next_seq(),
+            save_points: self.save_points.clone(),
+        }
+    }
+
+    /// Reconstruct the map as it was before the edit with the given sequence number.
+    pub fn map_at(&self, seq: u64) -> Option<MapState> {
+        if seq < self.base_seq || seq > self.next_seq() {
+            return None;
+        }
+
+        let mut map = self.base.clone();
+
+        for entry in self.entries.range(..(seq - self.base_seq) as usize) {
+            map.apply(entry.edit.clone());
+        }
+
+        Some(map)
+    }
+}
+
+fn custom_bytes(edit: &Edit) -> usize {
+    match edit {
+        Edit::AddCustomBlock(custom_block) => custom_block.bytes.len(),
+        Edit::AddCustomItem(custom_item) => custom_item.bytes.len(),
+        Edit::AddCustomSkin(custom_skin) => custom_skin.bytes.len(),
+        Edit::AddEmbeddedFile(embedded_file) => embedded_file.bytes.len(),
+        _ => 0,
+    }
+}
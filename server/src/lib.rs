@@ -0,0 +1,525 @@
+//! Sync edit server, which hosts rooms of maps that are edited collaboratively.
+//!
+//! ```no_run
+//! # async fn run(map_desc: shared::MapDesc) -> Result<(), Box<dyn std::error::Error>> {
+//! use shared::{MapParamsDesc, MedalTimesDesc, Mood};
+//! use tm_sync_edit_server::Server;
+//!
+//! let map_params_desc = MapParamsDesc {
+//!     mood: Mood::Day,
+//!     medal_times: MedalTimesDesc::default(),
+//!     validated: false,
+//! };
+//!
+//! let server = Server::builder()
+//!     .map("default", map_params_desc, map_desc)
+//!     .bind("0.0.0.0:8369")
+//!     .await?;
+//!
+//! let mut events = server.events();
+//!
+//! while let Ok(event) = events.recv().await {
+//!     // ...
+//! }
+//!
+//! server.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+mod blame;
+mod connection;
+mod history;
+mod op_log;
+mod playback;
+mod recorder;
+mod room;
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs, future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+pub use op_log::{read_log, read_snapshot, LogEntry};
+
+use connection::handle_connection;
+use room::{is_valid_room_name, Room};
+use shared::{
+    framed_stream,
+    gbx::{read_map, read_map_params, read_passthrough},
+    replay::{PlaybackSpeed, Replay},
+    Edit, FramedStream, MapDesc, MapParamsDesc,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, DuplexStream},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    select,
+    sync::{broadcast, mpsc, Mutex},
+    task::{JoinHandle, JoinSet},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Number of bytes buffered in each direction of an in-memory connection.
+const IN_MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of events buffered for each event receiver before it starts missing events.
+const EVENT_CAPACITY: usize = 1024;
+
+/// File marking a room folder as belonging to a room played back from a replay.
+///
+/// Such rooms start over from their replay every time the server starts, so their folders are
+/// discarded instead of recovered.
+const REPLAY_ROOM_FILE_NAME: &str = "replay-room";
+
+/// Something that happened on the server.
+#[derive(Clone)]
+pub enum ServerEvent {
+    /// A client created a new room.
+    RoomCreated { room: String },
+    /// A client joined a room.
+    Joined {
+        room: String,
+        user_name: String,
+        socket_addr: SocketAddr,
+    },
+    /// A client left a room.
+    Left {
+        room: String,
+        user_name: String,
+        socket_addr: SocketAddr,
+    },
+    /// An edit was accepted in a room.
+    Edit {
+        room: String,
+        author: String,
+        seq: u64,
+        edit: Edit,
+    },
+    /// A member sent a chat message.
+    Chat {
+        room: String,
+        user_name: String,
+        text: String,
+    },
+    /// The medal times or validation state of the map of a room changed.
+    MapParams {
+        room: String,
+        map_params_desc: MapParamsDesc,
+    },
+}
+
+/// Source of the initial map of a room.
+enum InitialMap {
+    Desc(MapParamsDesc, Box<MapDesc>),
+    File(PathBuf),
+}
+
+/// Builder of a [`Server`].
+pub struct ServerBuilder {
+    data_folder: PathBuf,
+    rooms: Vec<(String, InitialMap)>,
+    replays: Vec<(String, Replay, PlaybackSpeed)>,
+    recover_rooms: bool,
+    record_replays: bool,
+}
+
+impl ServerBuilder {
+    /// Folder in which the operation logs and snapshots of all rooms are stored.
+    ///
+    /// Defaults to `data` in the working directory.
+    pub fn data_folder(mut self, data_folder: impl Into<PathBuf>) -> Self {
+        self.data_folder = data_folder.into();
+        self
+    }
+
+    /// Host a room with the given name, starting from the given map if the room has no operation
+    /// log yet.
+    pub fn map(
+        mut self,
+        room: impl Into<String>,
+        map_params_desc: MapParamsDesc,
+        map_desc: MapDesc,
+    ) -> Self {
+        self.rooms.push((
+            room.into(),
+            InitialMap::Desc(map_params_desc, Box::new(map_desc)),
+        ));
+
+        self
+    }
+
+    /// Host a room with the given name, starting from the given map file if the room has no
+    /// operation log yet.
+    pub fn map_file(mut self, room: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.rooms
+            .push((room.into(), InitialMap::File(path.into())));
+        self
+    }
+
+    /// Host a new room with the given name that starts from the map of the given replay, into
+    /// which the recorded edits are played back at the given speed.
+    ///
+    /// The room starts over every time the server starts, discarding what was played back
+    /// before. No other room with the same name may exist.
+    pub fn replay(mut self, room: impl Into<String>, replay: Replay, speed: PlaybackSpeed) -> Self {
+        self.replays.push((room.into(), replay, speed));
+        self
+    }
+
+    /// Whether to record the edits accepted in every room into a replay file in the folder of the
+    /// room, see [`shared::replay`]. A new file is started every time a room is opened.
+    ///
+    /// Defaults to `false`.
+    pub fn record_replays(mut self, record_replays: bool) -> Self {
+        self.record_replays = record_replays;
+        self
+    }
+
+    /// Whether to also host the rooms found in the data folder that were not added explicitly,
+    /// such as rooms created by clients in earlier runs.
+    ///
+    /// Defaults to `true`.
+    pub fn recover_rooms(mut self, recover_rooms: bool) -> Self {
+        self.recover_rooms = recover_rooms;
+        self
+    }
+
+    /// Open all rooms and start accepting connections on the given address, as well as in-memory
+    /// connections.
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> Result<Server, Box<dyn Error>> {
+        let tcp_listener = TcpListener::bind(addr).await?;
+
+        log::info!("listening on {}", tcp_listener.local_addr()?);
+
+        self.start_with(Some(tcp_listener))
+    }
+
+    /// Open all rooms and start accepting only in-memory connections, see
+    /// [`Server::connect_in_memory`].
+    pub fn start(self) -> Result<Server, Box<dyn Error>> {
+        self.start_with(None)
+    }
+
+    fn start_with(self, tcp_listener: Option<TcpListener>) -> Result<Server, Box<dyn Error>> {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+
+        let mut rooms = HashMap::new();
+
+        for (name, initial_map) in self.rooms {
+            if !is_valid_room_name(&name) {
+                return Err(format!("invalid room name {name:?}").into());
+            }
+
+            let folder = self.data_folder.join(&name);
+
+            let mut passthrough = None;
+
+            let mut room = Room::open(
+                name.clone(),
+                &folder,
+                events.clone(),
+                || match initial_map {
+                    InitialMap::Desc(map_params_desc, map_desc) => Ok((map_params_desc, *map_desc)),
+                    InitialMap::File(path) => {
+                        let map_desc = read_map(&path)?;
+                        let map_params_desc = read_map_params(&path)?;
+                        passthrough = Some(read_passthrough(&path)?);
+
+                        Ok((map_params_desc, map_desc))
+                    }
+                },
+            )?;
+
+            if let Some((passthrough, report)) = passthrough {
+                log::info!("room {name:?} {report}");
+
+                room.keep_passthrough(&folder, passthrough)?;
+            }
+
+            log::info!("hosting room {name:?}");
+
+            rooms.insert(name, Arc::new(Mutex::new(room)));
+        }
+
+        let mut playbacks = vec![];
+
+        for (name, replay, speed) in self.replays {
+            if !is_valid_room_name(&name) {
+                return Err(format!("invalid room name {name:?}").into());
+            }
+
+            let folder = self.data_folder.join(&name);
+
+            if rooms.contains_key(&name) {
+                return Err(format!("room {name:?} already exists").into());
+            }
+
+            if folder.exists() {
+                if !folder.join(REPLAY_ROOM_FILE_NAME).exists() {
+                    return Err(format!("room {name:?} already exists").into());
+                }
+
+                fs::remove_dir_all(&folder)?;
+            }
+
+            fs::create_dir_all(&folder)?;
+            fs::write(folder.join(REPLAY_ROOM_FILE_NAME), [])?;
+
+            let Replay { header, edits } = replay;
+
+            let room = Room::open(name.clone(), &folder, events.clone(), || {
+                Ok((header.map_params_desc, header.map_desc))
+            })?;
+
+            log::info!("hosting replay room {name:?}");
+
+            let room = Arc::new(Mutex::new(room));
+
+            playbacks.push((Arc::clone(&room), edits, speed));
+            rooms.insert(name, room);
+        }
+
+        if self.recover_rooms {
+            recover_rooms(&self.data_folder, &mut rooms, &events)?;
+        }
+
+        if self.record_replays {
+            for (name, room) in &rooms {
+                room.try_lock()
+                    .expect("rooms are not shared yet")
+                    .start_recording(&self.data_folder.join(name))?;
+            }
+        }
+
+        let local_addr = match &tcp_listener {
+            Some(tcp_listener) => Some(tcp_listener.local_addr()?),
+            None => None,
+        };
+
+        let state = Arc::new(Mutex::new(State {
+            rooms,
+            hosting: HashSet::new(),
+            data_folder: self.data_folder,
+            events: events.clone(),
+            connections: 0,
+            record_replays: self.record_replays,
+        }));
+
+        let shutdown = CancellationToken::new();
+
+        for (room, edits, speed) in playbacks {
+            tokio::spawn(playback::play(room, edits, speed, shutdown.clone()));
+        }
+
+        let (in_memory_sender, in_memory_receiver) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(accept_connections(
+            tcp_listener,
+            in_memory_receiver,
+            state,
+            shutdown.clone(),
+        ));
+
+        Ok(Server {
+            local_addr,
+            events,
+            in_memory_sender,
+            next_in_memory_port: AtomicU16::new(1),
+            shutdown,
+            task,
+        })
+    }
+}
+
+/// A running server.
+///
+/// Dropping this does not stop the server, use [`Server::shutdown`] for that.
+pub struct Server {
+    local_addr: Option<SocketAddr>,
+    events: broadcast::Sender<ServerEvent>,
+    in_memory_sender: mpsc::UnboundedSender<(DuplexStream, SocketAddr)>,
+    next_in_memory_port: AtomicU16,
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            data_folder: PathBuf::from("data"),
+            rooms: vec![],
+            replays: vec![],
+            recover_rooms: true,
+            record_replays: false,
+        }
+    }
+
+    /// Address the server is accepting TCP connections on, if any.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Connect to the server over an in-memory pipe, which behaves exactly like a TCP connection.
+    ///
+    /// The server sees the connection as coming from an unspecified address with a port unique
+    /// among in-memory connections.
+    pub fn connect_in_memory(&self) -> FramedStream<DuplexStream> {
+        let (client_stream, server_stream) = io::duplex(IN_MEMORY_BUFFER_SIZE);
+
+        let port = self.next_in_memory_port.fetch_add(1, Ordering::Relaxed);
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+
+        // If the server has shut down the client stream is closed right away, as it would be when
+        // connecting over TCP.
+        let _ = self.in_memory_sender.send((server_stream, socket_addr));
+
+        framed_stream(client_stream)
+    }
+
+    /// Subscribe to the events of the server, starting at the next event.
+    pub fn events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Stop accepting connections, close all existing connections and wait for that to finish.
+    pub async fn shutdown(self) {
+        self.shutdown.cancel();
+
+        if let Err(error) = self.task.await {
+            log::error!("server task failed: {error}");
+        }
+    }
+}
+
+struct State {
+    rooms: HashMap<String, Arc<Mutex<Room>>>,
+    /// Names of the rooms clients are creating.
+    hosting: HashSet<String>,
+    /// Folder containing a folder for every room.
+    data_folder: PathBuf,
+    events: broadcast::Sender<ServerEvent>,
+    /// Number of open connections.
+    connections: usize,
+    /// Whether rooms created by clients are recorded.
+    record_replays: bool,
+}
+
+/// Open every room in the data folder that is not in the given rooms yet.
+fn recover_rooms(
+    data_folder: &Path,
+    rooms: &mut HashMap<String, Arc<Mutex<Room>>>,
+    events: &broadcast::Sender<ServerEvent>,
+) -> Result<(), Box<dyn Error>> {
+    let Ok(entries) = fs::read_dir(data_folder) else {
+        return Ok(());
+    };
+
+    for entry in entries {
+        let entry = entry?;
+
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+
+        if rooms.contains_key(&name) || !is_valid_room_name(&name) || !entry.path().is_dir() {
+            continue;
+        }
+
+        if entry.path().join(REPLAY_ROOM_FILE_NAME).exists() {
+            log::info!("not recovering room {name:?}, which was played back from a replay");
+
+            continue;
+        }
+
+        match Room::open(name.clone(), &entry.path(), events.clone(), || {
+            Err("room has no snapshot".into())
+        }) {
+            Ok(room) => {
+                log::info!("hosting recovered room {name:?}");
+
+                rooms.insert(name, Arc::new(Mutex::new(room)));
+            }
+            Err(error) => log::warn!("failed to recover room {name:?}: {error}"),
+        }
+    }
+
+    Ok(())
+}
+
+async fn accept_connections(
+    tcp_listener: Option<TcpListener>,
+    mut in_memory_receiver: mpsc::UnboundedReceiver<(DuplexStream, SocketAddr)>,
+    state: Arc<Mutex<State>>,
+    shutdown: CancellationToken,
+) {
+    let mut connections = JoinSet::new();
+
+    loop {
+        select! {
+            result = accept_tcp(&tcp_listener) => match result {
+                Ok((tcp_stream, socket_addr)) => {
+                    connections.spawn(serve(
+                        framed_stream(tcp_stream),
+                        socket_addr,
+                        Arc::clone(&state),
+                        shutdown.clone(),
+                    ));
+                }
+                Err(error) => log::warn!("failed to accept connection: {error}"),
+            },
+            Some((stream, socket_addr)) = in_memory_receiver.recv() => {
+                connections.spawn(serve(
+                    framed_stream(stream),
+                    socket_addr,
+                    Arc::clone(&state),
+                    shutdown.clone(),
+                ));
+            }
+            // Reap finished connections so they do not accumulate.
+            Some(_) = connections.join_next() => {}
+            _ = shutdown.cancelled() => break,
+        }
+    }
+
+    while connections.join_next().await.is_some() {}
+
+    log::info!("server stopped");
+}
+
+/// Accept the next TCP connection, or wait forever if there is no listener.
+async fn accept_tcp(tcp_listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match tcp_listener {
+        Some(tcp_listener) => tcp_listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+    mut framed_stream: FramedStream<T>,
+    socket_addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: CancellationToken,
+) {
+    log::info!("accepted connection to {socket_addr}");
+
+    state.lock().await.connections += 1;
+
+    let result = handle_connection(&mut framed_stream, socket_addr, &state, &shutdown).await;
+
+    state.lock().await.connections -= 1;
+
+    match result {
+        Ok(()) => log::info!("connection to {socket_addr} closed"),
+        Err(error) if error.is_disconnect() => {
+            log::info!("connection to {socket_addr} lost: {error}")
+        }
+        Err(error) => log::warn!("connection to {socket_addr} failed: {error}"),
+    }
+}
@@ -1,215 +1,106 @@
 use std::{
-    collections::HashMap,
+    env,
     error::Error,
-    io::{Cursor, Read},
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
+    time::Duration,
 };
 
-use futures_util::{SinkExt, TryStreamExt};
-use gamebox::{engines::game::map::BlockKind, Vec3};
 use log::LevelFilter;
-use shared::{
-    framed_tcp_stream, hash, serialize, BlockDesc, CustomBlockDesc, CustomItemDesc, FreeBlockDesc,
-    GhostBlockDesc, ItemDesc, MapDesc, MapParamsDesc, ModelId, Mood, NotNan,
-};
-use tokio::{net::TcpListener, runtime, spawn, sync::Mutex};
-use zip::ZipArchive;
-
+use shared::replay::{PlaybackSpeed, Replay};
+use tm_sync_edit_server::Server;
+use tokio::{runtime, signal};
+
+/// Folder in which the operation logs and snapshots of all rooms are stored.
+const DATA_FOLDER: &str = "data";
+
+/// Room hosted if no rooms are given on the command line.
+const DEFAULT_ROOM_NAME: &str = "default";
+const DEFAULT_MAP_PATH: &str =
+    "C:\\Users\\Justin\\Documents\\Trackmania\\Maps\\My Maps\\Unnamed.Map.Gbx";
+
+/// Longest pause between two edits when playing back a replay.
+const MAX_PLAYBACK_DELAY: Duration = Duration::from_secs(5);
+
+/// Usage: `tm-sync-edit-server [--record] [--speed SPEED] [NAME=MAP_PATH]...`
+///
+/// Every `NAME=MAP_PATH` argument hosts a room with the given name, starting from the given map if
+/// the room has no operation log yet. A `.replay` file instead creates a new room, into which the
+/// recorded edits are played back `SPEED` times as fast as they were made, defaulting to 10, from
+/// the start on every run. Rooms created by clients in earlier runs are hosted as well.
+///
+/// With `--record`, the edits of every room are recorded into replay files in the room folders.
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::builder()
         .filter_level(LevelFilter::Info)
         .try_init()?;
 
-    let runtime = runtime::Builder::new_multi_thread().enable_io().build()?;
-
-    let _ = load_map();
-
-    runtime.block_on(async {
-        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8369);
-
-        let tcp_listerner = TcpListener::bind(&socket_addr).await?;
-
-        log::info!("listening on {socket_addr}");
-
-        let state = Arc::new(Mutex::new(State::new()));
-
-        loop {
-            let (tcp_stream, socket_addr) = tcp_listerner.accept().await?;
-
-            let state = Arc::clone(&state);
-
-            spawn(async move {
-                log::info!("accepted connection to {socket_addr}");
-
-                state.lock().await.clients.insert(socket_addr, ());
-
-                let mut framed_tcp_stream = framed_tcp_stream(tcp_stream);
-
-                let map_params_desc = MapParamsDesc { mood: Mood::Day };
-
-                let frame = serialize(&map_params_desc).unwrap();
-                framed_tcp_stream.send(frame.into()).await.unwrap();
-
-                let map_desc = load_map();
-                let frame = serialize(&map_desc).unwrap();
-                framed_tcp_stream.send(frame.into()).await.unwrap();
-
-                while framed_tcp_stream.try_next().await.unwrap().is_some() {}
-
-                state.lock().await.clients.remove(&socket_addr);
-            });
-        }
-    })
-}
-
-struct State {
-    clients: HashMap<SocketAddr, ()>,
-}
+    let mut record_replays = false;
+    let mut speed = 10.0;
+    let mut room_args = vec![];
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => record_replays = true,
+            "--speed" => {
+                speed = args
+                    .next()
+                    .and_then(|speed| speed.parse::<f64>().ok())
+                    .filter(|&speed| speed > 0.0 && speed.is_finite())
+                    .ok_or("expected a positive finite number after --speed")?;
+            }
+            _ => {
+                let (name, map_path) = arg
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected NAME=MAP_PATH, got {arg:?}"))?;
 
-impl State {
-    fn new() -> Self {
-        Self {
-            clients: HashMap::new(),
+                room_args.push((name.to_owned(), PathBuf::from(map_path)));
+            }
         }
     }
-}
-
-pub fn load_map() -> MapDesc {
-    let map: gamebox::Map = gamebox::read_file(
-        "C:\\Users\\Justin\\Documents\\Trackmania\\Maps\\My Maps\\Unnamed.Map.Gbx",
-    )
-    .unwrap();
-
-    let mut custom_block_hashes = HashMap::new();
-    let mut custom_item_hashes = HashMap::new();
-    let mut custom_blocks = vec![];
-    let mut custom_items = vec![];
-
-    if let Some(embedded_objects) = map.embedded_objects() {
-        let mut zip_archive = ZipArchive::new(Cursor::new(embedded_objects.data())).unwrap();
-
-        for file_index in 0..zip_archive.len() {
-            let mut file = zip_archive.by_index(file_index).unwrap();
-
-            let mut bytes = vec![];
-            file.read_to_end(&mut bytes).unwrap();
-
-            let hash = hash(&bytes);
 
-            if file.name().to_lowercase().ends_with("block.gbx") {
-                let id = format!(
-                    "{}_CustomBlock",
-                    embedded_objects.ids().get(file_index).unwrap()
-                );
-
-                custom_block_hashes.insert(id, hash);
-
-                custom_blocks.push(CustomBlockDesc { bytes })
-            } else if file.name().to_lowercase().ends_with("item.gbx") {
-                let id = embedded_objects.ids().get(file_index).unwrap().to_owned();
-
-                custom_item_hashes.insert(id, hash);
-
-                custom_items.push(CustomItemDesc { bytes })
-            }
-        }
+    if room_args.is_empty() {
+        room_args.push((
+            DEFAULT_ROOM_NAME.to_owned(),
+            PathBuf::from(DEFAULT_MAP_PATH),
+        ));
     }
 
-    let mut blocks = vec![];
-    let mut ghost_blocks = vec![];
-    let mut free_blocks = vec![];
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
 
-    for block in map.blocks() {
-        let model_id = if let Some(&hash) = custom_block_hashes.get(block.info_id()) {
-            ModelId::Custom { hash }
-        } else {
-            ModelId::Game {
-                id: block.info_id().to_owned(),
-            }
+    runtime.block_on(async {
+        let mut builder = Server::builder()
+            .data_folder(DATA_FOLDER)
+            .record_replays(record_replays);
+
+        let speed = PlaybackSpeed {
+            speed,
+            max_delay: MAX_PLAYBACK_DELAY,
         };
 
-        match block.kind() {
-            BlockKind::Normal(block_kind) => {
-                if block_kind.is_ghost() {
-                    ghost_blocks.push(GhostBlockDesc {
-                        block_info_id: model_id,
-                        coord: block_kind.coord(),
-                        dir: block_kind.direction(),
-                        is_air_variant: block_kind.is_air_variant(),
-                        elem_color: block.elem_color(),
-                    })
-                } else {
-                    blocks.push(BlockDesc {
-                        block_info_id: model_id,
-                        coord: block_kind.coord(),
-                        dir: block_kind.direction(),
-                        is_air_variant: block_kind.is_air_variant(),
-                        elem_color: block.elem_color(),
-                    })
-                }
-            }
-            BlockKind::Free(block_kind) => {
-                let position = block_kind.position();
-                let rotation = block_kind.rotation();
-
-                free_blocks.push(FreeBlockDesc {
-                    block_info_id: model_id,
-                    position: Vec3 {
-                        x: NotNan::new(position.x).unwrap(),
-                        y: NotNan::new(position.y).unwrap(),
-                        z: NotNan::new(position.z).unwrap(),
-                    },
-                    yaw: NotNan::new(rotation.yaw).unwrap(),
-                    pitch: NotNan::new(rotation.pitch).unwrap(),
-                    roll: NotNan::new(rotation.roll).unwrap(),
-                    elem_color: block.elem_color(),
-                });
-            }
+        for (name, map_path) in room_args {
+            builder = if map_path
+                .extension()
+                .is_some_and(|extension| extension == "replay")
+            {
+                builder.replay(name, Replay::read(&map_path)?, speed)
+            } else {
+                builder.map_file(name, map_path)
+            };
         }
-    }
 
-    let mut items = vec![];
+        let server = builder
+            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8369))
+            .await?;
 
-    for item in map.items() {
-        let model_id = if let Some(&hash) = custom_item_hashes.get(item.model_id()) {
-            ModelId::Custom { hash }
-        } else {
-            ModelId::Game {
-                id: item.model_id().to_owned(),
-            }
-        };
+        signal::ctrl_c().await?;
 
-        let position = item.position();
-        let rotation = item.rotation();
-        let pivot_position = item.pivot_position();
-
-        items.push(ItemDesc {
-            item_model_id: model_id,
-            position: Vec3 {
-                x: NotNan::new(position.x).unwrap(),
-                y: NotNan::new(position.y).unwrap(),
-                z: NotNan::new(position.z).unwrap(),
-            },
-            yaw: NotNan::new(rotation.yaw).unwrap(),
-            pitch: NotNan::new(rotation.pitch).unwrap(),
-            roll: NotNan::new(rotation.roll).unwrap(),
-            pivot_position: Vec3 {
-                x: NotNan::new(pivot_position.x).unwrap(),
-                y: NotNan::new(pivot_position.y).unwrap(),
-                z: NotNan::new(pivot_position.z).unwrap(),
-            },
-            elem_color: item.elem_color(),
-            anim_offset: item.animation_offset(),
-        })
-    }
+        log::info!("shutting down");
 
-    MapDesc {
-        custom_blocks,
-        custom_items,
-        blocks,
-        ghost_blocks,
-        free_blocks,
-        items,
-    }
+        server.shutdown().await;
+
+        Ok(())
+    })
 }
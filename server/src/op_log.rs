@@ -0,0 +1,427 @@
+//! Append-only log of accepted edits with periodic snapshots of the map.
+//!
+//! Every file in a room folder starts with [`MAGIC`] and the little-endian `u32`
+//! [`FORMAT_VERSION`], followed by records made up of a little-endian `u32` payload length, the
+//! BLAKE3 hash of the payload and the payload itself. A record that is cut off or does not match
+//! its hash marks the end of the valid part of a file. Every record of the log holds all edits
+//! accepted together, so those are recovered either all or not at all.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use shared::{
+    deserialize, hash, map::MapState, serialize, Edit, MapDesc, SavePointDesc, SerdeError,
+};
+use tokio::task;
+
+use crate::blame::Blame;
+
+const LOG_FILE_NAME: &str = "edits.log";
+const SNAPSHOT_FILE_NAME: &str = "snapshot.bin";
+const SNAPSHOT_TEMP_FILE_NAME: &str = "snapshot.bin.tmp";
+
+/// Number of logged edits after which a new snapshot should be written.
+const SNAPSHOT_INTERVAL: u64 = 1024;
+
+/// Start of every file in a room folder.
+const MAGIC: &[u8; 8] = b"TMSEROOM";
+
+/// Version of the layout of the files in a room folder, which has to be increased whenever any of
+/// the stored types changes, as their encoding is not self-describing.
+const FORMAT_VERSION: u32 = 2;
+
+const FILE_HEADER_LEN: usize = MAGIC.len() + 4;
+
+const RECORD_HEADER_LEN: usize = 4 + 32;
+
+/// An edit as written to the log.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Sequence number of this edit, starting at 0 for the first edit ever accepted.
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch at which the edit was accepted.
+    pub timestamp: u64,
+    /// Name of the user that made the edit.
+    pub author: String,
+    pub edit: Edit,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Sequence number of the first edit not contained in the map.
+    seq: u64,
+    /// Milliseconds since the Unix epoch at which the snapshot was written.
+    timestamp: u64,
+    map_desc: MapDesc,
+    blame: Blame,
+}
+
+/// Contents of a room folder.
+pub struct Recovery {
+    /// Map of the latest snapshot.
+    pub snapshot: MapState,
+    /// Blame of the map of the latest snapshot.
+    pub blame: Blame,
+    pub save_point: SavePointDesc,
+    /// Edits logged after the snapshot, which the current map consists of on top of it.
+    pub entries: Vec<LogEntry>,
+}
+
+/// Write-ahead log of all edits accepted by the server.
+pub struct OpLog {
+    folder: PathBuf,
+    log_file: Arc<File>,
+    next_seq: u64,
+    edits_since_snapshot: u64,
+}
+
+impl OpLog {
+    /// Open the log in the given folder, returning the latest snapshot and the edits logged after
+    /// it.
+    ///
+    /// If there is no snapshot yet, a first snapshot of `initial_map` is written immediately.
+    pub fn open(
+        folder: &Path,
+        initial_map: impl FnOnce() -> MapDesc,
+    ) -> io::Result<(Self, Recovery)> {
+        fs::create_dir_all(folder)?;
+
+        let snapshot = if Self::exists(folder) {
+            Some(read_snapshot_file(folder)?)
+        } else {
+            None
+        };
+
+        let has_snapshot = snapshot.is_some();
+
+        let Snapshot {
+            seq,
+            timestamp,
+            map_desc,
+            blame,
+        } = snapshot.unwrap_or_else(|| Snapshot {
+            seq: 0,
+            timestamp: unix_timestamp(),
+            map_desc: initial_map(),
+            blame: Blame::default(),
+        });
+
+        let map = MapState::from_desc(map_desc);
+
+        let log_path = folder.join(LOG_FILE_NAME);
+
+        let mut log_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&log_path)?;
+
+        let mut bytes = vec![];
+        log_file.read_to_end(&mut bytes)?;
+
+        if bytes.is_empty() {
+            log_file.write_all(&file_header())?;
+            log_file.sync_all()?;
+
+            bytes = file_header();
+        }
+
+        let (records, valid_len) = decode_records(check_file_header(&bytes, &log_path)?);
+        let valid_len = FILE_HEADER_LEN + valid_len;
+
+        let mut entries = vec![];
+
+        for payload in records {
+            let record: Vec<LogEntry> = deserialize(payload).map_err(invalid_data)?;
+
+            // Edits up to the snapshot might still be in the log if the server stopped between
+            // writing the snapshot and truncating the log.
+            entries.extend(record.into_iter().filter(|entry| entry.seq >= seq));
+        }
+
+        let next_seq = entries.last().map_or(seq, |entry| entry.seq + 1);
+
+        if valid_len < bytes.len() {
+            log::warn!(
+                "discarding {} bytes of incomplete or corrupt data at the end of {}",
+                bytes.len() - valid_len,
+                log_path.display()
+            );
+
+            log_file.set_len(valid_len as u64)?;
+            log_file.sync_all()?;
+        }
+
+        log::info!("recovered map at edit {next_seq} from {}", folder.display());
+
+        let op_log = Self {
+            folder: folder.to_owned(),
+            log_file: Arc::new(log_file),
+            next_seq,
+            edits_since_snapshot: entries.len() as u64,
+        };
+
+        if !has_snapshot {
+            let payload = op_log.snapshot_payload(&map, &blame, timestamp)?;
+
+            write_snapshot(&op_log.folder, &op_log.log_file, &payload)?;
+        }
+
+        let recovery = Recovery {
+            snapshot: map,
+            blame,
+            save_point: SavePointDesc { seq, timestamp },
+            entries,
+        };
+
+        Ok((op_log, recovery))
+    }
+
+    /// Whether the given folder contains a snapshot to recover a map from.
+    pub fn exists(folder: &Path) -> bool {
+        folder.join(SNAPSHOT_FILE_NAME).exists()
+    }
+
+    /// Append the given edits made by the given user to the log as a single record, returning the
+    /// logged entries once they are on disk.
+    pub async fn append(&mut self, edits: Vec<Edit>, author: &str) -> io::Result<Vec<LogEntry>> {
+        let timestamp = unix_timestamp();
+
+        let entries: Vec<_> = edits
+            .into_iter()
+            .zip(self.next_seq..)
+            .map(|(edit, seq)| LogEntry {
+                seq,
+                timestamp,
+                author: author.to_owned(),
+                edit,
+            })
+            .collect();
+
+        let record = encode_record(&serialize(&entries).map_err(invalid_data)?);
+        let log_file = Arc::clone(&self.log_file);
+
+        blocking(move || {
+            (&*log_file).write_all(&record)?;
+            log_file.sync_data()
+        })
+        .await?;
+
+        self.next_seq += entries.len() as u64;
+        self.edits_since_snapshot += entries.len() as u64;
+
+        Ok(entries)
+    }
+
+    /// Whether enough edits have been logged to warrant a new snapshot.
+    pub fn needs_snapshot(&self) -> bool {
+        self.edits_since_snapshot >= SNAPSHOT_INTERVAL
+    }
+
+    /// Write a snapshot of the given map and its blame, which must include all logged edits, and
+    /// truncate the log.
+    pub async fn snapshot(&mut self, map: &MapState, blame: &Blame) -> io::Result<SavePointDesc> {
+        let timestamp = unix_timestamp();
+        let payload = self.snapshot_payload(map, blame, timestamp)?;
+        let folder = self.folder.clone();
+        let log_file = Arc::clone(&self.log_file);
+
+        blocking(move || write_snapshot(&folder, &log_file, &payload)).await?;
+
+        self.edits_since_snapshot = 0;
+
+        log::info!("wrote snapshot at edit {}", self.next_seq);
+
+        Ok(SavePointDesc {
+            seq: self.next_seq,
+            timestamp,
+        })
+    }
+
+    fn snapshot_payload(
+        &self,
+        map: &MapState,
+        blame: &Blame,
+        timestamp: u64,
+    ) -> io::Result<Vec<u8>> {
+        let snapshot = Snapshot {
+            seq: self.next_seq,
+            timestamp,
+            map_desc: map.to_desc(),
+            blame: blame.clone(),
+        };
+
+        serialize(&snapshot).map_err(invalid_data)
+    }
+}
+
+/// Replace the snapshot in the given folder with the given payload and truncate the given log.
+fn write_snapshot(folder: &Path, log_file: &File, payload: &[u8]) -> io::Result<()> {
+    let temp_path = folder.join(SNAPSHOT_TEMP_FILE_NAME);
+
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(&encode_file(payload))?;
+    temp_file.sync_all()?;
+
+    fs::rename(&temp_path, folder.join(SNAPSHOT_FILE_NAME))?;
+
+    log_file.set_len(FILE_HEADER_LEN as u64)?;
+    log_file.sync_all()
+}
+
+/// Run the given file operations on a thread that may block, so that waiting for the disk does not
+/// stall other connections while a room is locked.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    task::spawn_blocking(f).await.map_err(io::Error::other)?
+}
+
+/// Read the map of the latest snapshot in the given room folder.
+pub fn read_snapshot(folder: &Path) -> io::Result<MapDesc> {
+    Ok(read_snapshot_file(folder)?.map_desc)
+}
+
+/// Read the valid entries of the log in the given room folder.
+///
+/// The log is truncated whenever a snapshot is written, so this only contains the edits since the
+/// latest snapshot, plus possibly some before it.
+pub fn read_log(folder: &Path) -> io::Result<Vec<LogEntry>> {
+    let path = folder.join(LOG_FILE_NAME);
+    let bytes = fs::read(&path)?;
+
+    let (records, _) = decode_records(check_file_header(&bytes, &path)?);
+
+    let mut entries = vec![];
+
+    for payload in records {
+        entries.extend(deserialize::<Vec<LogEntry>>(payload).map_err(invalid_data)?);
+    }
+
+    Ok(entries)
+}
+
+fn read_snapshot_file(folder: &Path) -> io::Result<Snapshot> {
+    deserialize(&read_file(&folder.join(SNAPSHOT_FILE_NAME))?).map_err(invalid_data)
+}
+
+/// Read the payload of a file in a room folder that holds a single record.
+pub fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let bytes = fs::read(path)?;
+
+    let (records, _) = decode_records(check_file_header(&bytes, path)?);
+
+    records
+        .first()
+        .map(|payload| payload.to_vec())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is corrupt", path.display()),
+            )
+        })
+}
+
+/// Replace the file at the given path in a room folder with one that holds the given payload as a
+/// single record, through a temporary file so that a crash never leaves it half written.
+pub fn write_file(path: &Path, payload: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(&encode_file(payload))?;
+    temp_file.sync_all()?;
+
+    fs::rename(&temp_path, path)
+}
+
+/// Contents of a file in a room folder that holds the given payload as a single record.
+pub fn encode_file(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = file_header();
+    bytes.extend(encode_record(payload));
+    bytes
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend(FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// The given contents of the file at the given path without the file header, or an error if the
+/// file was written with another format version.
+fn check_file_header<'a>(bytes: &'a [u8], path: &Path) -> io::Result<&'a [u8]> {
+    let version = bytes
+        .strip_prefix(MAGIC)
+        .and_then(|rest| rest.first_chunk::<4>())
+        .map(|version| u32::from_le_bytes(*version));
+
+    match version {
+        Some(FORMAT_VERSION) => Ok(&bytes[FILE_HEADER_LEN..]),
+        version => {
+            let found = match version {
+                Some(version) => format!("format version {version}"),
+                None => "no format version".to_owned(),
+            };
+
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has {found}, but this server reads format version {FORMAT_VERSION}, \
+                     move the room folder away to host the room anew",
+                    path.display()
+                ),
+            ))
+        }
+    }
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(hash(payload).as_bytes());
+    record.extend(payload);
+    record
+}
+
+/// Decode all consecutive valid records, also returning the length of the valid prefix.
+fn decode_records(bytes: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = vec![];
+    let mut offset = 0;
+
+    while let Some(header) = bytes.get(offset..offset + RECORD_HEADER_LEN) {
+        let (len, payload_hash) = header.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().expect("length is 4 bytes")) as usize;
+
+        let payload_start = offset + RECORD_HEADER_LEN;
+
+        let Some(payload) = bytes.get(payload_start..payload_start + len) else {
+            break;
+        };
+
+        if hash(payload).as_bytes() != payload_hash {
+            break;
+        }
+
+        records.push(payload);
+        offset = payload_start + len;
+    }
+
+    (records, offset)
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn invalid_data(error: SerdeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
@@ -0,0 +1,54 @@
+//! Playing back replays into rooms.
+
+use std::sync::Arc;
+
+use shared::replay::{PlaybackSpeed, ReplayEdit};
+use tokio::{
+    select,
+    sync::Mutex,
+    time::{self, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::room::Room;
+
+/// Accept the given recorded edits into the room on behalf of their authors, at the given speed.
+///
+/// Edits that no longer apply, because members of the room changed the map in the meantime, are
+/// skipped.
+pub async fn play(
+    room: Arc<Mutex<Room>>,
+    edits: Vec<ReplayEdit>,
+    speed: PlaybackSpeed,
+    shutdown: CancellationToken,
+) {
+    let name = room.lock().await.name.clone();
+
+    log::info!("playing back {} edits in room {name:?}", edits.len());
+
+    // Deadlines are accumulated rather than sleeping for every delay, so that time spent applying
+    // edits does not slow down playback.
+    let mut deadline = Instant::now();
+
+    for ReplayEdit {
+        delay,
+        author,
+        edit,
+    } in edits
+    {
+        deadline += speed.delay(delay);
+
+        select! {
+            _ = time::sleep_until(deadline) => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        if let Err(error) = room.lock().await.accept_edit(edit, &author).await {
+            log::error!("failed to play back edit in room {name:?}: {error}");
+
+            return;
+        }
+    }
+
+    log::info!("finished playing back room {name:?}");
+}
@@ -0,0 +1,49 @@
+//! Recording the edits of rooms into replay files.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use shared::replay::{ReplayHeader, ReplayWriter};
+
+use crate::op_log::LogEntry;
+
+/// A replay file written on a thread of its own, so that recording never blocks its room.
+pub struct Recorder {
+    sender: Sender<LogEntry>,
+}
+
+impl Recorder {
+    /// Start a new replay file at the given path for the room with the given name.
+    pub fn start(path: &Path, header: &ReplayHeader, room_name: &str) -> io::Result<Self> {
+        let mut replay_writer = ReplayWriter::new(BufWriter::new(File::create_new(path)?), header)?;
+
+        let (sender, receiver) = mpsc::channel::<LogEntry>();
+        let room_name = room_name.to_owned();
+
+        thread::Builder::new()
+            .name(format!("recorder of {room_name}"))
+            .spawn(move || {
+                for entry in receiver {
+                    if let Err(error) =
+                        replay_writer.record(entry.timestamp, &entry.author, &entry.edit)
+                    {
+                        log::warn!("stopped recording room {room_name:?}: {error}");
+
+                        return;
+                    }
+                }
+            })?;
+
+        Ok(Self { sender })
+    }
+
+    /// Record the given edit, or return `false` if recording stopped.
+    pub fn record(&self, entry: &LogEntry) -> bool {
+        self.sender.send(entry.clone()).is_ok()
+    }
+}
@@ -0,0 +1,422 @@
+//! Rooms, each hosting a single map edited by its members.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs, io, iter,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use shared::{
+    deserialize, macroblock, map::MapState, patch::PatchFailure, replay::ReplayHeader, serialize,
+    Bytes, Edit, MapDesc, MapParamsDesc, MedalTimesDesc, PassthroughDesc, RoomDesc, SerdeError,
+    ServerMessage, Vec3,
+};
+use tokio::sync::{broadcast, mpsc::UnboundedSender};
+
+use crate::{
+    blame::Blame,
+    history::History,
+    op_log::{read_file, unix_timestamp, write_file, OpLog},
+    recorder::Recorder,
+    ServerEvent,
+};
+
+/// File in a room folder storing the settings of the room.
+const PARAMS_FILE_NAME: &str = "params.bin";
+
+/// File in a room folder storing the parts of the source map file the room does not model.
+const PASSTHROUGH_FILE_NAME: &str = "passthrough.bin";
+
+/// Folder in a room folder containing the replay files of the room.
+const REPLAYS_FOLDER_NAME: &str = "replays";
+
+/// A connected client that joined a room.
+pub struct Member {
+    pub user_name: String,
+    /// Outgoing frames of the client.
+    pub sender: UnboundedSender<Bytes>,
+    /// Sequence number of the first edit the client receives.
+    pub joined_seq: u64,
+}
+
+/// A single map together with everything needed to edit it collaboratively.
+pub struct Room {
+    pub name: String,
+    pub map_params_desc: MapParamsDesc,
+    params_path: PathBuf,
+    pub members: HashMap<SocketAddr, Member>,
+    pub map: MapState,
+    op_log: OpLog,
+    pub history: History,
+    pub blame: Blame,
+    /// Parts of the source map file of the room that are not part of the map, if the room was
+    /// created from a file.
+    pub passthrough: Option<PassthroughDesc>,
+    recorder: Option<Recorder>,
+    events: broadcast::Sender<ServerEvent>,
+}
+
+impl Room {
+    /// Open the room with the given name, recovering its settings and map from the given folder.
+    ///
+    /// If the folder does not contain a room yet, it is created with the settings and map
+    /// returned by `initial`.
+    pub fn open(
+        name: String,
+        folder: &Path,
+        events: broadcast::Sender<ServerEvent>,
+        initial: impl FnOnce() -> Result<(MapParamsDesc, MapDesc), Box<dyn Error>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let params_path = folder.join(PARAMS_FILE_NAME);
+
+        let stored_map_params_desc = if params_path.exists() {
+            Some(deserialize::<MapParamsDesc>(&read_file(&params_path)?)?)
+        } else {
+            None
+        };
+
+        let (map_params_desc, initial_map) = match stored_map_params_desc {
+            Some(map_params_desc) if OpLog::exists(folder) => (map_params_desc, None),
+            stored_map_params_desc => {
+                let (map_params_desc, map_desc) = initial()?;
+
+                (
+                    stored_map_params_desc.unwrap_or(map_params_desc),
+                    Some(map_desc),
+                )
+            }
+        };
+
+        let (op_log, recovery) = OpLog::open(folder, || {
+            initial_map.expect("initial map is loaded if there is no snapshot")
+        })?;
+
+        let mut map = recovery.snapshot.clone();
+        let mut blame = recovery.blame;
+        let mut history = History::new(recovery.save_point, recovery.snapshot);
+
+        for entry in recovery.entries {
+            map.apply(entry.edit.clone());
+            blame.record(&entry, &map);
+            history.push(entry);
+        }
+
+        if !params_path.exists() {
+            write_file(&params_path, &serialize(&map_params_desc)?)?;
+        }
+
+        let passthrough_path = folder.join(PASSTHROUGH_FILE_NAME);
+
+        let passthrough = if passthrough_path.exists() {
+            Some(deserialize(&read_file(&passthrough_path)?)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            name,
+            map_params_desc,
+            params_path,
+            members: HashMap::new(),
+            map,
+            op_log,
+            history,
+            blame,
+            passthrough,
+            recorder: None,
+            events,
+        })
+    }
+
+    /// Keep the given parts of the source map file of the room in the given room folder.
+    pub fn keep_passthrough(
+        &mut self,
+        folder: &Path,
+        passthrough: PassthroughDesc,
+    ) -> Result<(), Box<dyn Error>> {
+        write_file(
+            &folder.join(PASSTHROUGH_FILE_NAME),
+            &serialize(&passthrough)?,
+        )?;
+
+        self.passthrough = Some(passthrough);
+
+        Ok(())
+    }
+
+    /// Record all edits accepted from now on into a new replay file in the given room folder.
+    pub fn start_recording(&mut self, folder: &Path) -> io::Result<()> {
+        let folder = folder.join(REPLAYS_FOLDER_NAME);
+
+        fs::create_dir_all(&folder)?;
+
+        let header = ReplayHeader {
+            map_params_desc: self.map_params_desc.clone(),
+            map_desc: self.map.to_desc(),
+            timestamp: unix_timestamp(),
+        };
+
+        let path = folder.join(format!("{}.replay", header.timestamp));
+
+        self.recorder = Some(Recorder::start(&path, &header, &self.name)?);
+
+        log::info!("recording room {:?} to {}", self.name, path.display());
+
+        Ok(())
+    }
+
+    pub fn to_desc(&self) -> RoomDesc {
+        let mut members: Vec<_> = self
+            .members
+            .values()
+            .map(|member| member.user_name.clone())
+            .collect();
+
+        members.sort();
+
+        RoomDesc {
+            name: self.name.clone(),
+            members,
+        }
+    }
+
+    /// Log, apply and broadcast the given edit made by the given user if it changes the map.
+    pub async fn accept_edit(&mut self, edit: Edit, author: &str) -> Result<(), Box<dyn Error>> {
+        if !self.map.can_apply(&edit) {
+            return Ok(());
+        }
+
+        // Items attached to a removed object would be left floating, so they are removed with it
+        // on behalf of the same user, which lets all members know.
+        let attached_items: Vec<_> = match &edit {
+            Edit::Remove(object) => self.map.attached_items(object.id()).cloned().collect(),
+            _ => vec![],
+        };
+
+        let edits = iter::once(edit)
+            .chain(attached_items.into_iter().map(Edit::Remove))
+            .collect();
+
+        self.commit(edits, author).await
+    }
+
+    /// Log, apply and broadcast the given edits made by the given user, which must apply to the
+    /// map one after the other.
+    ///
+    /// The edits are logged as a single record, so they are either all recovered after a crash or
+    /// none of them.
+    async fn commit(&mut self, edits: Vec<Edit>, author: &str) -> Result<(), Box<dyn Error>> {
+        if edits.is_empty() {
+            return Ok(());
+        }
+
+        let changes_geometry = edits.iter().any(Edit::changes_geometry);
+
+        for entry in self.op_log.append(edits, author).await? {
+            self.map.apply(entry.edit.clone());
+
+            self.broadcast(&ServerMessage::Edit(entry.edit.clone()))?;
+
+            self.blame.record(&entry, &self.map);
+
+            if self
+                .recorder
+                .as_ref()
+                .is_some_and(|recorder| !recorder.record(&entry))
+            {
+                self.recorder = None;
+            }
+
+            // Having no event receivers is fine.
+            let _ = self.events.send(ServerEvent::Edit {
+                room: self.name.clone(),
+                author: entry.author.clone(),
+                seq: entry.seq,
+                edit: entry.edit.clone(),
+            });
+
+            self.history.push(entry);
+        }
+
+        if self.op_log.needs_snapshot() {
+            match self.op_log.snapshot(&self.map, &self.blame).await {
+                Ok(save_point) => self.history.add_save_point(save_point),
+                Err(error) => {
+                    log::warn!("failed to write snapshot of room {:?}: {error}", self.name)
+                }
+            }
+        }
+
+        // This is done once all edits are applied, as writing the parameters may fail.
+        if changes_geometry && self.map_params_desc.validated {
+            let mut map_params_desc = self.map_params_desc.clone();
+            map_params_desc.validated = false;
+
+            self.set_map_params(map_params_desc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Undo all edits starting at the given sequence number by accepting the reverse edits on
+    /// behalf of the given user, all of them or none.
+    ///
+    /// Returns whether the sequence number is part of the history.
+    pub async fn rollback(&mut self, seq: u64, author: &str) -> Result<bool, Box<dyn Error>> {
+        let Some(target) = self.history.map_at(seq) else {
+            return Ok(false);
+        };
+
+        let edits = self.map.edits_to(&target);
+
+        if !self
+            .map
+            .dry_run(|map| edits.iter().all(|edit| map.apply(edit.clone())))
+        {
+            return Err("reverse edits do not apply to the map".into());
+        }
+
+        self.commit(edits, author).await?;
+
+        Ok(true)
+    }
+
+    /// Insert the given macroblock on behalf of the given user, turned by the given number of
+    /// quarter turns with its smallest block coordinate at the given coordinate.
+    ///
+    /// The edits of the macroblock are checked in a dry run on the map first, and only committed if
+    /// all of them apply, as a single record. Otherwise the failures are returned and the map is
+    /// left unchanged.
+    pub async fn insert_macroblock(
+        &mut self,
+        macroblock: &MapDesc,
+        coord: Vec3<u8>,
+        quarter_turns: u8,
+        author: &str,
+    ) -> Result<Vec<PatchFailure>, Box<dyn Error>> {
+        let transform = macroblock::transform(macroblock, coord, quarter_turns);
+
+        let outcome = self
+            .map
+            .dry_run(|map| macroblock::patch(macroblock).apply(map, &transform));
+
+        let failures: Vec<_> = outcome
+            .failures
+            .into_iter()
+            .filter(macroblock::is_fatal)
+            .collect();
+
+        if !failures.is_empty() {
+            return Ok(failures);
+        }
+
+        let edits = outcome.applied.into_iter().map(|(_, edit)| edit).collect();
+
+        self.commit(edits, author).await?;
+
+        Ok(vec![])
+    }
+
+    /// Set the medal times of the map, keeping whether it is validated.
+    pub fn set_medal_times(&mut self, medal_times: MedalTimesDesc) -> Result<(), Box<dyn Error>> {
+        let mut map_params_desc = self.map_params_desc.clone();
+        map_params_desc.medal_times = medal_times;
+
+        self.set_map_params(map_params_desc)
+    }
+
+    /// Mark the map as validated with the given medal times by the member with the given address,
+    /// which received the given number of edits since joining.
+    ///
+    /// Returns whether the member had received all accepted edits, as it validated an outdated map
+    /// otherwise, which is ignored.
+    pub fn mark_validated(
+        &mut self,
+        socket_addr: SocketAddr,
+        medal_times: MedalTimesDesc,
+        received_edits: u64,
+    ) -> Result<bool, Box<dyn Error>> {
+        let Some(member) = self.members.get(&socket_addr) else {
+            return Ok(false);
+        };
+
+        if member.joined_seq + received_edits != self.history.next_seq() {
+            return Ok(false);
+        }
+
+        let mut map_params_desc = self.map_params_desc.clone();
+        map_params_desc.medal_times = medal_times;
+        map_params_desc.validated = true;
+
+        self.set_map_params(map_params_desc)?;
+
+        Ok(true)
+    }
+
+    /// Store and broadcast the given map parameters if they differ from the current ones.
+    ///
+    /// Only the medal times and whether the map is validated can change during a session.
+    fn set_map_params(&mut self, map_params_desc: MapParamsDesc) -> Result<(), Box<dyn Error>> {
+        if map_params_desc.medal_times == self.map_params_desc.medal_times
+            && map_params_desc.validated == self.map_params_desc.validated
+        {
+            return Ok(());
+        }
+
+        write_file(&self.params_path, &serialize(&map_params_desc)?)?;
+
+        self.map_params_desc = map_params_desc;
+
+        self.broadcast(&ServerMessage::MapParams(self.map_params_desc.clone()))?;
+
+        let _ = self.events.send(ServerEvent::MapParams {
+            room: self.name.clone(),
+            map_params_desc: self.map_params_desc.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// Broadcast a chat message of the given user.
+    pub fn chat(&self, user_name: &str, text: String) {
+        let message = ServerMessage::Chat {
+            user_name: user_name.to_owned(),
+            text: text.clone(),
+        };
+
+        if let Err(error) = self.broadcast(&message) {
+            log::error!("failed to broadcast chat message: {error}");
+        }
+
+        let _ = self.events.send(ServerEvent::Chat {
+            room: self.name.clone(),
+            user_name: user_name.to_owned(),
+            text,
+        });
+    }
+
+    /// Send the given message to all members.
+    pub fn broadcast(&self, message: &ServerMessage) -> Result<(), SerdeError> {
+        let frame = Bytes::from(serialize(message)?);
+
+        for member in self.members.values() {
+            // A closed channel means the client is disconnecting, which is handled by its own task.
+            let _ = member.sender.send(frame.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the given name can be used for a room.
+///
+/// Room names are used as folder names, so only a conservative set of characters is allowed.
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,324 @@
+//! Harness running a server and scripted fake clients over in-memory connections.
+//!
+//! Clients are driven one step at a time from a single task, so a test controls exactly when each
+//! client sends edits and when it processes the messages queued for it. After a schedule has run,
+//! [`Harness::settle`] drains all queues and checks that the map of every client equals the map of
+//! the server, which is what the `StateInvariant` of `models/Sync.tla` requires once all queues are
+//! empty. The map of the server is taken from a client joining afterwards, so it is the map the
+//! server holds rather than one built from the same broadcast edits as the client maps.
+
+#![allow(dead_code)]
+
+use std::{path::Path, time::Duration};
+
+use futures_util::{FutureExt, SinkExt, TryStreamExt};
+use shared::{
+    deserialize, map::MapState, patch::PatchFailure, serialize, Bytes, ClientMessage, Edit,
+    FramedStream, Handshake, HandshakeResponse, HistoryDesc, MapDesc, MapParamsDesc,
+    MedalTimesDesc, Mood, ServerMessage, Vec3,
+};
+use tempfile::TempDir;
+use tm_sync_edit_server::Server;
+use tokio::{io::DuplexStream, task, time};
+
+pub const ROOM_NAME: &str = "test";
+
+/// Time after which a client waiting for a message is considered stuck.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A single action of a fake client, matching the actions of a client in `models/Sync.tla`.
+#[derive(Clone)]
+pub enum Step {
+    /// Send the given edit to the server.
+    Edit { client: usize, edit: Edit },
+    /// Process the next message queued for the client, if there is one.
+    Receive { client: usize },
+}
+
+pub struct Harness {
+    server: Server,
+    pub clients: Vec<FakeClient>,
+    /// Sequence number of the first edit every client receives.
+    joined_seq: u64,
+    // Kept alive until the harness is dropped, as the server stores its rooms in there.
+    _data_folder: TempDir,
+}
+
+impl Harness {
+    /// Start a server hosting the given map and connect the given number of clients to it.
+    pub async fn start(map_desc: MapDesc, num_clients: usize) -> Self {
+        let data_folder = TempDir::new().unwrap();
+
+        let server = start_server(data_folder.path(), map_desc);
+
+        Self::connect(server, num_clients, data_folder).await
+    }
+
+    /// Stop the server and start it again from its data folder, reconnecting all clients.
+    pub async fn restart(self) -> Self {
+        self.restart_with(|_| {}).await
+    }
+
+    /// Stop the server, change the files of the room with the given function, as a crash could
+    /// have left them, and start the server again, reconnecting all clients.
+    pub async fn restart_with(self, damage: impl FnOnce(&Path)) -> Self {
+        let num_clients = self.clients.len();
+
+        drop(self.clients);
+        self.server.shutdown().await;
+
+        damage(&self._data_folder.path().join(ROOM_NAME));
+
+        // The room recovers its map from the data folder, so the initial map is not used.
+        let server = start_server(self._data_folder.path(), MapDesc::default());
+
+        Self::connect(server, num_clients, self._data_folder).await
+    }
+
+    async fn connect(server: Server, num_clients: usize, data_folder: TempDir) -> Self {
+        let mut clients = vec![];
+
+        for index in 0..num_clients {
+            clients.push(FakeClient::join(&server, &format!("client-{index}")).await);
+        }
+
+        let joined_seq = match clients.first_mut() {
+            Some(client) => client.history().await.next_seq,
+            None => 0,
+        };
+
+        Self {
+            server,
+            clients,
+            joined_seq,
+            _data_folder: data_folder,
+        }
+    }
+
+    /// Run the given steps in order.
+    pub async fn run(&mut self, steps: impl IntoIterator<Item = Step>) {
+        for step in steps {
+            match step {
+                Step::Edit { client, edit } => self.clients[client].edit(edit).await,
+                Step::Receive { client } => {
+                    // Let the server make progress, as it would concurrently.
+                    task::yield_now().await;
+
+                    self.clients[client].try_receive();
+                }
+            }
+        }
+    }
+
+    /// Wait until the server has handled every edit sent so far and every client has processed
+    /// every edit broadcast to it, then check that every client map equals the server map.
+    ///
+    /// Returns the map of the server.
+    pub async fn settle(&mut self) -> MapState {
+        // Messages of a single connection are handled in order, so once every client got a reply
+        // the server queue is empty.
+        let mut history = None;
+
+        for client in &mut self.clients {
+            history = Some(client.history().await);
+        }
+
+        let HistoryDesc { next_seq, .. } = history.expect("harness has clients");
+
+        // Every client joined before the first edit since the server started and receives every
+        // accepted edit, including its own.
+        for client in &mut self.clients {
+            while client.received_edits < next_seq - self.joined_seq {
+                client.receive().await;
+            }
+        }
+
+        for client in &mut self.clients {
+            assert!(
+                client.try_receive().is_none(),
+                "{} received a message after all edits",
+                client.user_name
+            );
+        }
+
+        let server_map = self.server_map().await;
+
+        for client in &self.clients {
+            assert!(
+                client.map == server_map,
+                "map of {} diverged from the server map",
+                client.user_name
+            );
+        }
+
+        server_map
+    }
+
+    /// Current map of the server, as seen by a client joining now.
+    pub async fn server_map(&self) -> MapState {
+        FakeClient::join(&self.server, "observer").await.map
+    }
+
+    pub async fn shutdown(self) {
+        self.server.shutdown().await;
+    }
+}
+
+fn start_server(data_folder: &Path, map_desc: MapDesc) -> Server {
+    let map_params_desc = MapParamsDesc {
+        mood: Mood::Day,
+        medal_times: MedalTimesDesc::default(),
+        validated: false,
+    };
+
+    Server::builder()
+        .data_folder(data_folder)
+        .map(ROOM_NAME, map_params_desc, map_desc)
+        .start()
+        .unwrap()
+}
+
+/// A scripted client that keeps its own copy of the map, like the game client does.
+pub struct FakeClient {
+    pub user_name: String,
+    pub map: MapState,
+    pub map_params_desc: MapParamsDesc,
+    /// Number of edits received from the server since joining.
+    pub received_edits: u64,
+    framed_stream: FramedStream<DuplexStream>,
+}
+
+impl FakeClient {
+    pub async fn join(server: &Server, user_name: &str) -> Self {
+        let mut framed_stream = server.connect_in_memory();
+
+        let handshake = Handshake::Join {
+            room: ROOM_NAME.to_owned(),
+            user_name: user_name.to_owned(),
+        };
+
+        framed_stream
+            .send(Bytes::from(serialize(&handshake).unwrap()))
+            .await
+            .unwrap();
+
+        let frame = framed_stream.try_next().await.unwrap().unwrap();
+
+        let map_params_desc = match deserialize(&frame).unwrap() {
+            HandshakeResponse::Joined(map_params_desc) => map_params_desc,
+            _ => panic!("{user_name} failed to join"),
+        };
+
+        let frame = framed_stream.try_next().await.unwrap().unwrap();
+        let map_desc: MapDesc = deserialize(&frame).unwrap();
+
+        Self {
+            user_name: user_name.to_owned(),
+            map: MapState::from_desc(map_desc),
+            map_params_desc,
+            received_edits: 0,
+            framed_stream,
+        }
+    }
+
+    pub async fn send(&mut self, message: &ClientMessage) {
+        self.framed_stream
+            .send(Bytes::from(serialize(message).unwrap()))
+            .await
+            .unwrap();
+    }
+
+    /// Send the given edit without applying it locally, the server echoes it if it is accepted.
+    pub async fn edit(&mut self, edit: Edit) {
+        self.send(&ClientMessage::Edit(edit)).await;
+    }
+
+    /// Wait for the next message, applying it if it is an edit or new map parameters.
+    pub async fn receive(&mut self) -> ServerMessage {
+        let frame = time::timeout(RECEIVE_TIMEOUT, self.framed_stream.try_next())
+            .await
+            .unwrap_or_else(|_| panic!("{} timed out waiting for a message", self.user_name))
+            .unwrap()
+            .unwrap_or_else(|| panic!("server closed the connection of {}", self.user_name));
+
+        self.process(&frame)
+    }
+
+    /// Process the next message if one is queued already.
+    pub fn try_receive(&mut self) -> Option<ServerMessage> {
+        let frame = self.framed_stream.try_next().now_or_never()?.unwrap()?;
+
+        Some(self.process(&frame))
+    }
+
+    fn process(&mut self, frame: &[u8]) -> ServerMessage {
+        let message = deserialize(frame).unwrap();
+
+        if let ServerMessage::Edit(edit) = &message {
+            assert!(
+                self.map.apply(edit.clone()),
+                "{} received an edit that does not apply to its map",
+                self.user_name
+            );
+
+            self.received_edits += 1;
+        }
+
+        if let ServerMessage::MapParams(map_params_desc) = &message {
+            self.map_params_desc = map_params_desc.clone();
+        }
+
+        message
+    }
+
+    /// Request the edit history, processing all edits received before the reply.
+    pub async fn history(&mut self) -> HistoryDesc {
+        self.send(&ClientMessage::GetHistory).await;
+
+        loop {
+            if let ServerMessage::History(history) = self.receive().await {
+                return history;
+            }
+        }
+    }
+
+    /// Insert the given macroblock and wait for the reply, processing all edits received before
+    /// it. Returns whether it was inserted and the edits that do not apply.
+    pub async fn insert_macroblock(
+        &mut self,
+        macroblock: MapDesc,
+        coord: Vec3<u8>,
+        quarter_turns: u8,
+    ) -> (bool, Vec<PatchFailure>) {
+        self.send(&ClientMessage::InsertMacroblock {
+            macroblock: Box::new(macroblock),
+            coord,
+            quarter_turns,
+        })
+        .await;
+
+        loop {
+            if let ServerMessage::InsertMacroblock { inserted, failures } = self.receive().await {
+                return (inserted, failures);
+            }
+        }
+    }
+}
+
+/// Deterministic pseudo random numbers for generating schedules from a seed.
+pub struct Lcg(u64);
+
+impl Lcg {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+
+        (self.0 >> 33) % bound
+    }
+}
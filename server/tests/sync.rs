@@ -0,0 +1,890 @@
+mod harness;
+
+use std::{fs, time::Duration};
+
+use gamebox::{
+    engines::game::map::{Direction, ElemColor, PhaseOffset},
+    Vec3,
+};
+use harness::{FakeClient, Harness, Lcg, Step, ROOM_NAME};
+use shared::{
+    macroblock::{extract, Selection},
+    map::{EditRejection, MapState},
+    patch::PatchFailureReason,
+    replay::{PlaybackSpeed, Replay, ReplayEdit, ReplayHeader},
+    AttachmentDesc, BlockDesc, ClientMessage, Edit, ItemDesc, MapDesc, MapParamsDesc,
+    MedalTimesDesc, ModelId, Mood, NotNan, ObjectDesc, OffzoneDesc, ServerMessage,
+};
+use tempfile::TempDir;
+use tm_sync_edit_server::Server;
+
+fn empty_map() -> MapDesc {
+    MapDesc {
+        custom_blocks: vec![],
+        custom_items: vec![],
+        custom_skins: vec![],
+        embedded_files: vec![],
+        blocks: vec![],
+        ghost_blocks: vec![],
+        free_blocks: vec![],
+        items: vec![],
+        offzones: vec![],
+    }
+}
+
+fn block(x: u8, z: u8) -> ObjectDesc {
+    ObjectDesc::Block(BlockDesc {
+        block_info_id: ModelId::Game {
+            id: "RoadTechStraight".to_owned(),
+        },
+        coord: Vec3 { x, y: 9, z },
+        dir: Direction::North,
+        is_air_variant: false,
+        variant_index: 0,
+        subvariant_index: 0,
+        elem_color: ElemColor::Default,
+        waypoint: None,
+        skin: None,
+    })
+}
+
+fn offzone(min: (u8, u8, u8), max: (u8, u8, u8)) -> OffzoneDesc {
+    OffzoneDesc {
+        min: Vec3 {
+            x: min.0,
+            y: min.1,
+            z: min.2,
+        },
+        max: Vec3 {
+            x: max.0,
+            y: max.1,
+            z: max.2,
+        },
+    }
+}
+
+fn offzone_volume(map: &MapState) -> u32 {
+    map.offzones()
+        .iter()
+        .map(|offzone| {
+            (offzone.max.x - offzone.min.x + 1) as u32
+                * (offzone.max.y - offzone.min.y + 1) as u32
+                * (offzone.max.z - offzone.min.z + 1) as u32
+        })
+        .sum()
+}
+
+fn item_on(object: &ObjectDesc) -> ObjectDesc {
+    let position = object.position();
+    let position = Vec3 {
+        x: NotNan::new(position.x).unwrap(),
+        y: NotNan::new(position.y).unwrap(),
+        z: NotNan::new(position.z).unwrap(),
+    };
+
+    ObjectDesc::Item(ItemDesc {
+        item_model_id: ModelId::Game {
+            id: "Flag".to_owned(),
+        },
+        position,
+        yaw: NotNan::default(),
+        pitch: NotNan::default(),
+        roll: NotNan::default(),
+        pivot_position: position,
+        elem_color: ElemColor::Default,
+        anim_offset: PhaseOffset::None,
+        waypoint: None,
+        skin: None,
+        attachment: Some(AttachmentDesc {
+            object_id: object.id(),
+        }),
+    })
+}
+
+#[tokio::test]
+async fn concurrent_edits_of_the_same_object_converge() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(1, 1)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(block(1, 1)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Remove(block(1, 1)),
+            },
+            Step::Receive { client: 0 },
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(1, 1)),
+            },
+        ])
+        .await;
+
+    harness.settle().await;
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn random_schedules_converge() {
+    const NUM_CLIENTS: usize = 4;
+
+    for seed in 0..16 {
+        let mut harness = Harness::start(empty_map(), NUM_CLIENTS).await;
+        let mut lcg = Lcg::new(seed);
+
+        let steps: Vec<_> = (0..64)
+            .map(|_| {
+                let client = lcg.next_below(NUM_CLIENTS as u64) as usize;
+
+                if lcg.next_below(2) == 0 {
+                    return Step::Receive { client };
+                }
+
+                // A small grid makes clients edit the same objects often.
+                let object = block(lcg.next_below(3) as u8, lcg.next_below(3) as u8);
+
+                let edit = if lcg.next_below(2) == 0 {
+                    Edit::Place(object)
+                } else {
+                    Edit::Remove(object)
+                };
+
+                Step::Edit { client, edit }
+            })
+            .collect();
+
+        harness.run(steps).await;
+        harness.settle().await;
+        harness.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn rollback_converges() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(0, 0)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(block(0, 1)),
+            },
+        ])
+        .await;
+
+    let before = harness.settle().await;
+    let seq = harness.clients[0].history().await.next_seq;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Remove(block(0, 0)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(block(2, 2)),
+            },
+        ])
+        .await;
+
+    harness.clients[1]
+        .send(&ClientMessage::Rollback { seq })
+        .await;
+
+    let after = harness.settle().await;
+
+    assert!(after == before);
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn late_joiner_gets_the_current_map() {
+    let mut map_desc = empty_map();
+
+    if let ObjectDesc::Block(block) = block(5, 5) {
+        map_desc.blocks.push(block);
+    }
+
+    let mut harness = Harness::start(map_desc, 1).await;
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Place(block(6, 6)),
+        }])
+        .await;
+
+    let server_map = harness.settle().await;
+
+    assert!(server_map.object(block(5, 5).id()).is_some());
+    assert!(server_map.object(block(6, 6).id()).is_some());
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn removing_a_block_removes_attached_items() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(3, 3)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(block(4, 4)),
+            },
+        ])
+        .await;
+
+    harness.settle().await;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(item_on(&block(3, 3))),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(item_on(&block(4, 4))),
+            },
+        ])
+        .await;
+
+    harness.settle().await;
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Remove(block(3, 3)),
+        }])
+        .await;
+
+    let server_map = harness.settle().await;
+
+    assert!(server_map.object(item_on(&block(3, 3)).id()).is_none());
+    assert!(server_map.object(item_on(&block(4, 4)).id()).is_some());
+    assert_eq!(server_map.attached_items(block(4, 4).id()).count(), 1);
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn modifying_a_block_keeps_attached_items() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    let mut recoloured = block(3, 3);
+
+    if let ObjectDesc::Block(block) = &mut recoloured {
+        block.elem_color = ElemColor::Red;
+    }
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(3, 3)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(item_on(&block(3, 3))),
+            },
+        ])
+        .await;
+
+    let before = harness.settle().await;
+    let seq = harness.clients[0].history().await.next_seq;
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Modify {
+                old: Box::new(block(3, 3)),
+                new: Box::new(recoloured.clone()),
+            },
+        }])
+        .await;
+
+    let server_map = harness.settle().await;
+
+    assert!(server_map.object(item_on(&recoloured).id()).is_some());
+    assert_eq!(server_map.attached_items(recoloured.id()).count(), 1);
+
+    // Rolling back the change of colour keeps the item as well.
+    harness.clients[1]
+        .send(&ClientMessage::Rollback { seq })
+        .await;
+
+    let after = harness.settle().await;
+
+    assert!(after == before);
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn overlapping_offzone_edits_converge() {
+    let mut harness = Harness::start(empty_map(), 3).await;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::AddOffzone(offzone((0, 0, 0), (3, 3, 3))),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::AddOffzone(offzone((2, 2, 2), (5, 5, 5))),
+            },
+        ])
+        .await;
+
+    let server_map = harness.settle().await;
+
+    assert_eq!(offzone_volume(&server_map), 64 + 64 - 8);
+
+    harness
+        .run([
+            Step::Edit {
+                client: 2,
+                edit: Edit::RemoveOffzone(offzone((1, 0, 1), (4, 1, 4))),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::AddOffzone(offzone((10, 0, 10), (10, 0, 10))),
+            },
+        ])
+        .await;
+
+    let server_map = harness.settle().await;
+
+    // The removed box only overlaps the first one, and does so by 3 x 2 x 3 coordinates.
+    assert_eq!(offzone_volume(&server_map), 64 + 64 - 8 - 18 + 1);
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn geometry_edits_invalidate_the_map() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    let medal_times = MedalTimesDesc {
+        author: Some(30_000),
+        gold: Some(32_000),
+        silver: Some(36_000),
+        bronze: Some(45_000),
+    };
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Place(block(1, 1)),
+        }])
+        .await;
+
+    // Whether this is handled before or after the edit, the map ends up unvalidated.
+    harness.clients[1]
+        .send(&ClientMessage::MarkValidated {
+            medal_times,
+            received_edits: 0,
+        })
+        .await;
+
+    harness.settle().await;
+
+    assert!(harness
+        .clients
+        .iter()
+        .all(|client| !client.map_params_desc.validated));
+
+    let received_edits = harness.clients[1].received_edits;
+
+    harness.clients[1]
+        .send(&ClientMessage::MarkValidated {
+            medal_times,
+            received_edits,
+        })
+        .await;
+
+    harness.settle().await;
+
+    assert!(harness.clients.iter().all(|client| {
+        client.map_params_desc.validated && client.map_params_desc.medal_times == medal_times
+    }));
+
+    let medal_times = MedalTimesDesc {
+        gold: Some(31_000),
+        ..medal_times
+    };
+
+    harness.clients[0]
+        .send(&ClientMessage::SetMedalTimes(medal_times))
+        .await;
+
+    harness.settle().await;
+
+    assert!(harness.clients.iter().all(|client| {
+        client.map_params_desc.validated && client.map_params_desc.medal_times == medal_times
+    }));
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Remove(block(1, 1)),
+        }])
+        .await;
+
+    harness.settle().await;
+
+    assert!(harness.clients.iter().all(|client| {
+        !client.map_params_desc.validated && client.map_params_desc.medal_times == medal_times
+    }));
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn macroblocks_are_inserted_as_a_whole() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    let macroblock = MapDesc {
+        blocks: [block(0, 0), block(1, 0)]
+            .into_iter()
+            .map(|object| match object {
+                ObjectDesc::Block(block) => block,
+                _ => unreachable!(),
+            })
+            .collect(),
+        items: [item_on(&block(0, 0))]
+            .into_iter()
+            .map(|object| match object {
+                ObjectDesc::Item(item) => item,
+                _ => unreachable!(),
+            })
+            .collect(),
+        ..empty_map()
+    };
+
+    let turned = |x, z| {
+        let mut object = block(x, z);
+
+        if let ObjectDesc::Block(block) = &mut object {
+            block.dir = Direction::East;
+        }
+
+        object
+    };
+
+    let (inserted, failures) = harness.clients[0]
+        .insert_macroblock(macroblock.clone(), Vec3 { x: 10, y: 9, z: 10 }, 1)
+        .await;
+
+    assert!(inserted && failures.is_empty());
+
+    let server_map = harness.settle().await;
+
+    assert_eq!(server_map.objects().count(), 3);
+    assert!(server_map.object(turned(10, 10).id()).is_some());
+    assert_eq!(server_map.attached_items(turned(10, 11).id()).count(), 1);
+
+    // One of the blocks would end up where the same block already is.
+    let (inserted, failures) = harness.clients[1]
+        .insert_macroblock(macroblock, Vec3 { x: 10, y: 9, z: 9 }, 1)
+        .await;
+
+    assert!(!inserted);
+    assert_eq!(failures.len(), 1);
+    assert_eq!(
+        failures[0].reason,
+        PatchFailureReason::Rejected(EditRejection::ObjectExists)
+    );
+
+    let server_map = harness.settle().await;
+
+    assert_eq!(server_map.objects().count(), 3);
+    assert!(server_map.object(turned(10, 9).id()).is_none());
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn extracted_macroblocks_insert_elsewhere() {
+    let mut harness = Harness::start(empty_map(), 1).await;
+
+    harness
+        .run(
+            [
+                block(10, 10),
+                block(11, 10),
+                item_on(&block(10, 10)),
+                block(20, 20),
+            ]
+            .into_iter()
+            .map(|object| Step::Edit {
+                client: 0,
+                edit: Edit::Place(object),
+            }),
+        )
+        .await;
+
+    let server_map = harness.settle().await;
+
+    let selection = Selection::Objects([block(10, 10).id(), block(11, 10).id()].into());
+    let Ok(macroblock) = extract(&server_map, &selection, None) else {
+        panic!("failed to extract macroblock");
+    };
+
+    assert_eq!(macroblock.blocks.len(), 2);
+    assert_eq!(macroblock.items.len(), 1);
+    assert!(macroblock.items[0].attachment.is_some());
+
+    harness.clients[0]
+        .send(&ClientMessage::InsertMacroblock {
+            macroblock: Box::new(macroblock),
+            coord: Vec3 { x: 30, y: 9, z: 30 },
+            quarter_turns: 0,
+        })
+        .await;
+
+    let server_map = harness.settle().await;
+
+    assert_eq!(server_map.objects().count(), 7);
+    assert!(server_map.object(block(31, 30).id()).is_some());
+    assert_eq!(server_map.attached_items(block(30, 30).id()).count(), 1);
+
+    // Attachments to objects that are not extracted are dropped.
+    let selection = Selection::Objects([item_on(&block(10, 10)).id()].into());
+    let Ok(macroblock) = extract(&server_map, &selection, None) else {
+        panic!("failed to extract macroblock");
+    };
+
+    assert_eq!(macroblock.items.len(), 1);
+    assert!(macroblock.items[0].attachment.is_none());
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn edits_survive_a_restart() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(1, 1)),
+            },
+            Step::Edit {
+                client: 1,
+                edit: Edit::Place(block(2, 2)),
+            },
+            Step::Edit {
+                client: 0,
+                edit: Edit::Remove(block(1, 1)),
+            },
+        ])
+        .await;
+
+    let before = harness.settle().await;
+
+    let mut harness = harness.restart().await;
+
+    let after = harness.settle().await;
+
+    assert!(after == before);
+    assert_eq!(harness.clients[0].history().await.next_seq, 3);
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn torn_log_records_are_discarded() {
+    // A record cut off after its header, and bytes that are no record at all.
+    let mut half_written = 100u32.to_le_bytes().to_vec();
+    half_written.extend([0; 32 + 10]);
+
+    for tail in [half_written, b"garbage".repeat(10)] {
+        let mut harness = Harness::start(empty_map(), 1).await;
+
+        harness
+            .run([
+                Step::Edit {
+                    client: 0,
+                    edit: Edit::Place(block(1, 1)),
+                },
+                Step::Edit {
+                    client: 0,
+                    edit: Edit::Place(block(2, 2)),
+                },
+            ])
+            .await;
+
+        let before = harness.settle().await;
+        let mut log_len = 0;
+
+        let mut harness = harness
+            .restart_with(|folder| {
+                let mut log = fs::read(folder.join("edits.log")).unwrap();
+                log_len = log.len();
+                log.extend(&tail);
+                fs::write(folder.join("edits.log"), log).unwrap();
+            })
+            .await;
+
+        let after = harness.settle().await;
+
+        assert!(after == before);
+        assert_eq!(harness.clients[0].history().await.next_seq, 2);
+
+        // Edits logged after the recovery must not end up behind the discarded bytes.
+        harness
+            .run([Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(3, 3)),
+            }])
+            .await;
+
+        let before = harness.settle().await;
+
+        let mut harness = harness
+            .restart_with(|folder| {
+                let log = fs::read(folder.join("edits.log")).unwrap();
+                assert!(!log.ends_with(&tail[tail.len() - 8..]));
+                assert!(log.len() > log_len);
+            })
+            .await;
+
+        let after = harness.settle().await;
+
+        assert!(after == before);
+        assert_eq!(harness.clients[0].history().await.next_seq, 3);
+
+        harness.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn edits_logged_before_the_snapshot_are_not_applied_again() {
+    let mut harness = Harness::start(empty_map(), 1).await;
+
+    // Enough edits in a single record to make the next edit write a snapshot.
+    let macroblock = MapDesc {
+        blocks: (0..32)
+            .flat_map(|x| (0..32).map(move |z| (x, z)))
+            .skip(1)
+            .map(|(x, z)| match block(x, z) {
+                ObjectDesc::Block(block) => block,
+                _ => unreachable!(),
+            })
+            .collect(),
+        ..empty_map()
+    };
+
+    harness.clients[0]
+        .send(&ClientMessage::InsertMacroblock {
+            macroblock: Box::new(macroblock),
+            coord: Vec3 { x: 0, y: 9, z: 1 },
+            quarter_turns: 0,
+        })
+        .await;
+
+    harness.settle().await;
+
+    let mut log = vec![];
+
+    let mut harness = harness
+        .restart_with(|folder| log = fs::read(folder.join("edits.log")).unwrap())
+        .await;
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Place(block(0, 0)),
+        }])
+        .await;
+
+    let before = harness.settle().await;
+
+    // As if the server stopped after writing the snapshot but before truncating the log.
+    let mut harness = harness
+        .restart_with(|folder| {
+            assert!(fs::read(folder.join("edits.log")).unwrap().len() < log.len());
+            fs::write(folder.join("edits.log"), &log).unwrap();
+        })
+        .await;
+
+    let after = harness.settle().await;
+
+    assert!(after == before);
+    assert_eq!(after.objects().count(), 1024);
+    assert_eq!(harness.clients[0].history().await.next_seq, 1024);
+
+    harness.shutdown().await;
+}
+
+#[test]
+fn rooms_of_other_format_versions_are_refused() {
+    let data_folder = TempDir::new().unwrap();
+    let folder = data_folder.path().join(ROOM_NAME);
+
+    fs::create_dir(&folder).unwrap();
+    fs::write(folder.join("params.bin"), [0; 16]).unwrap();
+
+    let map_params_desc = MapParamsDesc {
+        mood: Mood::Day,
+        medal_times: MedalTimesDesc::default(),
+        validated: false,
+    };
+
+    let Err(error) = Server::builder()
+        .data_folder(data_folder.path())
+        .map(ROOM_NAME, map_params_desc, empty_map())
+        .start()
+    else {
+        panic!("room with an unknown format version was opened");
+    };
+
+    assert!(error.to_string().contains("format version"));
+}
+
+#[tokio::test]
+async fn rollback_reaches_back_across_a_restart() {
+    let mut harness = Harness::start(empty_map(), 1).await;
+
+    harness
+        .run([Step::Edit {
+            client: 0,
+            edit: Edit::Place(block(1, 1)),
+        }])
+        .await;
+
+    let before = harness.settle().await;
+    let seq = harness.clients[0].history().await.next_seq;
+
+    harness
+        .run([
+            Step::Edit {
+                client: 0,
+                edit: Edit::Remove(block(1, 1)),
+            },
+            Step::Edit {
+                client: 0,
+                edit: Edit::Place(block(2, 2)),
+            },
+        ])
+        .await;
+
+    harness.settle().await;
+
+    let mut harness = harness.restart().await;
+
+    harness.clients[0]
+        .send(&ClientMessage::Rollback { seq })
+        .await;
+
+    loop {
+        if let ServerMessage::Rollback { rolled_back, .. } = harness.clients[0].receive().await {
+            assert!(rolled_back);
+            break;
+        }
+    }
+
+    let after = harness.settle().await;
+
+    assert!(after == before);
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn blame_survives_a_restart() {
+    let mut harness = Harness::start(empty_map(), 2).await;
+
+    harness
+        .run([Step::Edit {
+            client: 1,
+            edit: Edit::Place(block(3, 3)),
+        }])
+        .await;
+
+    harness.settle().await;
+
+    let mut harness = harness.restart().await;
+
+    harness.clients[0]
+        .send(&ClientMessage::BlameObject {
+            id: block(3, 3).id(),
+        })
+        .await;
+
+    loop {
+        if let ServerMessage::Blame(blame) = harness.clients[0].receive().await {
+            assert_eq!(blame.len(), 1);
+            assert_eq!(blame[0].edit.as_ref().unwrap().author, "client-1");
+            break;
+        }
+    }
+
+    harness.shutdown().await;
+}
+
+#[tokio::test]
+async fn replay_rooms_start_over_after_a_restart() {
+    let data_folder = TempDir::new().unwrap();
+
+    let replay = Replay {
+        header: ReplayHeader {
+            map_params_desc: MapParamsDesc {
+                mood: Mood::Day,
+                medal_times: MedalTimesDesc::default(),
+                validated: false,
+            },
+            map_desc: empty_map(),
+            timestamp: 0,
+        },
+        edits: [block(1, 1), block(2, 2)]
+            .map(|object| ReplayEdit {
+                delay: Duration::ZERO,
+                author: "recorded".to_owned(),
+                edit: Edit::Place(object),
+            })
+            .into(),
+    };
+
+    let speed = PlaybackSpeed {
+        speed: 1.0,
+        max_delay: Duration::ZERO,
+    };
+
+    for _ in 0..2 {
+        let server = Server::builder()
+            .data_folder(data_folder.path())
+            .replay(ROOM_NAME, replay.clone(), speed)
+            .start()
+            .unwrap();
+
+        let mut client = FakeClient::join(&server, "observer").await;
+
+        while client.map.objects().count() < 2 {
+            client.receive().await;
+        }
+
+        server.shutdown().await;
+    }
+}
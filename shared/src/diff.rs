@@ -0,0 +1,568 @@
+//! Structural differences between maps.
+
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use serde::Serialize;
+
+use gamebox::Vec3;
+
+use crate::{
+    map::MapState, offzone, serialize, CustomBlockDesc, CustomItemDesc, Edit, EmbeddedFileDesc,
+    Hash, MapDesc, ModelId, NotNan, ObjectDesc, ObjectId, OffzoneDesc,
+};
+
+/// Differences between an old and a new map.
+///
+/// Objects have no identity of their own, so an object of the old map is considered the same as
+/// an object of the new map if both are of the same kind and model and are either at the same
+/// position or differ only in position. Objects of the same kind and model that are left over are
+/// paired with the nearest one, as they were moved and changed otherwise as well.
+#[derive(Clone, Default)]
+pub struct MapDiff {
+    pub custom_blocks_added: Vec<CustomBlockDesc>,
+    pub custom_blocks_removed: Vec<CustomBlockDesc>,
+    pub custom_items_added: Vec<CustomItemDesc>,
+    pub custom_items_removed: Vec<CustomItemDesc>,
+    pub custom_skins_added: Vec<EmbeddedFileDesc>,
+    pub custom_skins_removed: Vec<EmbeddedFileDesc>,
+    pub embedded_files_added: Vec<EmbeddedFileDesc>,
+    pub embedded_files_removed: Vec<EmbeddedFileDesc>,
+    pub added: Vec<ObjectDesc>,
+    pub removed: Vec<ObjectDesc>,
+    pub modified: Vec<ObjectModification>,
+    /// Boxes of coordinates that became offzone, see [`offzone`].
+    pub offzones_added: Vec<OffzoneDesc>,
+    /// Boxes of coordinates that are no longer offzone.
+    pub offzones_removed: Vec<OffzoneDesc>,
+}
+
+/// An object that is in both maps, but changed.
+#[derive(Clone, Serialize)]
+pub struct ObjectModification {
+    pub old: ObjectDesc,
+    pub new: ObjectDesc,
+    pub changes: Vec<ObjectChange>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum ObjectChange {
+    Moved,
+    Rotated,
+    Recoloured,
+    /// The waypoint properties changed, such as the order of a checkpoint.
+    Waypoint,
+    Reskinned,
+    Reanimated,
+    /// The item was attached to another object, or detached.
+    Reattached,
+    /// Any other property changed, such as the air variant of a block or the pivot of an item.
+    Other,
+}
+
+impl MapDiff {
+    pub fn new(old: &MapDesc, new: &MapDesc) -> Self {
+        let mut diff = Self {
+            custom_blocks_added: difference(&new.custom_blocks, &old.custom_blocks, |c| c.hash()),
+            custom_blocks_removed: difference(&old.custom_blocks, &new.custom_blocks, |c| c.hash()),
+            custom_items_added: difference(&new.custom_items, &old.custom_items, |c| c.hash()),
+            custom_items_removed: difference(&old.custom_items, &new.custom_items, |c| c.hash()),
+            custom_skins_added: difference(&new.custom_skins, &old.custom_skins, |c| c.hash()),
+            custom_skins_removed: difference(&old.custom_skins, &new.custom_skins, |c| c.hash()),
+            embedded_files_added: difference(&new.embedded_files, &old.embedded_files, |f| {
+                f.hash()
+            }),
+            embedded_files_removed: difference(&old.embedded_files, &new.embedded_files, |f| {
+                f.hash()
+            }),
+            offzones_added: offzone::difference(&new.offzones, &old.offzones),
+            offzones_removed: offzone::difference(&old.offzones, &new.offzones),
+            ..Self::default()
+        };
+
+        (diff.removed, diff.added, diff.modified) =
+            object_diff(&objects_by_id(old), &objects_by_id(new));
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.custom_blocks_added.is_empty()
+            && self.custom_blocks_removed.is_empty()
+            && self.custom_items_added.is_empty()
+            && self.custom_items_removed.is_empty()
+            && self.custom_skins_added.is_empty()
+            && self.custom_skins_removed.is_empty()
+            && self.embedded_files_added.is_empty()
+            && self.embedded_files_removed.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.offzones_added.is_empty()
+            && self.offzones_removed.is_empty()
+    }
+
+    /// Edits that turn the old map into the new map.
+    ///
+    /// Custom objects and skins are only ever added, as they may still be used by objects of other
+    /// edits.
+    pub fn edits(&self) -> Vec<Edit> {
+        let mut edits = vec![];
+
+        // Custom objects can only be added once the files they depend on are.
+        edits.extend(
+            self.embedded_files_added
+                .iter()
+                .cloned()
+                .map(Edit::AddEmbeddedFile),
+        );
+        edits.extend(
+            self.custom_blocks_added
+                .iter()
+                .cloned()
+                .map(Edit::AddCustomBlock),
+        );
+        edits.extend(
+            self.custom_items_added
+                .iter()
+                .cloned()
+                .map(Edit::AddCustomItem),
+        );
+        edits.extend(
+            self.custom_skins_added
+                .iter()
+                .cloned()
+                .map(Edit::AddCustomSkin),
+        );
+
+        edits.extend(object_edits(&self.removed, &self.added, &self.modified));
+
+        edits.extend(
+            self.offzones_removed
+                .iter()
+                .copied()
+                .map(Edit::RemoveOffzone),
+        );
+        edits.extend(self.offzones_added.iter().copied().map(Edit::AddOffzone));
+
+        edits
+    }
+}
+
+/// Objects removed from, added to and modified between the given objects.
+pub(crate) fn object_diff(
+    old: &HashMap<ObjectId, ObjectDesc>,
+    new: &HashMap<ObjectId, ObjectDesc>,
+) -> (Vec<ObjectDesc>, Vec<ObjectDesc>, Vec<ObjectModification>) {
+    let mut removed = unmatched(old, new);
+    let mut added = unmatched(new, old);
+
+    // Prefer pairing objects that stayed in place, so that an object replaced in place and
+    // another one moved next to it are not confused.
+    let mut modified = pair(&mut removed, &mut added, |old, new| {
+        identity(old) == identity(new) && old.position() == new.position()
+    });
+
+    modified.extend(pair(&mut removed, &mut added, |old, new| {
+        without_position(old) == without_position(new)
+    }));
+
+    modified.extend(pair_nearest(&mut removed, &mut added));
+
+    (removed, added, modified)
+}
+
+/// Edits that remove, add and modify the given objects.
+///
+/// Modifying an object attaches its items to the new version, so items that only changed along
+/// with the object they are attached to need no edit of their own.
+pub(crate) fn object_edits(
+    removed: &[ObjectDesc],
+    added: &[ObjectDesc],
+    modified: &[ObjectModification],
+) -> Vec<Edit> {
+    let mut edits: Vec<_> = removed.iter().cloned().map(Edit::Remove).collect();
+
+    let new_ids: HashMap<ObjectId, ObjectId> = modified
+        .iter()
+        .map(|modification| (modification.old.id(), modification.new.id()))
+        .collect();
+
+    // Items are modified last, when the objects they are attached to already are.
+    let (item_modifications, object_modifications): (Vec<_>, Vec<_>) = modified
+        .iter()
+        .partition(|modification| matches!(modification.old, ObjectDesc::Item(_)));
+
+    for modification in object_modifications.into_iter().chain(item_modifications) {
+        let mut old = modification.old.clone();
+
+        if let Some(&new_id) = old.attached_to().and_then(|id| new_ids.get(&id)) {
+            old.reattach(new_id);
+        }
+
+        if old != modification.new {
+            edits.push(Edit::Modify {
+                old: Box::new(old),
+                new: Box::new(modification.new.clone()),
+            });
+        }
+    }
+
+    edits.extend(added.iter().cloned().map(Edit::Place));
+
+    edits
+}
+
+/// Custom objects of `a` that are not in `b`.
+fn difference<T: Clone>(a: &[T], b: &[T], hash: fn(&T) -> Hash) -> Vec<T> {
+    let b_hashes: HashSet<Hash> = b.iter().map(hash).collect();
+
+    a.iter()
+        .filter(|x| !b_hashes.contains(&hash(x)))
+        .cloned()
+        .collect()
+}
+
+fn objects_by_id(map_desc: &MapDesc) -> HashMap<ObjectId, ObjectDesc> {
+    MapState::from_desc(map_desc.clone())
+        .objects()
+        .map(|object| (object.id(), object.clone()))
+        .collect()
+}
+
+/// Objects of `a` that are not in `b`, in a stable order.
+fn unmatched(
+    a: &HashMap<ObjectId, ObjectDesc>,
+    b: &HashMap<ObjectId, ObjectDesc>,
+) -> Vec<ObjectDesc> {
+    let mut objects: Vec<_> = a
+        .iter()
+        .filter(|(id, _)| !b.contains_key(id))
+        .map(|(_, object)| object.clone())
+        .collect();
+
+    objects.sort_by_cached_key(|object| {
+        serialize(object).expect("object descriptions are always serializable")
+    });
+
+    objects
+}
+
+/// Take every pair of a removed and an added object that `is_same` considers the same object.
+fn pair(
+    removed: &mut Vec<ObjectDesc>,
+    added: &mut Vec<ObjectDesc>,
+    is_same: impl Fn(&ObjectDesc, &ObjectDesc) -> bool,
+) -> Vec<ObjectModification> {
+    let mut modifications = vec![];
+    let mut unpaired = vec![];
+
+    for old in mem::take(removed) {
+        match added.iter().position(|new| is_same(&old, new)) {
+            Some(index) => {
+                let new = added.remove(index);
+                let changes = changes(&old, &new);
+
+                modifications.push(ObjectModification { old, new, changes });
+            }
+            None => unpaired.push(old),
+        }
+    }
+
+    *removed = unpaired;
+
+    modifications
+}
+
+/// Take every pair of a removed and the nearest added object of the same kind and model.
+fn pair_nearest(
+    removed: &mut Vec<ObjectDesc>,
+    added: &mut Vec<ObjectDesc>,
+) -> Vec<ObjectModification> {
+    let mut modifications = vec![];
+    let mut unpaired = vec![];
+
+    for old in mem::take(removed) {
+        let nearest = added
+            .iter()
+            .enumerate()
+            .filter(|(_, new)| identity(&old) == identity(new))
+            .min_by(|(_, a), (_, b)| distance(&old, a).total_cmp(&distance(&old, b)))
+            .map(|(index, _)| index);
+
+        match nearest {
+            Some(index) => {
+                let new = added.remove(index);
+                let changes = changes(&old, &new);
+
+                modifications.push(ObjectModification { old, new, changes });
+            }
+            None => unpaired.push(old),
+        }
+    }
+
+    *removed = unpaired;
+
+    modifications
+}
+
+fn distance(a: &ObjectDesc, b: &ObjectDesc) -> f32 {
+    let (a, b) = (a.position(), b.position());
+
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+/// Kind and model of an object, which never change for the same object.
+fn identity(object: &ObjectDesc) -> (u8, &ModelId) {
+    let kind = match object {
+        ObjectDesc::Block(_) => 0,
+        ObjectDesc::GhostBlock(_) => 1,
+        ObjectDesc::FreeBlock(_) => 2,
+        ObjectDesc::Item(_) => 3,
+    };
+
+    (kind, object.model_id())
+}
+
+fn without_position(object: &ObjectDesc) -> ObjectDesc {
+    let mut object = object.clone();
+
+    match &mut object {
+        ObjectDesc::Block(block) => block.coord = Vec3 { x: 0, y: 0, z: 0 },
+        ObjectDesc::GhostBlock(ghost_block) => ghost_block.coord = Vec3 { x: 0, y: 0, z: 0 },
+        ObjectDesc::FreeBlock(free_block) => free_block.position = zero_position(),
+        ObjectDesc::Item(item) => {
+            item.position = zero_position();
+            // Attached items move with the object they are attached to, whose id changes.
+            item.attachment = None;
+        }
+    }
+
+    object
+}
+
+fn zero_position() -> Vec3<NotNan<f32>> {
+    Vec3 {
+        x: NotNan::default(),
+        y: NotNan::default(),
+        z: NotNan::default(),
+    }
+}
+
+/// Properties that differ between two versions of the same object.
+fn changes(old: &ObjectDesc, new: &ObjectDesc) -> Vec<ObjectChange> {
+    let mut changes = vec![];
+
+    if old.position() != new.position() {
+        changes.push(ObjectChange::Moved);
+    }
+
+    let (rotated, recoloured, reanimated, other) = match (old, new) {
+        (ObjectDesc::Block(old), ObjectDesc::Block(new)) => (
+            old.dir != new.dir,
+            old.elem_color != new.elem_color,
+            false,
+            (old.is_air_variant, old.variant_index, old.subvariant_index)
+                != (new.is_air_variant, new.variant_index, new.subvariant_index),
+        ),
+        (ObjectDesc::GhostBlock(old), ObjectDesc::GhostBlock(new)) => (
+            old.dir != new.dir,
+            old.elem_color != new.elem_color,
+            false,
+            (old.is_air_variant, old.variant_index, old.subvariant_index)
+                != (new.is_air_variant, new.variant_index, new.subvariant_index),
+        ),
+        (ObjectDesc::FreeBlock(old), ObjectDesc::FreeBlock(new)) => (
+            (old.yaw, old.pitch, old.roll) != (new.yaw, new.pitch, new.roll),
+            old.elem_color != new.elem_color,
+            false,
+            false,
+        ),
+        (ObjectDesc::Item(old), ObjectDesc::Item(new)) => (
+            (old.yaw, old.pitch, old.roll) != (new.yaw, new.pitch, new.roll),
+            old.elem_color != new.elem_color,
+            old.anim_offset != new.anim_offset,
+            old.pivot_position != new.pivot_position,
+        ),
+        _ => (false, false, false, true),
+    };
+
+    for (changed, change) in [
+        (rotated, ObjectChange::Rotated),
+        (recoloured, ObjectChange::Recoloured),
+        (old.waypoint() != new.waypoint(), ObjectChange::Waypoint),
+        (old.skin() != new.skin(), ObjectChange::Reskinned),
+        (reanimated, ObjectChange::Reanimated),
+        (
+            old.attached_to() != new.attached_to(),
+            ObjectChange::Reattached,
+        ),
+        (other, ObjectChange::Other),
+    ] {
+        if changed {
+            changes.push(change);
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{block, item_on, map, offzone, recoloured},
+        DependencyDesc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn identical_maps_have_no_differences() {
+        let map_desc = map([block(1, 1), item_on(&block(1, 1))]);
+
+        assert!(MapDiff::new(&map_desc, &map_desc).is_empty());
+    }
+
+    #[test]
+    fn changed_objects_are_paired_with_their_old_versions() {
+        let old = map([block(1, 1), block(5, 5), block(9, 9)]);
+        let new = map([block(2, 1), recoloured(block(5, 5)), block(12, 12)]);
+
+        let diff = MapDiff::new(&old, &new);
+
+        let mut changes: Vec<_> = diff
+            .modified
+            .iter()
+            .map(|modification| modification.changes.clone())
+            .collect();
+
+        changes.sort_by_key(|changes| format!("{changes:?}"));
+
+        assert_eq!(
+            changes,
+            [
+                vec![ObjectChange::Moved],
+                vec![ObjectChange::Moved],
+                vec![ObjectChange::Recoloured]
+            ]
+        );
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+    }
+
+    #[test]
+    fn objects_in_place_are_paired_before_moved_ones() {
+        let old = map([block(1, 1)]);
+        let new = map([block(1, 1), block(2, 1)]);
+
+        let diff = MapDiff::new(&old, &new);
+
+        assert!(diff.modified.is_empty());
+        assert!(diff.added == [block(2, 1)]);
+    }
+
+    #[test]
+    fn objects_moved_and_changed_are_paired_with_the_nearest_old_version() {
+        let old = map([block(1, 1), block(9, 9)]);
+        let new = map([recoloured(block(2, 1)), recoloured(block(9, 12))]);
+
+        let diff = MapDiff::new(&old, &new);
+
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.modified.len(), 2);
+
+        for modification in &diff.modified {
+            assert_eq!(
+                modification.changes,
+                [ObjectChange::Moved, ObjectChange::Recoloured]
+            );
+        }
+
+        assert!(diff
+            .modified
+            .iter()
+            .any(|modification| modification.old == block(1, 1)
+                && modification.new == recoloured(block(2, 1))));
+    }
+
+    #[test]
+    fn edits_turn_the_old_map_into_the_new_map() {
+        let mut old = map([block(1, 1), item_on(&block(1, 1)), block(5, 5)]);
+        let mut new = map([recoloured(block(1, 1)), block(7, 7), item_on(&block(7, 7))]);
+
+        old.offzones = vec![offzone((0, 0, 0), (3, 3, 3))];
+        new.offzones = vec![offzone((2, 2, 2), (5, 5, 5))];
+
+        let mut map = MapState::from_desc(old.clone());
+
+        for edit in MapDiff::new(&old, &new).edits() {
+            assert!(map.apply(edit), "edit applies");
+        }
+
+        assert!(map == MapState::from_desc(new));
+    }
+
+    #[test]
+    fn recoloured_blocks_keep_their_items() {
+        let old = map([block(1, 1), item_on(&block(1, 1))]);
+        let new = map([recoloured(block(1, 1)), item_on(&recoloured(block(1, 1)))]);
+
+        let edits = MapDiff::new(&old, &new).edits();
+
+        // The item moves onto the recoloured block along with it.
+        assert_eq!(edits.len(), 1);
+        assert!(matches!(
+            &edits[0],
+            Edit::Modify { old, new } if **old == block(1, 1) && **new == recoloured(block(1, 1))
+        ));
+
+        let mut map = MapState::from_desc(old);
+
+        assert!(map.apply(edits[0].clone()));
+        assert!(map == MapState::from_desc(new));
+        assert_eq!(map.attached_items(recoloured(block(1, 1)).id()).count(), 1);
+    }
+
+    #[test]
+    fn custom_objects_with_other_dependencies_are_other_objects() {
+        let texture = |bytes: &[u8]| EmbeddedFileDesc {
+            path: "Items/Texture.dds".to_owned(),
+            bytes: bytes.to_vec(),
+        };
+        let custom_item = |texture: &EmbeddedFileDesc| CustomItemDesc {
+            path: "Items/Custom.Item.Gbx".to_owned(),
+            bytes: b"item".to_vec(),
+            dependencies: vec![DependencyDesc {
+                path: texture.path.clone(),
+                hash: texture.hash(),
+            }],
+        };
+
+        let (red, blue) = (texture(b"red"), texture(b"blue"));
+
+        let old = MapDesc {
+            custom_items: vec![custom_item(&red)],
+            embedded_files: vec![red.clone()],
+            ..MapDesc::default()
+        };
+        let new = MapDesc {
+            custom_items: vec![custom_item(&red), custom_item(&blue)],
+            embedded_files: vec![red, blue.clone()],
+            ..old.clone()
+        };
+
+        let diff = MapDiff::new(&old, &new);
+
+        assert!(diff.custom_items_added == [custom_item(&blue)]);
+        assert!(diff.embedded_files_added == [blue]);
+
+        let mut map = MapState::from_desc(old);
+
+        for edit in diff.edits() {
+            assert!(map.apply(edit), "edit applies");
+        }
+
+        assert_eq!(map.to_desc().custom_items.len(), 2);
+    }
+}
@@ -0,0 +1,1754 @@
+//! Conversion of Gbx files to descriptions, and passthrough of the parts of map files that are
+//! not described.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::{Cursor, Read},
+    ops::Range,
+    path::Path,
+};
+
+use gamebox::{
+    engines::game::map::{
+        Block, BlockKind, EmbeddedObjects, FileRef, Item, Skin, WaypointSpecialProperty,
+    },
+    Vec3,
+};
+use zip::ZipArchive;
+
+use crate::{
+    hash, lzo, offzone, AttachmentDesc, BlockDesc, CustomBlockDesc, CustomItemDesc, DependencyDesc,
+    EmbeddedFileDesc, FreeBlockDesc, GbxChunkDesc, GhostBlockDesc, Hash, ItemDesc, MapDesc,
+    MapParamsDesc, MedalTimesDesc, ModelId, Mood, NotNan, ObjectDesc, OffzoneDesc, PassthroughDesc,
+    SkinDesc, SkinFileDesc, WaypointDesc,
+};
+
+/// Class id of maps.
+const MAP_CLASS_ID: u32 = 0x03043000;
+
+/// Header chunks of maps that the game derives from the rest of the map when saving it, which
+/// therefore must not be passed through.
+const DERIVED_HEADER_CHUNK_IDS: [u32; 4] = [0x03043002, 0x03043003, 0x03043004, 0x03043005];
+
+/// Body chunks of maps holding parts that the description does not model, with the names of the
+/// parts.
+const PASSTHROUGH_BODY_CHUNKS: [(u32, &str); 1] = [(0x03043044, "script metadata")];
+
+/// Body chunks of maps holding blocks, items and their properties, which the description models.
+const DESCRIBED_BODY_CHUNK_IDS: [u32; 6] = [
+    0x0304301f, 0x03043040, 0x03043054, 0x0304305f, 0x03043062, 0x03043063,
+];
+
+/// Body chunks of maps holding parts that the description does not model but that are not passed
+/// through, with the names of the parts. The game computes lightmaps from the rest of the map, and
+/// stores MediaTracker clips without their size.
+const LOST_BODY_CHUNKS: [(u32, &str); 2] = [
+    (0x0304303d, "lightmaps"),
+    (0x03043049, "MediaTracker clips"),
+];
+
+/// Marker following the id of a body chunk that is stored with its size.
+const SKIPPABLE_CHUNK_MARKER: &[u8; 4] = b"PIKS";
+
+/// Marker at the end of the body of a Gbx file.
+const END_MARKER: u32 = 0xfacade01;
+
+/// Header chunk of maps containing the medal times, among other parameters.
+const PARAMS_CHUNK_ID: u32 = 0x03043002;
+
+/// Offset of the medal times in the parameters header chunk, which are preceded by the version
+/// and a flag.
+const MEDAL_TIMES_OFFSET: usize = 5;
+
+/// Header chunk of maps containing a summary of the map as XML.
+const XML_CHUNK_ID: u32 = 0x03043005;
+
+/// First body chunk of maps, containing the vehicle.
+const VEHICLE_CHUNK_ID: u32 = 0x0304300d;
+
+/// Body chunk of maps containing the node listing the blocks the map can use and the parameters
+/// node.
+const PARAMS_NODE_CHUNK_ID: u32 = 0x03043011;
+
+/// Class id of the node listing the blocks a map can use, which is also the id of its only chunk.
+const COLLECTOR_LIST_CLASS_ID: u32 = 0x0301b000;
+
+/// Class id of the parameters node of maps, which contains the medal times.
+const CHALLENGE_PARAMS_CLASS_ID: u32 = 0x0305b000;
+
+/// Medal time stored for medals that are not set.
+const NO_TIME: u32 = u32::MAX;
+
+/// Kind of custom object stored in an embedded file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CustomObjectKind {
+    Block,
+    Item,
+}
+
+impl CustomObjectKind {
+    fn from_path(path: &str) -> Option<Self> {
+        let path = path.to_lowercase();
+
+        if path.ends_with("block.gbx") {
+            Some(Self::Block)
+        } else if path.ends_with("item.gbx") {
+            Some(Self::Item)
+        } else {
+            None
+        }
+    }
+}
+
+/// Read a map file and convert it to a description.
+pub fn read_map(path: impl AsRef<Path>) -> Result<MapDesc, Box<dyn Error>> {
+    let map: gamebox::Map = gamebox::read_file(path)?;
+
+    map_to_desc(&map)
+}
+
+/// Convert a map to a description.
+pub fn map_to_desc(map: &gamebox::Map) -> Result<MapDesc, Box<dyn Error>> {
+    let mut map_desc = objects_to_desc(map.embedded_objects(), map.blocks(), map.items())?;
+
+    let mut offzones = vec![];
+
+    for offzone in map.offzones() {
+        let coord = |coord: Vec3<u32>| -> Result<Vec3<u8>, Box<dyn Error>> {
+            Ok(Vec3 {
+                x: u8::try_from(coord.x)?,
+                y: u8::try_from(coord.y)?,
+                z: u8::try_from(coord.z)?,
+            })
+        };
+
+        offzones.push(OffzoneDesc {
+            min: coord(offzone.start())?,
+            max: coord(offzone.end())?,
+        });
+    }
+
+    map_desc.offzones = offzone::normalize(&offzones);
+
+    Ok(map_desc)
+}
+
+/// Read a macroblock file and convert it to a description, see [`crate::macroblock`].
+pub fn read_macroblock(path: impl AsRef<Path>) -> Result<MapDesc, Box<dyn Error>> {
+    let macroblock: gamebox::Macroblock = gamebox::read_file(path)?;
+
+    objects_to_desc(
+        macroblock.embedded_objects(),
+        macroblock.blocks(),
+        macroblock.items(),
+    )
+}
+
+/// Convert the given objects and the custom objects they use to a description without offzones.
+fn objects_to_desc(
+    embedded_objects: Option<&EmbeddedObjects>,
+    gbx_blocks: &[Block],
+    gbx_items: &[Item],
+) -> Result<MapDesc, Box<dyn Error>> {
+    let mut custom_block_hashes = HashMap::new();
+    let mut custom_item_hashes = HashMap::new();
+    let mut custom_blocks = vec![];
+    let mut custom_items = vec![];
+    let mut custom_skin_hashes = HashMap::new();
+    let mut custom_skins = vec![];
+    let mut embedded_files = vec![];
+
+    if let Some(embedded_objects) = embedded_objects {
+        let embedded_object_id = |file_index: usize| {
+            embedded_objects
+                .ids()
+                .get(file_index)
+                .ok_or("missing id of embedded object")
+        };
+
+        let mut zip_archive = ZipArchive::new(Cursor::new(embedded_objects.data()))?;
+
+        let mut files = vec![];
+
+        for file_index in 0..zip_archive.len() {
+            let mut file = zip_archive.by_index(file_index)?;
+
+            if file.is_dir() {
+                continue;
+            }
+
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes)?;
+
+            files.push((file_index, normalize_path(file.name()), bytes));
+        }
+
+        // Skin files can be shared by any objects, so they do not belong to a custom object.
+        let skin_paths: HashSet<String> = gbx_blocks
+            .iter()
+            .filter_map(|block| block.skin())
+            .chain(gbx_items.iter().filter_map(|item| item.skin()))
+            .flat_map(|skin| [skin.background(), skin.foreground()])
+            .flatten()
+            .map(|file_ref| normalize_path(file_ref.path()).to_lowercase())
+            .collect();
+
+        let (skin_files, files): (Vec<_>, Vec<_>) = files
+            .into_iter()
+            .partition(|(_, path, _)| skin_paths.contains(&path.to_lowercase()));
+
+        for (_, path, bytes) in skin_files {
+            custom_skin_hashes.insert(path.to_lowercase(), hash(&bytes));
+            custom_skins.push(EmbeddedFileDesc { path, bytes });
+        }
+
+        let mut dependencies = group_dependencies(&files);
+        // Files shared by several objects are stored once.
+        let mut embedded_file_indices = BTreeSet::new();
+
+        for (file_index, path, bytes) in &files {
+            let Some(kind) = CustomObjectKind::from_path(path) else {
+                continue;
+            };
+
+            let dependency_indices = dependencies.remove(path).unwrap_or_default();
+
+            let dependencies = dependency_indices
+                .iter()
+                .map(|&dependency_index| {
+                    let (_, path, bytes) = &files[dependency_index];
+
+                    DependencyDesc {
+                        path: path.clone(),
+                        hash: hash(bytes),
+                    }
+                })
+                .collect();
+
+            embedded_file_indices.extend(dependency_indices);
+
+            match kind {
+                CustomObjectKind::Block => {
+                    let id = format!("{}_CustomBlock", embedded_object_id(*file_index)?);
+
+                    let custom_block = CustomBlockDesc {
+                        path: path.clone(),
+                        bytes: bytes.clone(),
+                        dependencies,
+                    };
+
+                    custom_block_hashes.insert(id, custom_block.hash());
+                    custom_blocks.push(custom_block);
+                }
+                CustomObjectKind::Item => {
+                    let id = embedded_object_id(*file_index)?.to_owned();
+
+                    let custom_item = CustomItemDesc {
+                        path: path.clone(),
+                        bytes: bytes.clone(),
+                        dependencies,
+                    };
+
+                    custom_item_hashes.insert(id, custom_item.hash());
+                    custom_items.push(custom_item);
+                }
+            }
+        }
+
+        for index in embedded_file_indices {
+            let (_, path, bytes) = &files[index];
+
+            embedded_files.push(EmbeddedFileDesc {
+                path: path.clone(),
+                bytes: bytes.clone(),
+            });
+        }
+    }
+
+    let mut blocks = vec![];
+    let mut ghost_blocks = vec![];
+    let mut free_blocks = vec![];
+    // Items refer to the blocks they are attached to by their index.
+    let mut block_ids = vec![];
+
+    for block in gbx_blocks {
+        let model_id = if let Some(&hash) = custom_block_hashes.get(block.info_id()) {
+            ModelId::Custom { hash }
+        } else {
+            ModelId::Game {
+                id: block.info_id().to_owned(),
+            }
+        };
+
+        match block.kind() {
+            BlockKind::Normal(block_kind) => {
+                if block_kind.is_ghost() {
+                    let ghost_block = GhostBlockDesc {
+                        block_info_id: model_id,
+                        coord: block_kind.coord(),
+                        dir: block_kind.direction(),
+                        is_air_variant: block_kind.is_air_variant(),
+                        variant_index: block_kind.variant_index(),
+                        subvariant_index: block_kind.subvariant_index(),
+                        elem_color: block.elem_color(),
+                        waypoint: waypoint_desc(block.waypoint_property()),
+                        skin: skin_desc(block.skin(), &custom_skin_hashes),
+                    };
+
+                    block_ids.push(ObjectDesc::GhostBlock(ghost_block.clone()).id());
+                    ghost_blocks.push(ghost_block);
+                } else {
+                    let block_desc = BlockDesc {
+                        block_info_id: model_id,
+                        coord: block_kind.coord(),
+                        dir: block_kind.direction(),
+                        is_air_variant: block_kind.is_air_variant(),
+                        variant_index: block_kind.variant_index(),
+                        subvariant_index: block_kind.subvariant_index(),
+                        elem_color: block.elem_color(),
+                        waypoint: waypoint_desc(block.waypoint_property()),
+                        skin: skin_desc(block.skin(), &custom_skin_hashes),
+                    };
+
+                    block_ids.push(ObjectDesc::Block(block_desc.clone()).id());
+                    blocks.push(block_desc);
+                }
+            }
+            BlockKind::Free(block_kind) => {
+                let position = block_kind.position();
+                let rotation = block_kind.rotation();
+
+                let free_block = FreeBlockDesc {
+                    block_info_id: model_id,
+                    position: Vec3 {
+                        x: NotNan::new(position.x)?,
+                        y: NotNan::new(position.y)?,
+                        z: NotNan::new(position.z)?,
+                    },
+                    yaw: NotNan::new(rotation.yaw)?,
+                    pitch: NotNan::new(rotation.pitch)?,
+                    roll: NotNan::new(rotation.roll)?,
+                    elem_color: block.elem_color(),
+                    waypoint: waypoint_desc(block.waypoint_property()),
+                    skin: skin_desc(block.skin(), &custom_skin_hashes),
+                };
+
+                block_ids.push(ObjectDesc::FreeBlock(free_block.clone()).id());
+                free_blocks.push(free_block);
+            }
+        }
+    }
+
+    let mut items = vec![];
+
+    for item in gbx_items {
+        let model_id = if let Some(&hash) = custom_item_hashes.get(item.model_id()) {
+            ModelId::Custom { hash }
+        } else {
+            ModelId::Game {
+                id: item.model_id().to_owned(),
+            }
+        };
+
+        let position = item.position();
+        let rotation = item.rotation();
+        let pivot_position = item.pivot_position();
+
+        items.push(ItemDesc {
+            item_model_id: model_id,
+            position: Vec3 {
+                x: NotNan::new(position.x)?,
+                y: NotNan::new(position.y)?,
+                z: NotNan::new(position.z)?,
+            },
+            yaw: NotNan::new(rotation.yaw)?,
+            pitch: NotNan::new(rotation.pitch)?,
+            roll: NotNan::new(rotation.roll)?,
+            pivot_position: Vec3 {
+                x: NotNan::new(pivot_position.x)?,
+                y: NotNan::new(pivot_position.y)?,
+                z: NotNan::new(pivot_position.z)?,
+            },
+            elem_color: item.elem_color(),
+            anim_offset: item.animation_offset(),
+            waypoint: waypoint_desc(item.waypoint_property()),
+            skin: skin_desc(item.skin(), &custom_skin_hashes),
+            attachment: item
+                .parent_block_index()
+                .and_then(|block_index| block_ids.get(block_index))
+                .map(|&object_id| AttachmentDesc { object_id }),
+        })
+    }
+
+    Ok(MapDesc {
+        custom_blocks,
+        custom_items,
+        custom_skins,
+        embedded_files,
+        blocks,
+        ghost_blocks,
+        free_blocks,
+        items,
+        offzones: vec![],
+    })
+}
+
+/// What is kept of the parts of a map that its description does not model.
+pub struct PassthroughReport {
+    /// Names of the kept parts.
+    pub kept: Vec<String>,
+    /// Names of the parts that the map has but that are not kept.
+    pub lost: Vec<String>,
+}
+
+impl Display for PassthroughReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.kept.is_empty() {
+            write!(f, "kept nothing")?;
+        } else {
+            write!(f, "kept {}", self.kept.join(", "))?;
+        }
+
+        if self.lost.is_empty() {
+            write!(f, "; lost nothing")
+        } else {
+            write!(f, "; lost {}", self.lost.join(", "))
+        }
+    }
+}
+
+/// Read the parts of a map file that [`read_map`] does not convert.
+///
+/// Only body chunks stored with their size can be passed through. Every other body chunk that is
+/// found and not modelled by the description is reported as lost, see [`find_body_chunks`].
+pub fn read_passthrough(
+    path: impl AsRef<Path>,
+) -> Result<(PassthroughDesc, PassthroughReport), Box<dyn Error>> {
+    passthrough_of(&fs::read(path)?)
+}
+
+fn passthrough_of(bytes: &[u8]) -> Result<(PassthroughDesc, PassthroughReport), Box<dyn Error>> {
+    let header = read_header(bytes).ok_or("unsupported Gbx header")?;
+
+    if header.class_id != MAP_CLASS_ID {
+        return Err("not a map file".into());
+    }
+
+    let header_chunks: Vec<GbxChunkDesc> = header
+        .chunks
+        .into_iter()
+        .filter(|chunk| !DERIVED_HEADER_CHUNK_IDS.contains(&chunk.id))
+        .collect();
+
+    let mut body_chunks = vec![];
+    let mut lost = vec![];
+
+    match read_body(bytes) {
+        Some(body) => {
+            for chunk in find_body_chunks(&body.bytes) {
+                match chunk.data {
+                    Some(data) if part_name(&PASSTHROUGH_BODY_CHUNKS, chunk.id).is_some() => {
+                        body_chunks.push(GbxChunkDesc {
+                            id: chunk.id,
+                            heavy: false,
+                            bytes: body.bytes[data].to_vec(),
+                        })
+                    }
+                    _ if DESCRIBED_BODY_CHUNK_IDS.contains(&chunk.id) => {}
+                    _ => lost.push(match part_name(&LOST_BODY_CHUNKS, chunk.id) {
+                        Some(name) => name.to_owned(),
+                        None => format!("body chunk {:08x}", chunk.id),
+                    }),
+                }
+            }
+        }
+        None => lost.push("the body, which could not be read".to_owned()),
+    }
+
+    let passthrough = PassthroughDesc {
+        header_chunks,
+        body_chunks,
+        lost,
+    };
+    let report = passthrough_report(&passthrough);
+
+    Ok((passthrough, report))
+}
+
+/// Put the given passthrough back into a map file saved from the game, returning the resulting
+/// file.
+///
+/// Header and body chunks of the saved file are replaced by the passed through ones with the same
+/// id. Passed through body chunks the saved file lacks are added at the end of its body.
+pub fn restore_passthrough(
+    saved: &[u8],
+    passthrough: &PassthroughDesc,
+) -> Result<(Vec<u8>, PassthroughReport), Box<dyn Error>> {
+    let header = read_header(saved).ok_or("unsupported Gbx header")?;
+
+    if header.class_id != MAP_CLASS_ID {
+        return Err("not a map file".into());
+    }
+
+    let mut chunks: Vec<&GbxChunkDesc> = header
+        .chunks
+        .iter()
+        .filter(|chunk| {
+            !passthrough
+                .header_chunks
+                .iter()
+                .any(|passed| passed.id == chunk.id)
+        })
+        .chain(&passthrough.header_chunks)
+        .collect();
+
+    chunks.sort_by_key(|chunk| chunk.id);
+
+    let mut bytes = replace_header_chunks(saved, &header, &chunks);
+
+    if !passthrough.body_chunks.is_empty() {
+        let body = read_body(&bytes).ok_or("unsupported Gbx body")?;
+        let contents = put_body_chunks(&body.bytes, &passthrough.body_chunks)
+            .ok_or("the body of the saved map has no end marker")?;
+
+        bytes = replace_body(&bytes, &body, &contents);
+    }
+
+    Ok((bytes, passthrough_report(passthrough)))
+}
+
+/// Read the parameters of a map file.
+///
+/// The mood and whether the map is validated are read from the XML summary in the header, the
+/// medal times from the parameters in the header. Missing parts are left at their defaults.
+pub fn read_map_params(path: impl AsRef<Path>) -> Result<MapParamsDesc, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    let header = read_header(&bytes).ok_or("unsupported Gbx header")?;
+
+    if header.class_id != MAP_CLASS_ID {
+        return Err("not a map file".into());
+    }
+
+    let mut map_params_desc = MapParamsDesc {
+        mood: Mood::Day,
+        medal_times: MedalTimesDesc::default(),
+        validated: false,
+    };
+
+    for chunk in &header.chunks {
+        match chunk.id {
+            PARAMS_CHUNK_ID => {
+                if let Some(medal_times) = read_medal_times(&chunk.bytes) {
+                    map_params_desc.medal_times = medal_times;
+                }
+            }
+            XML_CHUNK_ID => {
+                let Some(xml) = GbxReader {
+                    bytes: &chunk.bytes,
+                }
+                .string() else {
+                    continue;
+                };
+
+                map_params_desc.validated = xml_attribute(&xml, "desc", "validated") == Some("1");
+
+                map_params_desc.mood = match xml_attribute(&xml, "desc", "mood") {
+                    Some("Sunset") => Mood::Sunset,
+                    Some("Night") => Mood::Night,
+                    Some("Sunrise") => Mood::Sunrise,
+                    _ => Mood::Day,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    Ok(map_params_desc)
+}
+
+/// Write the medal times and validation state of the given parameters into a map file saved from
+/// the game, returning the resulting file.
+///
+/// The game reads them from the parameters node at the start of the body, see
+/// [`medal_time_positions`]. A map that is not validated has no author time there. The parameters
+/// and the summary in the header, which map lists show, are changed to match.
+pub fn write_map_params(
+    saved: &[u8],
+    map_params_desc: &MapParamsDesc,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let header = read_header(saved).ok_or("unsupported Gbx header")?;
+
+    if header.class_id != MAP_CLASS_ID {
+        return Err("not a map file".into());
+    }
+
+    let medal_times = &map_params_desc.medal_times;
+
+    let times = [
+        medal_times.bronze,
+        medal_times.silver,
+        medal_times.gold,
+        medal_times.author.filter(|_| map_params_desc.validated),
+    ]
+    .map(|time| time.unwrap_or(NO_TIME));
+
+    let write_times = |bytes: &mut [u8]| {
+        for (bytes, time) in bytes.chunks_exact_mut(4).zip(times) {
+            bytes.copy_from_slice(&time.to_le_bytes());
+        }
+    };
+
+    let mut chunks = header.chunks.clone();
+
+    for chunk in &mut chunks {
+        match chunk.id {
+            PARAMS_CHUNK_ID => {
+                if !matches!(chunk.bytes.first(), Some(&version) if version >= 3) {
+                    return Err("unsupported map parameters".into());
+                }
+
+                write_times(
+                    chunk
+                        .bytes
+                        .get_mut(MEDAL_TIMES_OFFSET..MEDAL_TIMES_OFFSET + 16)
+                        .ok_or("unsupported map parameters")?,
+                );
+            }
+            XML_CHUNK_ID => {
+                let mut xml = GbxReader {
+                    bytes: &chunk.bytes,
+                }
+                .string()
+                .ok_or("unsupported map summary")?;
+
+                let validated = if map_params_desc.validated { "1" } else { "0" };
+                set_xml_attribute(&mut xml, "desc", "validated", validated);
+
+                for (name, time) in ["bronze", "silver", "gold", "authortime"].iter().zip(times) {
+                    let time = if time == NO_TIME {
+                        "-1".to_owned()
+                    } else {
+                        time.to_string()
+                    };
+
+                    set_xml_attribute(&mut xml, "times", name, &time);
+                }
+
+                chunk.bytes = (xml.len() as u32).to_le_bytes().to_vec();
+                chunk.bytes.extend(xml.as_bytes());
+            }
+            _ => {}
+        }
+    }
+
+    let chunks: Vec<&GbxChunkDesc> = chunks.iter().collect();
+    let bytes = replace_header_chunks(saved, &header, &chunks);
+
+    let mut body = read_body(&bytes).ok_or("unsupported Gbx body")?;
+    let positions = medal_time_positions(&body.bytes).ok_or("unsupported map parameters")?;
+
+    for position in positions {
+        write_times(&mut body.bytes[position..position + 16]);
+    }
+
+    Ok(replace_body(&bytes, &body, &body.bytes))
+}
+
+/// Positions of the medal times in the body of a map, which are in two chunks of the parameters
+/// node.
+///
+/// The body starts with the vehicle of the map, followed by the chunk holding the list of blocks
+/// the map can use and the parameters node. Chunks of the parameters node that are stored without
+/// their size and hold anything but tips, times and no validation ghost are not supported.
+fn medal_time_positions(body: &[u8]) -> Option<Vec<usize>> {
+    let mut reader = GbxReader { bytes: body };
+    let mut ids = BodyIds::default();
+
+    if reader.u32()? != VEHICLE_CHUNK_ID {
+        return None;
+    }
+
+    ids.skip_ident(&mut reader)?;
+
+    if reader.u32()? != PARAMS_NODE_CHUNK_ID {
+        return None;
+    }
+
+    // The node listing the blocks the map can use.
+    let _node_index = reader.u32()?;
+
+    if reader.u32()? != COLLECTOR_LIST_CLASS_ID || reader.u32()? != COLLECTOR_LIST_CLASS_ID {
+        return None;
+    }
+
+    for _ in 0..reader.u32()? {
+        ids.skip_ident(&mut reader)?;
+        let _count = reader.u32()?;
+    }
+
+    if reader.u32()? != END_MARKER {
+        return None;
+    }
+
+    let _node_index = reader.u32()?;
+
+    if reader.u32()? != CHALLENGE_PARAMS_CLASS_ID {
+        return None;
+    }
+
+    let mut positions = vec![];
+
+    loop {
+        let id = reader.u32()?;
+
+        if id == END_MARKER {
+            break;
+        }
+
+        if reader.bytes.starts_with(SKIPPABLE_CHUNK_MARKER) {
+            reader.take(4)?;
+            let len = reader.u32()? as usize;
+
+            // A flag, the medal times, the time limit and the author score.
+            if id == 0x0305b00a {
+                if len != 28 {
+                    return None;
+                }
+
+                positions.push(body.len() - reader.bytes.len() + 4);
+            }
+
+            reader.take(len)?;
+
+            continue;
+        }
+
+        match id {
+            // Tips.
+            0x0305b001 => {
+                for _ in 0..4 {
+                    reader.string()?;
+                }
+            }
+            // The medal times and a flag.
+            0x0305b004 => {
+                positions.push(body.len() - reader.bytes.len());
+                reader.take(20)?;
+            }
+            // The time limit and the author score.
+            0x0305b008 => {
+                reader.take(8)?;
+            }
+            // The validation ghost, which maps saved by the game do not have.
+            0x0305b00d => {
+                if reader.u32()? != u32::MAX {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(positions)
+}
+
+/// Ids in the body of a Gbx file, which are strings stored once and referred to by index after.
+#[derive(Default)]
+struct BodyIds {
+    /// Whether the version of the ids, which precedes the first one, was read.
+    started: bool,
+}
+
+impl BodyIds {
+    fn skip(&mut self, reader: &mut GbxReader) -> Option<()> {
+        if !self.started {
+            if reader.u32()? != 3 {
+                return None;
+            }
+
+            self.started = true;
+        }
+
+        let index = reader.u32()?;
+
+        // Either no id, a number, a reference to an earlier string or a new string.
+        if index != u32::MAX && index & 0xc0000000 != 0 && index & 0x3fffffff == 0 {
+            reader.string()?;
+        }
+
+        Some(())
+    }
+
+    /// Skip the name, collection and author of an object.
+    fn skip_ident(&mut self, reader: &mut GbxReader) -> Option<()> {
+        for _ in 0..3 {
+            self.skip(reader)?;
+        }
+
+        Some(())
+    }
+}
+
+/// Read the medal times from the parameters header chunk of a map.
+///
+/// Versions before 3 store more before the medal times, and are not supported.
+fn read_medal_times(bytes: &[u8]) -> Option<MedalTimesDesc> {
+    let mut reader = GbxReader { bytes };
+
+    if reader.take(1)?[0] < 3 {
+        return None;
+    }
+
+    let _flag = reader.u32()?;
+
+    let mut time = || reader.u32().map(|time| (time != NO_TIME).then_some(time));
+
+    let bronze = time()?;
+    let silver = time()?;
+    let gold = time()?;
+    let author = time()?;
+
+    Some(MedalTimesDesc {
+        author,
+        gold,
+        silver,
+        bronze,
+    })
+}
+
+/// Value of the attribute with the given name of the first element with the given name.
+fn xml_attribute<'a>(xml: &'a str, element: &str, name: &str) -> Option<&'a str> {
+    let range = xml_attribute_range(xml, element, name)?;
+
+    Some(&xml[range])
+}
+
+/// Set the value of the attribute with the given name of the first element with the given name,
+/// if the element has that attribute.
+fn set_xml_attribute(xml: &mut String, element: &str, name: &str, value: &str) {
+    if let Some(range) = xml_attribute_range(xml, element, name) {
+        xml.replace_range(range, value);
+    }
+}
+
+/// Position of the value of an attribute, which must not contain escaped quotes.
+fn xml_attribute_range(xml: &str, element: &str, name: &str) -> Option<Range<usize>> {
+    let start = xml.find(&format!("<{element} "))?;
+    let end = start + xml[start..].find('>')?;
+
+    let tag = &xml[start..end];
+    let value_start = start + tag.find(&format!(" {name}=\""))? + name.len() + 3;
+    let value_end = value_start + xml[value_start..end].find('"')?;
+
+    Some(value_start..value_end)
+}
+
+/// Replace the header chunks of a map file with the given ones, returning the resulting file.
+fn replace_header_chunks(bytes: &[u8], header: &GbxHeader, chunks: &[&GbxChunkDesc]) -> Vec<u8> {
+    let mut user_data = vec![];
+    user_data.extend((chunks.len() as u32).to_le_bytes());
+
+    for chunk in chunks {
+        let size = chunk.bytes.len() as u32 | if chunk.heavy { 0x80000000 } else { 0 };
+
+        user_data.extend(chunk.id.to_le_bytes());
+        user_data.extend(size.to_le_bytes());
+    }
+
+    for chunk in chunks {
+        user_data.extend(&chunk.bytes);
+    }
+
+    // The length of the user data directly precedes it.
+    let mut result = bytes[..header.user_data.start - 4].to_vec();
+    result.extend((user_data.len() as u32).to_le_bytes());
+    result.extend(user_data);
+    result.extend(&bytes[header.user_data.end..]);
+
+    result
+}
+
+fn passthrough_report(passthrough: &PassthroughDesc) -> PassthroughReport {
+    let header_parts = passthrough
+        .header_chunks
+        .iter()
+        .map(|chunk| match chunk.id {
+            0x03043007 => "thumbnail and comments".to_owned(),
+            0x03043008 => "author".to_owned(),
+            id => format!("header chunk {id:08x}"),
+        });
+
+    let body_parts = passthrough.body_chunks.iter().map(|chunk| {
+        part_name(&PASSTHROUGH_BODY_CHUNKS, chunk.id)
+            .map_or_else(|| format!("body chunk {:08x}", chunk.id), str::to_owned)
+    });
+
+    PassthroughReport {
+        kept: header_parts.chain(body_parts).collect(),
+        lost: passthrough.lost.clone(),
+    }
+}
+
+/// Name of the part held by the chunk with the given id among the given chunks.
+fn part_name(chunks: &[(u32, &'static str)], id: u32) -> Option<&'static str> {
+    chunks
+        .iter()
+        .find(|(chunk_id, _)| *chunk_id == id)
+        .map(|(_, name)| *name)
+}
+
+fn waypoint_desc(waypoint: Option<&WaypointSpecialProperty>) -> Option<WaypointDesc> {
+    waypoint.map(|waypoint| WaypointDesc {
+        tag: waypoint.tag().to_owned(),
+        order: waypoint.order(),
+    })
+}
+
+/// Convert a skin, referring to the skin files embedded in the map through the given hashes of
+/// their bytes, which are keyed by their lowercase paths.
+fn skin_desc(
+    skin: Option<&Skin>,
+    custom_skin_hashes: &HashMap<String, Hash>,
+) -> Option<Box<SkinDesc>> {
+    let skin_file_desc = |file_ref: &FileRef| match custom_skin_hashes
+        .get(&normalize_path(file_ref.path()).to_lowercase())
+    {
+        Some(&hash) => SkinFileDesc::Custom { hash },
+        None => SkinFileDesc::External {
+            path: file_ref.path().to_owned(),
+            url: file_ref.locator_url().map(str::to_owned),
+        },
+    };
+
+    skin.map(|skin| {
+        Box::new(SkinDesc {
+            background: skin.background().map(skin_file_desc),
+            foreground: skin.foreground().map(skin_file_desc),
+        })
+    })
+}
+
+/// Assign every embedded file that is not a custom object to the custom objects that need it,
+/// returning the indices of the dependencies of every object by its path.
+///
+/// An object needs the files it references, directly or through other files. Files that are not
+/// referenced by any object, for example because their references could not be read, are assigned
+/// to the objects in the closest folder containing them, or to all objects if there are none, so
+/// that no file is lost.
+fn group_dependencies(files: &[(usize, String, Vec<u8>)]) -> HashMap<String, Vec<usize>> {
+    let file_indices: HashMap<String, usize> = files
+        .iter()
+        .enumerate()
+        .map(|(index, (_, path, _))| (path.to_lowercase(), index))
+        .collect();
+
+    let objects: Vec<usize> = (0..files.len())
+        .filter(|&index| CustomObjectKind::from_path(&files[index].1).is_some())
+        .collect();
+
+    let mut dependencies: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut claimed = HashSet::new();
+
+    for &object in &objects {
+        let mut visited = HashSet::from([object]);
+        let mut stack = vec![object];
+
+        while let Some(index) = stack.pop() {
+            let (_, path, bytes) = &files[index];
+
+            for reference in external_references(path, bytes) {
+                let Some(&dependency) = file_indices.get(&reference.to_lowercase()) else {
+                    continue;
+                };
+
+                if CustomObjectKind::from_path(&files[dependency].1).is_none()
+                    && visited.insert(dependency)
+                {
+                    stack.push(dependency);
+                }
+            }
+        }
+
+        visited.remove(&object);
+
+        let mut object_dependencies: Vec<usize> = visited.into_iter().collect();
+        object_dependencies.sort();
+
+        claimed.extend(object_dependencies.iter().copied());
+
+        dependencies.insert(files[object].1.clone(), object_dependencies);
+    }
+
+    for index in 0..files.len() {
+        if claimed.contains(&index) || objects.contains(&index) {
+            continue;
+        }
+
+        let folder = parent_folder(&files[index].1);
+
+        let closest_folder = objects
+            .iter()
+            .map(|&object| parent_folder(&files[object].1))
+            .filter(|object_folder| is_in_folder(folder, object_folder))
+            .max_by_key(|object_folder| object_folder.len());
+
+        for &object in &objects {
+            let is_owner = match closest_folder {
+                Some(closest_folder) => parent_folder(&files[object].1) == closest_folder,
+                None => true,
+            };
+
+            if is_owner {
+                dependencies
+                    .entry(files[object].1.clone())
+                    .or_default()
+                    .push(index);
+            }
+        }
+    }
+
+    dependencies
+}
+
+/// Paths of the files referenced by the Gbx file with the given path and contents, or nothing if
+/// its reference table cannot be read.
+fn external_references(path: &str, bytes: &[u8]) -> Vec<String> {
+    let Some(references) = read_reference_table(bytes) else {
+        return vec![];
+    };
+
+    let mut base_folder: Vec<&str> = parent_folder(path)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    for _ in 0..references.ancestor_level {
+        base_folder.pop();
+    }
+
+    references
+        .files
+        .into_iter()
+        .map(|(folder, file_name)| {
+            let mut segments = base_folder.clone();
+
+            if let Some(folder) = &folder {
+                segments.extend(folder.split('/'));
+            }
+
+            segments.push(&file_name);
+
+            segments.join("/")
+        })
+        .collect()
+}
+
+/// Header of a binary Gbx file.
+struct GbxHeader {
+    class_id: u32,
+    /// Position of the user data, which contains the header chunks, in the file.
+    user_data: Range<usize>,
+    chunks: Vec<GbxChunkDesc>,
+}
+
+/// Read the header of a binary Gbx file.
+///
+/// Only the latest version of the format, which is the only one with header chunks in the user
+/// data, is supported.
+fn read_header(bytes: &[u8]) -> Option<GbxHeader> {
+    let mut reader = GbxReader { bytes };
+
+    if reader.take(3)? != b"GBX" || reader.u16()? != 6 {
+        return None;
+    }
+
+    if reader.take(4)?[0] != b'B' {
+        return None;
+    }
+
+    let class_id = reader.u32()?;
+    let user_data_len = reader.u32()? as usize;
+
+    let start = bytes.len() - reader.bytes.len();
+    let user_data = start..start + user_data_len;
+
+    let mut reader = GbxReader {
+        bytes: bytes.get(user_data.clone())?,
+    };
+
+    let num_chunks = reader.u32()?;
+
+    let mut entries = vec![];
+
+    for _ in 0..num_chunks {
+        let id = reader.u32()?;
+        let size = reader.u32()?;
+
+        entries.push((id, size & 0x7fffffff, size & 0x80000000 != 0));
+    }
+
+    let mut chunks = vec![];
+
+    for (id, size, heavy) in entries {
+        chunks.push(GbxChunkDesc {
+            id,
+            heavy,
+            bytes: reader.take(size as usize)?.to_vec(),
+        });
+    }
+
+    Some(GbxHeader {
+        class_id,
+        user_data,
+        chunks,
+    })
+}
+
+/// External files referenced by a Gbx file.
+struct ReferenceTable {
+    /// Number of folders to go up from the folder of the file before resolving references.
+    ancestor_level: u32,
+    /// Folder and name of every referenced file.
+    files: Vec<(Option<String>, String)>,
+    /// Position of the end of the table in the file, where the body starts.
+    end: usize,
+    /// Whether the body is compressed.
+    compressed_body: bool,
+}
+
+/// Read the reference table in the header of a binary Gbx file.
+fn read_reference_table(bytes: &[u8]) -> Option<ReferenceTable> {
+    let mut reader = GbxReader { bytes };
+
+    if reader.take(3)? != b"GBX" {
+        return None;
+    }
+
+    let version = reader.u16()?;
+
+    if !(3..=6).contains(&version) {
+        return None;
+    }
+
+    let format = reader.take(if version >= 4 { 4 } else { 3 })?;
+
+    // Text files and compressed reference tables are not supported.
+    if format[0] != b'B' || format[1] != b'U' {
+        return None;
+    }
+
+    let _class_id = reader.u32()?;
+
+    if version >= 6 {
+        let user_data_len = reader.u32()?;
+        reader.take(user_data_len as usize)?;
+    }
+
+    let compressed_body = format.get(2) == Some(&b'C');
+
+    let _num_nodes = reader.u32()?;
+    let num_external_nodes = reader.u32()?;
+
+    if num_external_nodes == 0 {
+        return Some(ReferenceTable {
+            ancestor_level: 0,
+            files: vec![],
+            end: bytes.len() - reader.bytes.len(),
+            compressed_body,
+        });
+    }
+
+    let ancestor_level = reader.u32()?;
+
+    let num_root_folders = reader.u32()?;
+    let folders = read_reference_folders(&mut reader, num_root_folders)?;
+
+    let mut files = vec![];
+
+    for _ in 0..num_external_nodes {
+        let flags = reader.u32()?;
+
+        // Other flags refer to resources of the game rather than files.
+        if flags & 4 != 0 {
+            let _resource_index = reader.u32()?;
+            let _node_index = reader.u32()?;
+
+            if version >= 5 {
+                let _use_file = reader.u32()?;
+            }
+
+            continue;
+        }
+
+        let file_name = reader.string()?;
+        let _node_index = reader.u32()?;
+
+        if version >= 5 {
+            let _use_file = reader.u32()?;
+        }
+
+        let folder = match reader.u32()? {
+            0 => None,
+            folder_index => Some(folders.get(folder_index as usize - 1)?.clone()),
+        };
+
+        files.push((folder, file_name.replace('\\', "/")));
+    }
+
+    Some(ReferenceTable {
+        ancestor_level,
+        files,
+        end: bytes.len() - reader.bytes.len(),
+        compressed_body,
+    })
+}
+
+/// Read the given number of folders with their subfolders, returning their paths depth first.
+///
+/// The folders are nested as deep as the file says, so they are read without recursing.
+fn read_reference_folders(reader: &mut GbxReader, num_folders: u32) -> Option<Vec<String>> {
+    let mut folders = vec![];
+    // Path of every folder whose subfolders are being read, with the number of them left to read.
+    let mut levels = vec![(String::new(), num_folders)];
+
+    while let Some((parent, num_left)) = levels.last_mut() {
+        if *num_left == 0 {
+            levels.pop();
+            continue;
+        }
+
+        *num_left -= 1;
+
+        let name = reader.string()?;
+
+        let path = if parent.is_empty() {
+            name
+        } else {
+            format!("{parent}/{name}")
+        };
+
+        let num_subfolders = reader.u32()?;
+
+        folders.push(path.clone());
+        levels.push((path, num_subfolders));
+    }
+
+    Some(folders)
+}
+
+/// Body of a binary Gbx file.
+struct GbxBody {
+    /// Position of the body in the file, including the sizes in front of compressed data.
+    range: Range<usize>,
+    compressed: bool,
+    /// Uncompressed contents of the body.
+    bytes: Vec<u8>,
+}
+
+/// Read the body of a binary Gbx file.
+fn read_body(bytes: &[u8]) -> Option<GbxBody> {
+    let table = read_reference_table(bytes)?;
+
+    if !table.compressed_body {
+        return Some(GbxBody {
+            range: table.end..bytes.len(),
+            compressed: false,
+            bytes: bytes[table.end..].to_vec(),
+        });
+    }
+
+    let mut reader = GbxReader {
+        bytes: &bytes[table.end..],
+    };
+
+    let uncompressed_len = reader.u32()? as usize;
+    let compressed_len = reader.u32()? as usize;
+    let contents = lzo::decompress(reader.take(compressed_len)?)?;
+
+    (contents.len() == uncompressed_len).then_some(GbxBody {
+        range: table.end..table.end + 8 + compressed_len,
+        compressed: true,
+        bytes: contents,
+    })
+}
+
+/// The given file with the given body replaced by the given contents.
+fn replace_body(bytes: &[u8], body: &GbxBody, contents: &[u8]) -> Vec<u8> {
+    let mut result = bytes[..body.range.start].to_vec();
+
+    if body.compressed {
+        let compressed = lzo::compress(contents);
+
+        result.extend((contents.len() as u32).to_le_bytes());
+        result.extend((compressed.len() as u32).to_le_bytes());
+        result.extend(compressed);
+    } else {
+        result.extend_from_slice(contents);
+    }
+
+    result.extend_from_slice(&bytes[body.range.end..]);
+
+    result
+}
+
+/// Body chunk of a map.
+struct BodyChunk {
+    id: u32,
+    /// Position of the chunk in the body, including its id, marker and size. Only the id is known
+    /// of chunks stored without their size.
+    range: Range<usize>,
+    /// Position of the data of the chunk in the body, if it is stored with its size.
+    data: Option<Range<usize>>,
+}
+
+/// Find the body chunks of a map that are stored with their size, and the chunks stored without
+/// it that directly follow them.
+///
+/// Chunks stored without their size cannot be skipped without reading them, so the body is
+/// searched for the marker of skippable chunks. A match is only taken if it starts a run of
+/// skippable chunks, each starting where the previous one ended, that ends at the end marker or at
+/// the id of a chunk stored without its size. Chunk ids increase throughout the body, so this is
+/// checked as well. The search continues after the id of such a chunk, so the data of chunks
+/// stored without their size is never mistaken for chunks. Chunks stored without their size that
+/// do not follow a run, such as those at the start of the body, are not found.
+fn find_body_chunks(body: &[u8]) -> Vec<BodyChunk> {
+    let mut chunks: Vec<BodyChunk> = vec![];
+    let mut position = 0;
+
+    while let Some(offset) = body.get(position + 4..).and_then(|rest| {
+        rest.windows(4)
+            .position(|window| window == SKIPPABLE_CHUNK_MARKER)
+    }) {
+        let start = position + offset;
+        let previous_id = chunks.last().map(|chunk| chunk.id);
+
+        match skippable_run(body, start, previous_id) {
+            Some((run, next)) => {
+                chunks.extend(run);
+
+                match next {
+                    Some(next) => {
+                        position = next.range.end;
+                        chunks.push(next);
+                    }
+                    None => break,
+                }
+            }
+            None => position = start + 1,
+        }
+    }
+
+    chunks
+}
+
+/// The skippable chunks starting at the given position of a body, and the chunk stored without
+/// its size that follows them, or `None` if they do not end at the end marker or at such a chunk.
+fn skippable_run(
+    body: &[u8],
+    start: usize,
+    previous_id: Option<u32>,
+) -> Option<(Vec<BodyChunk>, Option<BodyChunk>)> {
+    let mut run = vec![];
+    let mut position = start;
+    let mut previous_id = previous_id;
+
+    loop {
+        let mut reader = GbxReader {
+            bytes: body.get(position..)?,
+        };
+
+        let id = reader.u32()?;
+
+        if id == END_MARKER && position + 4 == body.len() {
+            return (!run.is_empty()).then_some((run, None));
+        }
+
+        if id & 0xfffff000 != MAP_CLASS_ID || previous_id.is_some_and(|previous| id <= previous) {
+            return None;
+        }
+
+        if reader.take(4) != Some(SKIPPABLE_CHUNK_MARKER) {
+            let next = BodyChunk {
+                id,
+                range: position..position + 4,
+                data: None,
+            };
+
+            return (!run.is_empty()).then_some((run, Some(next)));
+        }
+
+        let len = reader.u32()? as usize;
+        let data = position + 12..(position + 12).checked_add(len)?;
+
+        if data.end > body.len() {
+            return None;
+        }
+
+        run.push(BodyChunk {
+            id,
+            range: position..data.end,
+            data: Some(data.clone()),
+        });
+
+        previous_id = Some(id);
+        position = data.end;
+    }
+}
+
+/// The given body with its chunks replaced by the given chunks with the same id, and the given
+/// chunks it lacks added in the order of their ids, or `None` if it has no end marker.
+fn put_body_chunks(body: &[u8], chunks: &[GbxChunkDesc]) -> Option<Vec<u8>> {
+    let encode = |chunk: &GbxChunkDesc| {
+        let mut bytes = chunk.id.to_le_bytes().to_vec();
+        bytes.extend_from_slice(SKIPPABLE_CHUNK_MARKER);
+        bytes.extend((chunk.bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&chunk.bytes);
+        bytes
+    };
+
+    let end = body
+        .len()
+        .checked_sub(4)
+        .filter(|&end| body[end..] == END_MARKER.to_le_bytes())?;
+
+    let found = find_body_chunks(body);
+    let mut result = vec![];
+    let mut position = 0;
+
+    let mut added: Vec<_> = chunks
+        .iter()
+        .filter(|chunk| !found.iter().any(|found_chunk| found_chunk.id == chunk.id))
+        .collect();
+
+    added.sort_by_key(|chunk| chunk.id);
+
+    let mut added = added.into_iter().peekable();
+
+    for found_chunk in &found {
+        result.extend_from_slice(&body[position..found_chunk.range.start]);
+        position = found_chunk.range.start;
+
+        // Added chunks go before the first chunk with a larger id, as ids increase throughout
+        // the body.
+        while let Some(chunk) = added.next_if(|chunk| chunk.id < found_chunk.id) {
+            result.extend(encode(chunk));
+        }
+
+        if found_chunk.data.is_none() {
+            continue;
+        }
+
+        if let Some(chunk) = chunks.iter().find(|chunk| chunk.id == found_chunk.id) {
+            result.extend(encode(chunk));
+            position = found_chunk.range.end;
+        }
+    }
+
+    result.extend_from_slice(&body[position..end]);
+    result.extend(added.flat_map(encode));
+    result.extend(END_MARKER.to_le_bytes());
+
+    Some(result)
+}
+
+struct GbxReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> GbxReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.bytes.split_at_checked(len)?;
+        self.bytes = rest;
+
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()?;
+
+        String::from_utf8(self.take(len as usize)?.to_vec()).ok()
+    }
+}
+
+/// Path of a file among the embedded files of a map, using `/` as separator.
+fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+/// Folder of the given path, which is empty for files at the root.
+fn parent_folder(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(folder, _)| folder)
+}
+
+/// Whether the given folder is the other folder or inside of it.
+fn is_in_folder(folder: &str, other: &str) -> bool {
+    other.is_empty()
+        || folder.eq_ignore_ascii_case(other)
+        || folder
+            .get(..other.len() + 1)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(&format!("{other}/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A map file without header chunks or references, with the given body compressed.
+    fn map_file(body: &[u8]) -> Vec<u8> {
+        let compressed = lzo::compress(body);
+
+        let mut bytes = b"GBX".to_vec();
+        bytes.extend(6u16.to_le_bytes());
+        bytes.extend(b"BUCR");
+        bytes.extend(MAP_CLASS_ID.to_le_bytes());
+        // User data with no chunks, no nodes and no references.
+        bytes.extend(4u32.to_le_bytes());
+        bytes.extend([0; 12]);
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend((compressed.len() as u32).to_le_bytes());
+        bytes.extend(compressed);
+
+        bytes
+    }
+
+    fn skippable_chunk(id: u32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_le_bytes().to_vec();
+        bytes.extend(SKIPPABLE_CHUNK_MARKER);
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+
+        bytes
+    }
+
+    fn body(chunks: &[Vec<u8>]) -> Vec<u8> {
+        // A chunk that is stored without its size.
+        let mut bytes = 0x0304300du32.to_le_bytes().to_vec();
+        bytes.extend(b"blocks");
+        bytes.extend(chunks.concat());
+        bytes.extend(END_MARKER.to_le_bytes());
+
+        bytes
+    }
+
+    fn passthrough(body_chunks: Vec<GbxChunkDesc>) -> PassthroughDesc {
+        PassthroughDesc {
+            header_chunks: vec![],
+            body_chunks,
+            lost: vec!["lightmaps".to_owned()],
+        }
+    }
+
+    fn metadata(bytes: &[u8]) -> GbxChunkDesc {
+        GbxChunkDesc {
+            id: 0x03043044,
+            heavy: false,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    fn chunk_ids(chunks: &[BodyChunk]) -> Vec<(u32, bool)> {
+        chunks
+            .iter()
+            .map(|chunk| (chunk.id, chunk.data.is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn body_chunks_are_found_by_their_marker() {
+        let body = body(&[
+            skippable_chunk(0x0304303d, b"light"),
+            skippable_chunk(0x03043044, b"PIKS in data"),
+        ]);
+
+        let chunks = find_body_chunks(&body);
+
+        assert_eq!(chunk_ids(&chunks), [(0x0304303d, true), (0x03043044, true)]);
+        assert_eq!(&body[chunks[1].data.clone().unwrap()], b"PIKS in data");
+    }
+
+    #[test]
+    fn markers_in_chunks_stored_without_their_size_are_not_chunks() {
+        // Data that looks like a skippable chunk, but is not followed by another chunk.
+        let mut blocks = skippable_chunk(0x03043051, b"xx");
+        blocks.extend(b"more blocks");
+
+        let mut clips = 0x03043049u32.to_le_bytes().to_vec();
+        clips.extend(skippable_chunk(0x0304304a, b"clip"));
+        clips.extend(b"more clips");
+
+        let body = body(&[
+            blocks,
+            skippable_chunk(0x03043044, b"metadata"),
+            clips,
+            skippable_chunk(0x0304304b, b"unknown"),
+        ]);
+
+        assert_eq!(
+            chunk_ids(&find_body_chunks(&body)),
+            [(0x03043044, true), (0x03043049, false), (0x0304304b, true)]
+        );
+
+        let (passthrough, report) = passthrough_of(&map_file(&body)).unwrap();
+
+        assert!(passthrough.body_chunks.len() == 1);
+        assert_eq!(passthrough.body_chunks[0].bytes, b"metadata");
+        assert_eq!(report.kept, ["script metadata"]);
+        assert_eq!(report.lost, ["MediaTracker clips", "body chunk 0304304b"]);
+    }
+
+    #[test]
+    fn body_chunks_are_replaced_or_added() {
+        let saved = map_file(&body(&[
+            skippable_chunk(0x03043040, b"items"),
+            skippable_chunk(0x03043044, b"saved"),
+        ]));
+
+        let (restored, report) = restore_passthrough(
+            &saved,
+            &passthrough(vec![
+                metadata(b"kept"),
+                GbxChunkDesc {
+                    id: 0x03043042,
+                    heavy: false,
+                    bytes: b"author".to_vec(),
+                },
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_body(&restored).unwrap().bytes,
+            body(&[
+                skippable_chunk(0x03043040, b"items"),
+                skippable_chunk(0x03043042, b"author"),
+                skippable_chunk(0x03043044, b"kept"),
+            ])
+        );
+        assert_eq!(report.kept, ["script metadata", "body chunk 03043042"]);
+        assert_eq!(report.lost, ["lightmaps"]);
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    /// The start of a map body with the given chunks in its parameters node.
+    fn params_body(params_chunks: &[Vec<u8>]) -> Vec<u8> {
+        // The vehicle, as a new id, a number and a new id.
+        let mut bytes = u32s(&[VEHICLE_CHUNK_ID, 3, 0x40000000, 8]);
+        bytes.extend(b"CarSport");
+        bytes.extend(u32s(&[10003, 0x40000000, 5]));
+        bytes.extend(b"Nadeo");
+        // A list of one block, referring to the vehicle ids.
+        bytes.extend(u32s(&[PARAMS_NODE_CHUNK_ID, 0, COLLECTOR_LIST_CLASS_ID]));
+        bytes.extend(u32s(&[COLLECTOR_LIST_CLASS_ID, 1, 0x40000000, 4]));
+        bytes.extend(b"Road");
+        bytes.extend(u32s(&[10003, 0x40000002, 1, END_MARKER]));
+        bytes.extend(u32s(&[1, CHALLENGE_PARAMS_CLASS_ID]));
+        bytes.extend(params_chunks.concat());
+        bytes.extend(u32s(&[END_MARKER, 0x03043014]));
+        bytes.extend(b"more map");
+
+        bytes
+    }
+
+    #[test]
+    fn medal_times_are_written_into_the_parameters_node() {
+        let mut tips = 0x0305b001u32.to_le_bytes().to_vec();
+        tips.extend([0; 16]);
+
+        let saved = map_file(&params_body(&[
+            tips.clone(),
+            u32s(&[0x0305b004, 1, 2, 3, 4, 0]),
+            u32s(&[0x0305b008, 60000, 0]),
+            skippable_chunk(0x0305b00a, &u32s(&[0, 1, 2, 3, 4, 60000, 0])),
+            u32s(&[0x0305b00d, u32::MAX]),
+        ]));
+
+        let map_params_desc = MapParamsDesc {
+            mood: Mood::Day,
+            medal_times: MedalTimesDesc {
+                author: Some(10000),
+                gold: Some(11000),
+                silver: None,
+                bronze: Some(15000),
+            },
+            validated: false,
+        };
+
+        let written = write_map_params(&saved, &map_params_desc).unwrap();
+
+        assert_eq!(
+            read_body(&written).unwrap().bytes,
+            params_body(&[
+                tips.clone(),
+                u32s(&[0x0305b004, 15000, NO_TIME, 11000, NO_TIME, 0]),
+                u32s(&[0x0305b008, 60000, 0]),
+                skippable_chunk(
+                    0x0305b00a,
+                    &u32s(&[0, 15000, NO_TIME, 11000, NO_TIME, 60000, 0])
+                ),
+                u32s(&[0x0305b00d, u32::MAX]),
+            ])
+        );
+
+        let validated = MapParamsDesc {
+            validated: true,
+            ..map_params_desc
+        };
+
+        let written = write_map_params(&saved, &validated).unwrap();
+
+        assert_eq!(
+            read_body(&written).unwrap().bytes,
+            params_body(&[
+                tips,
+                u32s(&[0x0305b004, 15000, NO_TIME, 11000, 10000, 0]),
+                u32s(&[0x0305b008, 60000, 0]),
+                skippable_chunk(
+                    0x0305b00a,
+                    &u32s(&[0, 15000, NO_TIME, 11000, 10000, 60000, 0])
+                ),
+                u32s(&[0x0305b00d, u32::MAX]),
+            ])
+        );
+    }
+
+    #[test]
+    fn unknown_parameters_are_refused() {
+        let saved = map_file(&params_body(&[
+            u32s(&[0x0305b004, 1, 2, 3, 4, 0]),
+            u32s(&[0x0305b00e, 0]),
+        ]));
+
+        let map_params_desc = MapParamsDesc {
+            mood: Mood::Day,
+            medal_times: MedalTimesDesc::default(),
+            validated: false,
+        };
+
+        assert!(write_map_params(&saved, &map_params_desc).is_err());
+    }
+
+    #[test]
+    fn saved_maps_without_end_marker_are_refused() {
+        let mut body = body(&[]);
+        body.pop();
+
+        assert!(restore_passthrough(&map_file(&body), &passthrough(vec![metadata(b"")])).is_err());
+    }
+}
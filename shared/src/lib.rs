@@ -1,24 +1,62 @@
-use gamebox::{
-    engines::game::map::{Direction, ElemColor, PhaseOffset},
-    Vec3,
-};
+pub mod diff;
+pub mod gbx;
+mod lzo;
+pub mod macroblock;
+pub mod map;
+pub mod merge;
+pub mod offzone;
+pub mod patch;
+pub mod replay;
+
+#[cfg(test)]
+mod test_util;
+
+use gamebox::engines::game::map::{Direction, ElemColor, PhaseOffset};
+use patch::PatchFailure;
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
 use tokio_util::codec::{Decoder, Framed, LengthDelimitedCodec};
 
-pub type FramedTcpStream = Framed<TcpStream, LengthDelimitedCodec>;
+pub use gamebox::Vec3;
+pub use tokio_util::bytes::Bytes;
+
+/// Stream of length delimited frames over any transport, such as a TCP stream or an in-memory
+/// pipe.
+pub type FramedStream<T> = Framed<T, LengthDelimitedCodec>;
+pub type FramedTcpStream = FramedStream<TcpStream>;
 pub type Hash = blake3::Hash;
 pub type NotNan<T> = ordered_float::NotNan<T>;
+pub type SerdeError = postcard::Error;
+
+/// Content-derived identifier of an object, see [`ObjectDesc::id`].
+pub type ObjectId = Hash;
+
+/// Size of a block coordinate unit in world units.
+pub const BLOCK_SIZE: Vec3<f32> = Vec3 {
+    x: 32.0,
+    y: 8.0,
+    z: 32.0,
+};
+
+/// Block coordinate height at which the world height is 0.
+pub const BLOCK_COORD_Y_OFFSET: u8 = 8;
+
+pub fn framed_stream<T: AsyncRead + AsyncWrite>(stream: T) -> FramedStream<T> {
+    LengthDelimitedCodec::new().framed(stream)
+}
 
 pub fn framed_tcp_stream(tcp_stream: TcpStream) -> FramedTcpStream {
-    LengthDelimitedCodec::new().framed(tcp_stream)
+    framed_stream(tcp_stream)
 }
 
-pub fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>, postcard::Error> {
+pub fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>, SerdeError> {
     postcard::to_stdvec(&value)
 }
 
-pub fn deserialize<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, postcard::Error> {
+pub fn deserialize<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, SerdeError> {
     postcard::from_bytes(bytes)
 }
 
@@ -26,7 +64,7 @@ pub fn hash(bytes: &[u8]) -> Hash {
     blake3::Hasher::new().update(bytes).finalize()
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum Mood {
     Day,
     Sunset,
@@ -34,50 +72,178 @@ pub enum Mood {
     Sunrise,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MapParamsDesc {
     pub mood: Mood,
+    pub medal_times: MedalTimesDesc,
+    /// Whether the map was driven to the author time since the last change to its geometry.
+    pub validated: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Medal times of a map in milliseconds, each of which is `None` if it is not set.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct MedalTimesDesc {
+    pub author: Option<u32>,
+    pub gold: Option<u32>,
+    pub silver: Option<u32>,
+    pub bronze: Option<u32>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct MapDesc {
     pub custom_blocks: Vec<CustomBlockDesc>,
     pub custom_items: Vec<CustomItemDesc>,
+    /// Skin files embedded in the map, identified by the hash of their bytes.
+    pub custom_skins: Vec<EmbeddedFileDesc>,
+    /// Files the custom blocks and items depend on, each stored once and identified by the hash
+    /// of its bytes.
+    pub embedded_files: Vec<EmbeddedFileDesc>,
     pub blocks: Vec<BlockDesc>,
     pub ghost_blocks: Vec<GhostBlockDesc>,
     pub free_blocks: Vec<FreeBlockDesc>,
     pub items: Vec<ItemDesc>,
+    /// Disjoint boxes in which cars are not allowed to be, see [`offzone`].
+    pub offzones: Vec<OffzoneDesc>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A custom block, identified by [`CustomBlockDesc::hash`].
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CustomBlockDesc {
+    /// Path of the block among the embedded files of the map.
+    pub path: String,
     pub bytes: Vec<u8>,
+    /// Other embedded files the block needs, such as materials and textures.
+    pub dependencies: Vec<DependencyDesc>,
 }
 
-#[derive(Serialize, Deserialize)]
+impl CustomBlockDesc {
+    /// Hash of the bytes and dependencies of the block.
+    pub fn hash(&self) -> Hash {
+        custom_object_hash(&self.bytes, &self.dependencies)
+    }
+}
+
+/// A custom item, identified by [`CustomItemDesc::hash`].
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CustomItemDesc {
+    /// Path of the item among the embedded files of the map.
+    pub path: String,
+    pub bytes: Vec<u8>,
+    /// Other embedded files the item needs, such as materials and textures.
+    pub dependencies: Vec<DependencyDesc>,
+}
+
+impl CustomItemDesc {
+    /// Hash of the bytes and dependencies of the item.
+    pub fn hash(&self) -> Hash {
+        custom_object_hash(&self.bytes, &self.dependencies)
+    }
+}
+
+/// Hash identifying a custom object, which is the hash of its bytes if it has no dependencies, so
+/// that the same object copied with different dependencies is a different object.
+fn custom_object_hash(bytes: &[u8], dependencies: &[DependencyDesc]) -> Hash {
+    let bytes_hash = hash(bytes);
+
+    if dependencies.is_empty() {
+        return bytes_hash;
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(bytes_hash.as_bytes());
+
+    for dependency in dependencies {
+        hasher.update(&(dependency.path.len() as u64).to_le_bytes());
+        hasher.update(dependency.path.as_bytes());
+        hasher.update(dependency.hash.as_bytes());
+    }
+
+    hasher.finalize()
+}
+
+/// A file embedded in a map that is not a custom object itself, identified by the hash of its
+/// bytes.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbeddedFileDesc {
+    /// Path of the file among the embedded files of the map, using `/` as separator.
+    pub path: String,
+    pub bytes: Vec<u8>,
+}
+
+impl EmbeddedFileDesc {
+    pub fn hash(&self) -> Hash {
+        hash(&self.bytes)
+    }
+}
+
+/// Embedded file a custom object needs, see [`MapDesc::embedded_files`].
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DependencyDesc {
+    /// Path of the file among the embedded files of the map, using `/` as separator.
+    pub path: String,
+    #[serde(with = "hex_hash")]
+    pub hash: Hash,
+}
+
+/// Box of block coordinates in which cars are not allowed to be.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct OffzoneDesc {
+    pub min: Vec3<u8>,
+    /// Coordinate of the opposite corner, which is part of the box as well.
+    pub max: Vec3<u8>,
+}
+
+/// Parts of the source map file of a room that its description does not model, kept as they are
+/// so that they can be put back into the map when it is saved, see [`gbx::restore_passthrough`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PassthroughDesc {
+    pub header_chunks: Vec<GbxChunkDesc>,
+    pub body_chunks: Vec<GbxChunkDesc>,
+    /// Names of the parts that the source map file has but that are not kept.
+    pub lost: Vec<String>,
+}
+
+/// A chunk of a Gbx file that is not understood.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GbxChunkDesc {
+    pub id: u32,
+    /// Whether the game skips the chunk when only reading the header of the file, which is never
+    /// the case for body chunks.
+    pub heavy: bool,
     pub bytes: Vec<u8>,
 }
 
-#[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct BlockDesc {
     pub block_info_id: ModelId,
     pub coord: Vec3<u8>,
     pub dir: Direction,
     pub is_air_variant: bool,
+    /// Index of the ground or air variant of the block info, depending on `is_air_variant`.
+    pub variant_index: u8,
+    /// Index of the mobil within the variant.
+    pub subvariant_index: u8,
     pub elem_color: ElemColor,
+    pub waypoint: Option<WaypointDesc>,
+    pub skin: Option<Box<SkinDesc>>,
 }
 
-#[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct GhostBlockDesc {
     pub block_info_id: ModelId,
     pub coord: Vec3<u8>,
     pub dir: Direction,
     pub is_air_variant: bool,
+    /// Index of the ground or air variant of the block info, depending on `is_air_variant`.
+    pub variant_index: u8,
+    /// Index of the mobil within the variant.
+    pub subvariant_index: u8,
     pub elem_color: ElemColor,
+    pub waypoint: Option<WaypointDesc>,
+    pub skin: Option<Box<SkinDesc>>,
 }
 
-#[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FreeBlockDesc {
     pub block_info_id: ModelId,
     pub position: Vec3<NotNan<f32>>,
@@ -85,9 +251,11 @@ pub struct FreeBlockDesc {
     pub pitch: NotNan<f32>,
     pub roll: NotNan<f32>,
     pub elem_color: ElemColor,
+    pub waypoint: Option<WaypointDesc>,
+    pub skin: Option<Box<SkinDesc>>,
 }
 
-#[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ItemDesc {
     pub item_model_id: ModelId,
     pub position: Vec3<NotNan<f32>>,
@@ -97,10 +265,455 @@ pub struct ItemDesc {
     pub pivot_position: Vec3<NotNan<f32>>,
     pub elem_color: ElemColor,
     pub anim_offset: PhaseOffset,
+    pub waypoint: Option<WaypointDesc>,
+    pub skin: Option<Box<SkinDesc>>,
+    pub attachment: Option<AttachmentDesc>,
+}
+
+/// Object an item is attached to, such as the block it was placed on or a moving part of a block.
+///
+/// Removing the object removes the items attached to it as well, while [`Edit::Modify`] attaches
+/// them to the new version of the object.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AttachmentDesc {
+    /// Id of the block, ghost block or free block.
+    #[serde(with = "hex_hash")]
+    pub object_id: ObjectId,
+}
+
+/// Role of a block or item in the race, such as being a checkpoint.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct WaypointDesc {
+    /// Kind of waypoint as named in Gbx files, which is `Spawn`, `Goal`, `StartFinish`,
+    /// `Checkpoint` or `LinkedCheckpoint`.
+    pub tag: String,
+    /// Order of the waypoint, which for linked checkpoints is the group they are linked by.
+    pub order: u32,
 }
 
-#[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// Skin of a block or item, such as the image on a sign or flag.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SkinDesc {
+    pub background: Option<SkinFileDesc>,
+    /// Layer drawn on top of the background, as used by signs.
+    pub foreground: Option<SkinFileDesc>,
+}
+
+/// A file used as skin.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SkinFileDesc {
+    /// File that is not embedded in the map, such as a skin that comes with the game, which is
+    /// downloaded from the given URL if it is missing.
+    External { path: String, url: Option<String> },
+    /// Skin file embedded in the map, see [`MapDesc::custom_skins`].
+    Custom {
+        #[serde(with = "hex_hash")]
+        hash: Hash,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ModelId {
-    Game { id: String },
-    Custom { hash: Hash },
+    Game {
+        id: String,
+    },
+    Custom {
+        #[serde(with = "hex_hash")]
+        hash: Hash,
+    },
+}
+
+/// Serialization of hashes as hex strings in human-readable formats such as JSON, keeping the
+/// compact binary encoding otherwise.
+pub mod hex_hash {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::Hash;
+
+    pub fn serialize<S: Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hash.to_hex())
+        } else {
+            hash.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hash, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+
+            Hash::from_hex(hex).map_err(D::Error::custom)
+        } else {
+            Hash::deserialize(deserializer)
+        }
+    }
+}
+
+/// A single object placed in a map.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ObjectDesc {
+    Block(BlockDesc),
+    GhostBlock(GhostBlockDesc),
+    FreeBlock(FreeBlockDesc),
+    Item(ItemDesc),
+}
+
+impl ObjectDesc {
+    /// Id of the block info or item model of this object.
+    pub fn model_id(&self) -> &ModelId {
+        match self {
+            Self::Block(block) => &block.block_info_id,
+            Self::GhostBlock(ghost_block) => &ghost_block.block_info_id,
+            Self::FreeBlock(free_block) => &free_block.block_info_id,
+            Self::Item(item) => &item.item_model_id,
+        }
+    }
+
+    pub fn skin(&self) -> Option<&SkinDesc> {
+        match self {
+            Self::Block(block) => block.skin.as_deref(),
+            Self::GhostBlock(ghost_block) => ghost_block.skin.as_deref(),
+            Self::FreeBlock(free_block) => free_block.skin.as_deref(),
+            Self::Item(item) => item.skin.as_deref(),
+        }
+    }
+
+    /// Hashes of the custom skin files used by this object.
+    pub fn custom_skins(&self) -> impl Iterator<Item = Hash> + '_ {
+        self.skin()
+            .into_iter()
+            .flat_map(|skin| [&skin.background, &skin.foreground])
+            .filter_map(|skin_file| match skin_file {
+                Some(SkinFileDesc::Custom { hash }) => Some(*hash),
+                _ => None,
+            })
+    }
+
+    pub fn waypoint(&self) -> Option<&WaypointDesc> {
+        match self {
+            Self::Block(block) => block.waypoint.as_ref(),
+            Self::GhostBlock(ghost_block) => ghost_block.waypoint.as_ref(),
+            Self::FreeBlock(free_block) => free_block.waypoint.as_ref(),
+            Self::Item(item) => item.waypoint.as_ref(),
+        }
+    }
+
+    /// Id of the object this object is attached to, if any.
+    pub fn attached_to(&self) -> Option<ObjectId> {
+        match self {
+            Self::Item(item) => item
+                .attachment
+                .as_ref()
+                .map(|attachment| attachment.object_id),
+            _ => None,
+        }
+    }
+
+    /// Attach this object to the object with the given id instead, if it is attached to any.
+    pub fn reattach(&mut self, object_id: ObjectId) {
+        if let Self::Item(ItemDesc {
+            attachment: Some(attachment),
+            ..
+        }) = self
+        {
+            attachment.object_id = object_id;
+        }
+    }
+
+    /// Identifier of this object, which is the hash of its serialized description.
+    pub fn id(&self) -> ObjectId {
+        hash(&serialize(self).expect("object descriptions are always serializable"))
+    }
+
+    /// Position of this object in world units.
+    ///
+    /// For blocks on the grid this is the corner of the block with the smallest coordinates.
+    pub fn position(&self) -> Vec3<f32> {
+        match self {
+            Self::Block(BlockDesc { coord, .. })
+            | Self::GhostBlock(GhostBlockDesc { coord, .. }) => coord_to_position(*coord),
+            Self::FreeBlock(FreeBlockDesc { position, .. })
+            | Self::Item(ItemDesc { position, .. }) => Vec3 {
+                x: position.x.into_inner(),
+                y: position.y.into_inner(),
+                z: position.z.into_inner(),
+            },
+        }
+    }
+}
+
+/// Convert a block coordinate to a position in world units.
+pub fn coord_to_position(coord: Vec3<u8>) -> Vec3<f32> {
+    Vec3 {
+        x: coord.x as f32 * BLOCK_SIZE.x,
+        y: (coord.y as f32 - BLOCK_COORD_Y_OFFSET as f32) * BLOCK_SIZE.y,
+        z: coord.z as f32 * BLOCK_SIZE.z,
+    }
+}
+
+/// Axis-aligned bounding box in world units.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct AabbDesc {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>,
+}
+
+impl AabbDesc {
+    pub fn contains(&self, position: Vec3<f32>) -> bool {
+        (self.min.x..=self.max.x).contains(&position.x)
+            && (self.min.y..=self.max.y).contains(&position.y)
+            && (self.min.z..=self.max.z).contains(&position.z)
+    }
+}
+
+/// A single change to a map.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Edit {
+    AddCustomBlock(CustomBlockDesc),
+    AddCustomItem(CustomItemDesc),
+    AddCustomSkin(EmbeddedFileDesc),
+    /// Add a file that custom blocks and items can depend on.
+    AddEmbeddedFile(EmbeddedFileDesc),
+    Place(ObjectDesc),
+    Remove(ObjectDesc),
+    /// Replace an object with a changed version of it, such as the same block in another colour.
+    ///
+    /// Unlike removing the object and placing the new version, this keeps the items attached to
+    /// the object, which are attached to the new version instead.
+    Modify {
+        old: Box<ObjectDesc>,
+        new: Box<ObjectDesc>,
+    },
+    /// Cover the coordinates of the given box with offzone, merging it with the offzones next to
+    /// it.
+    AddOffzone(OffzoneDesc),
+    /// Remove offzone from the coordinates of the given box, splitting the offzones it overlaps.
+    RemoveOffzone(OffzoneDesc),
+}
+
+impl Edit {
+    /// Whether the edit changes what can be driven on, which invalidates the map.
+    pub fn changes_geometry(&self) -> bool {
+        match self {
+            Self::AddCustomBlock(_)
+            | Self::AddCustomItem(_)
+            | Self::AddCustomSkin(_)
+            | Self::AddEmbeddedFile(_) => false,
+            Self::Place(_)
+            | Self::Remove(_)
+            | Self::Modify { .. }
+            | Self::AddOffzone(_)
+            | Self::RemoveOffzone(_) => true,
+        }
+    }
+}
+
+/// Point in the edit history at which the server saved a snapshot of the map.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavePointDesc {
+    /// Sequence number of the first edit not contained in the snapshot.
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch at which the snapshot was saved.
+    pub timestamp: u64,
+}
+
+/// Edit history the server keeps for a room.
+///
+/// It is limited in size, and after the server restarts it only reaches back to the latest
+/// snapshot of the room.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryDesc {
+    /// Earliest sequence number the map can be previewed or rolled back at.
+    pub first_seq: u64,
+    /// Sequence number the next accepted edit will get.
+    pub next_seq: u64,
+    pub save_points: Vec<SavePointDesc>,
+}
+
+/// Who made the last change to an object, and when.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EditInfoDesc {
+    pub author: String,
+    /// Milliseconds since the Unix epoch at which the edit was accepted.
+    pub timestamp: u64,
+    pub seq: u64,
+}
+
+/// Blame of a single object in the map.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ObjectBlameDesc {
+    pub object: ObjectDesc,
+    /// Last edit that placed or modified the object, or `None` if it was already in the map when
+    /// the session started.
+    pub edit: Option<EditInfoDesc>,
+}
+
+/// Number of edits a single user made in the session.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UserEditSummaryDesc {
+    pub user_name: String,
+    pub placed: u64,
+    pub removed: u64,
+    pub modified: u64,
+    pub custom_objects_added: u64,
+    /// Milliseconds since the Unix epoch at which the last edit of this user was accepted.
+    pub last_timestamp: u64,
+}
+
+/// A room hosted by the server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomDesc {
+    pub name: String,
+    /// User names of the members currently in the room.
+    pub members: Vec<String>,
+}
+
+/// Resource usage of the server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ServerStatsDesc {
+    /// Number of open connections, including the one requesting the stats.
+    pub connections: u64,
+    /// Resident memory of the server process in bytes, if the platform reports it.
+    pub resident_memory: Option<u64>,
+}
+
+/// First message sent by a client after connecting.
+#[derive(Serialize, Deserialize)]
+pub enum Handshake {
+    /// Join the room with the given name.
+    Join { room: String, user_name: String },
+    /// Request the rooms hosted by the server, after which the connection is closed.
+    ListRooms,
+    /// Create a new room from the given map and join it.
+    ///
+    /// The server does not send the map back, as the client already has it. The passthrough is
+    /// kept with the room if the map comes from a map file.
+    Host {
+        room: String,
+        user_name: String,
+        map_params_desc: MapParamsDesc,
+        map_desc: Box<MapDesc>,
+        passthrough: Option<PassthroughDesc>,
+    },
+    /// Request the resource usage of the server, after which the connection is closed.
+    GetStats,
+    /// Request the map parameters and passthrough of the room with the given name, after which the
+    /// connection is closed.
+    GetPassthrough { room: String },
+}
+
+/// Response of the server to a [`Handshake`].
+///
+/// After [`HandshakeResponse::Joined`] the server sends the [`MapDesc`] of the room, after which
+/// all further messages are [`ServerMessage`]s, as they are directly after
+/// [`HandshakeResponse::Hosted`]. After any other response the connection is closed.
+#[derive(Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    Joined(MapParamsDesc),
+    UnknownRoom,
+    Rooms(Vec<RoomDesc>),
+    /// The room of a [`Handshake::Host`] was created and joined.
+    Hosted,
+    /// The room of a [`Handshake::Host`] already exists.
+    RoomExists,
+    /// The room name of a [`Handshake::Host`] is not allowed.
+    InvalidRoomName,
+    /// The room of a [`Handshake::Host`] could not be created.
+    HostFailed,
+    Stats(ServerStatsDesc),
+    /// Map parameters and passthrough of the room of a [`Handshake::GetPassthrough`], the latter
+    /// of which is `None` if the room was not created from a map file.
+    Passthrough {
+        map_params_desc: MapParamsDesc,
+        passthrough: Option<PassthroughDesc>,
+    },
+}
+
+/// Message sent by a client after it received the initial map.
+#[derive(Serialize, Deserialize)]
+pub enum ClientMessage {
+    Edit(Edit),
+    /// Request the edit history of the session.
+    GetHistory,
+    /// Request the map as it was before the edit with the given sequence number.
+    PreviewHistory {
+        seq: u64,
+    },
+    /// Undo all edits starting at the given sequence number, which is answered with
+    /// [`ServerMessage::Rollback`].
+    Rollback {
+        seq: u64,
+    },
+    /// Request the blame of the object with the given id.
+    BlameObject {
+        id: ObjectId,
+    },
+    /// Request the blame of all objects positioned within the given box.
+    BlameRegion {
+        aabb: AabbDesc,
+    },
+    /// Request a per-user summary of the edits in the session.
+    GetEditSummary,
+    /// Send a chat message to all members of the room.
+    Chat {
+        text: String,
+    },
+    /// Insert a macroblock, see [`macroblock`], turned by the given number of quarter turns with
+    /// its smallest block coordinate at the given coordinate.
+    ///
+    /// All of its edits are accepted one after the other, or none if any of them does not apply,
+    /// which is answered with [`ServerMessage::InsertMacroblock`].
+    InsertMacroblock {
+        macroblock: Box<MapDesc>,
+        coord: Vec3<u8>,
+        quarter_turns: u8,
+    },
+    /// Set the medal times of the map, without changing whether it is validated.
+    SetMedalTimes(MedalTimesDesc),
+    /// Request a [`ServerMessage::Barrier`], which arrives after the echoes of and replies to all
+    /// messages sent before.
+    Barrier,
+    /// Mark the map as validated with the given medal times.
+    ///
+    /// Ignored unless the client had received all accepted edits, given by the number of edits it
+    /// received since joining, as it validated an outdated map otherwise.
+    MarkValidated {
+        medal_times: MedalTimesDesc,
+        received_edits: u64,
+    },
+}
+
+/// Message sent by the server after the initial map.
+#[derive(Serialize, Deserialize)]
+pub enum ServerMessage {
+    Edit(Edit),
+    History(HistoryDesc),
+    /// Map before the edit with the given sequence number, or `None` if that is not in the history.
+    Preview {
+        seq: u64,
+        map_desc: Option<MapDesc>,
+    },
+    /// Whether the map was rolled back to before the edit with the given sequence number, after
+    /// the reverse edits. Fails if that edit is no longer part of the history.
+    Rollback {
+        seq: u64,
+        rolled_back: bool,
+    },
+    Blame(Vec<ObjectBlameDesc>),
+    EditSummary(Vec<UserEditSummaryDesc>),
+    /// Whether the macroblock was inserted, after its edits, or the edits of the macroblock that
+    /// do not apply. Not inserting it without failures means the server failed to store it.
+    InsertMacroblock {
+        inserted: bool,
+        failures: Vec<PatchFailure>,
+    },
+    /// Chat message sent by a member of the room, including the receiving client.
+    Chat {
+        user_name: String,
+        text: String,
+    },
+    /// The medal times or validation state of the map changed.
+    MapParams(MapParamsDesc),
+    /// Reply to [`ClientMessage::Barrier`].
+    Barrier,
 }
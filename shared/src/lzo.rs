@@ -0,0 +1,179 @@
+//! LZO1X, the compression of the body of Gbx files.
+//!
+//! Compression only emits literals, which every LZO1X decompressor reads, at the cost of not
+//! making the data any smaller.
+
+/// Decompress the given LZO1X data, or return `None` if it is invalid.
+pub fn decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut output = vec![];
+    let mut reader = Reader { input, position: 0 };
+
+    // Number of literals copied after the last match, which determines how a small instruction
+    // is read.
+    let mut state;
+
+    let first = reader.peek()?;
+
+    if first > 17 {
+        reader.byte()?;
+
+        let count = (first - 17) as usize;
+        output.extend_from_slice(reader.take(count)?);
+        state = if count < 4 { count } else { 4 };
+    } else {
+        state = 0;
+    }
+
+    loop {
+        let instruction = reader.byte()? as usize;
+
+        let (distance, length, next) = if instruction < 16 {
+            match state {
+                0 => {
+                    let count = reader.length(instruction, 15)? + 3;
+                    output.extend_from_slice(reader.take(count)?);
+                    state = 4;
+                    continue;
+                }
+                1..=3 => {
+                    let distance = 1 + (instruction >> 2) + ((reader.byte()? as usize) << 2);
+                    (distance, 2, instruction & 3)
+                }
+                _ => {
+                    let distance = 2049 + (instruction >> 2) + ((reader.byte()? as usize) << 2);
+                    (distance, 3, instruction & 3)
+                }
+            }
+        } else if instruction >= 64 {
+            let distance = 1 + ((instruction >> 2) & 7) + ((reader.byte()? as usize) << 3);
+            (distance, (instruction >> 5) + 1, instruction & 3)
+        } else if instruction >= 32 {
+            let length = reader.length(instruction & 31, 31)? + 2;
+            let value = reader.u16()?;
+            (1 + (value >> 2), length, value & 3)
+        } else {
+            let length = reader.length(instruction & 7, 7)? + 2;
+            let value = reader.u16()?;
+            let distance = ((instruction & 8) << 11) + (value >> 2);
+
+            if distance == 0 {
+                // The end of the stream.
+                return (reader.position == input.len()).then_some(output);
+            }
+
+            (distance + 16384, length, value & 3)
+        };
+
+        let start = output.len().checked_sub(distance)?;
+
+        // The match may overlap the bytes it produces.
+        for index in start..start + length {
+            output.push(output[index]);
+        }
+
+        output.extend_from_slice(reader.take(next)?);
+        state = next;
+    }
+}
+
+/// Compress the given data as LZO1X.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![];
+
+    match data.len() {
+        0 => {}
+        len @ 1..=238 => output.push(len as u8 + 17),
+        len => {
+            // The length beyond 18 is stored as a run of zeros worth 255 each and a last byte,
+            // which must not be zero.
+            let rest = len - 18;
+            let zeros = (rest - 1) / 255;
+
+            output.push(0);
+            output.extend(std::iter::repeat_n(0, zeros));
+            output.push((rest - zeros * 255) as u8);
+        }
+    }
+
+    output.extend_from_slice(data);
+
+    // The end of the stream.
+    output.extend([17, 0, 0]);
+
+    output
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.position += 1;
+
+        Some(byte)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        Some(self.byte()? as usize | (self.byte()? as usize) << 8)
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .input
+            .get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+
+        Some(bytes)
+    }
+
+    /// The given length, or if it is zero the extended length that follows, counting `base` for
+    /// the zero length.
+    fn length(&mut self, length: usize, base: usize) -> Option<usize> {
+        if length != 0 {
+            return Some(length);
+        }
+
+        let mut length = base;
+
+        while self.peek()? == 0 {
+            self.byte()?;
+            length += 255;
+        }
+
+        Some(length + self.byte()? as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_data_decompresses_to_itself() {
+        for len in [0, 1, 3, 4, 200, 238, 239, 255, 273, 274, 1000, 70000] {
+            let data: Vec<u8> = (0..len).map(|index| (index * 7 % 251) as u8).collect();
+
+            assert_eq!(decompress(&compress(&data)), Some(data), "length {len}");
+        }
+    }
+
+    #[test]
+    fn matches_repeat_earlier_output() {
+        // Three literals, a match of three bytes at distance three and the end of the stream.
+        let input = [20, b'a', b'b', b'c', 33, 8, 0, 17, 0, 0];
+
+        assert_eq!(decompress(&input).as_deref(), Some(&b"abcabc"[..]));
+    }
+
+    #[test]
+    fn truncated_data_is_refused() {
+        assert_eq!(decompress(&compress(b"abcdef")[..5]), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! Macroblocks, which are groups of objects that are inserted into maps as a whole.
+//!
+//! A macroblock is described like a map, with its objects placed relative to each other. It is
+//! inserted by placing all of its objects, after adding the custom objects and skins they use.
+//! Selected objects of a map can be extracted into a macroblock the same way.
+
+use std::collections::HashSet;
+
+use gamebox::Vec3;
+
+use crate::{
+    map::{EditRejection, MapState},
+    patch::{BlockSizes, Patch, PatchFailure, PatchFailureReason, Transform},
+    AabbDesc, Edit, MapDesc, ModelId, ObjectDesc, ObjectId, BLOCK_COORD_Y_OFFSET, BLOCK_SIZE,
+};
+
+/// Objects of a map to extract into a macroblock.
+pub enum Selection {
+    /// All objects positioned within the given box.
+    Region(AabbDesc),
+    /// The objects with the given ids.
+    Objects(HashSet<ObjectId>),
+}
+
+/// Patch that inserts the given macroblock where it is.
+///
+/// Embedded files, custom objects and skins come first, then blocks, so that items follow the
+/// blocks they are attached to.
+pub fn patch(macroblock: &MapDesc) -> Patch {
+    let mut edits = vec![];
+
+    edits.extend(
+        macroblock
+            .embedded_files
+            .iter()
+            .cloned()
+            .map(Edit::AddEmbeddedFile),
+    );
+    edits.extend(
+        macroblock
+            .custom_blocks
+            .iter()
+            .cloned()
+            .map(Edit::AddCustomBlock),
+    );
+    edits.extend(
+        macroblock
+            .custom_items
+            .iter()
+            .cloned()
+            .map(Edit::AddCustomItem),
+    );
+    edits.extend(
+        macroblock
+            .custom_skins
+            .iter()
+            .cloned()
+            .map(Edit::AddCustomSkin),
+    );
+    edits.extend(objects(macroblock).map(Edit::Place));
+    edits.extend(macroblock.offzones.iter().copied().map(Edit::AddOffzone));
+
+    Patch { edits }
+}
+
+/// Transform that turns the given macroblock by the given number of quarter turns and moves it so
+/// that the smallest block coordinate it covers ends up at the given coordinate.
+pub fn transform(macroblock: &MapDesc, coord: Vec3<u8>, quarter_turns: u8) -> Transform {
+    let origin = Vec3 { x: 0, y: 0, z: 0 };
+    let (min, max) = bounds(macroblock).unwrap_or((origin, origin));
+
+    // Turning around the smallest corner moves the covered coordinates below it along one or both
+    // horizontal axes.
+    let (width, depth) = (max.x - min.x + 1, max.z - min.z + 1);
+
+    let (turned_x, turned_z) = match quarter_turns % 4 {
+        0 => (0, 0),
+        1 => (0, -width),
+        2 => (-width, -depth),
+        _ => (-depth, 0),
+    };
+
+    Transform {
+        offset: Vec3 {
+            x: coord.x as i32 - min.x - turned_x,
+            y: coord.y as i32 - min.y,
+            z: coord.z as i32 - min.z - turned_z,
+        },
+        quarter_turns,
+        pivot: min,
+        block_sizes: BlockSizes::new(),
+    }
+}
+
+/// Whether the given failure of a macroblock patch prevents inserting the macroblock.
+///
+/// Custom objects and skins the map already has are simply shared.
+pub fn is_fatal(failure: &PatchFailure) -> bool {
+    failure.reason != PatchFailureReason::Rejected(EditRejection::CustomObjectExists)
+}
+
+/// Extract the selected objects of the given map into a macroblock, moved so that the given block
+/// coordinate becomes the origin, or the smallest coordinate they cover if none is given.
+///
+/// Items attached to selected objects are selected with them, while attachments to objects that
+/// are not selected are dropped. Only the custom objects and skins the selected objects use are
+/// included. Fails with the objects that would end up outside of the block grid.
+pub fn extract(
+    map: &MapState,
+    selection: &Selection,
+    origin: Option<Vec3<i32>>,
+) -> Result<MapDesc, Vec<PatchFailure>> {
+    let mut selected: HashSet<ObjectId> = map
+        .objects()
+        .filter(|object| match selection {
+            Selection::Region(aabb) => aabb.contains(object.position()),
+            Selection::Objects(ids) => ids.contains(&object.id()),
+        })
+        .map(ObjectDesc::id)
+        .collect();
+
+    let attached_items: Vec<_> = selected
+        .iter()
+        .flat_map(|&id| map.attached_items(id))
+        .map(ObjectDesc::id)
+        .collect();
+
+    selected.extend(attached_items);
+
+    let mut macroblock = MapDesc::default();
+    let mut used_hashes = HashSet::new();
+    let mut used_files = HashSet::new();
+
+    for object in selected.iter().filter_map(|&id| map.object(id)) {
+        if let ModelId::Custom { hash } = object.model_id() {
+            if used_hashes.insert(*hash) {
+                let dependencies = match object {
+                    ObjectDesc::Item(_) => map.custom_item(hash).map(|custom_item| {
+                        macroblock.custom_items.push(custom_item.clone());
+                        &custom_item.dependencies
+                    }),
+                    _ => map.custom_block(hash).map(|custom_block| {
+                        macroblock.custom_blocks.push(custom_block.clone());
+                        &custom_block.dependencies
+                    }),
+                };
+
+                for dependency in dependencies.into_iter().flatten() {
+                    if used_files.insert(dependency.hash) {
+                        macroblock
+                            .embedded_files
+                            .extend(map.embedded_file(&dependency.hash).cloned());
+                    }
+                }
+            }
+        }
+
+        for hash in object.custom_skins() {
+            if used_hashes.insert(hash) {
+                macroblock
+                    .custom_skins
+                    .extend(map.custom_skin(&hash).cloned());
+            }
+        }
+
+        match object.clone() {
+            ObjectDesc::Block(block) => macroblock.blocks.push(block),
+            ObjectDesc::GhostBlock(ghost_block) => macroblock.ghost_blocks.push(ghost_block),
+            ObjectDesc::FreeBlock(free_block) => macroblock.free_blocks.push(free_block),
+            ObjectDesc::Item(mut item) => {
+                if item
+                    .attachment
+                    .as_ref()
+                    .is_some_and(|attachment| !selected.contains(&attachment.object_id))
+                {
+                    item.attachment = None;
+                }
+
+                macroblock.items.push(item);
+            }
+        }
+    }
+
+    let Some(origin) = origin.or_else(|| bounds(&macroblock).map(|(min, _)| min)) else {
+        return Ok(macroblock);
+    };
+
+    let transform = Transform {
+        offset: Vec3 {
+            x: -origin.x,
+            y: -origin.y,
+            z: -origin.z,
+        },
+        quarter_turns: 0,
+        pivot: Vec3 { x: 0, y: 0, z: 0 },
+        block_sizes: BlockSizes::new(),
+    };
+
+    // Applying the macroblock moved to an empty map keeps attachments to moved blocks.
+    let mut extracted = MapState::from_desc(MapDesc::default());
+    let outcome = patch(&macroblock).apply(&mut extracted, &transform);
+
+    if !outcome.failures.is_empty() {
+        return Err(outcome.failures);
+    }
+
+    Ok(extracted.to_desc())
+}
+
+fn objects(macroblock: &MapDesc) -> impl Iterator<Item = ObjectDesc> + '_ {
+    macroblock
+        .blocks
+        .iter()
+        .cloned()
+        .map(ObjectDesc::Block)
+        .chain(
+            macroblock
+                .ghost_blocks
+                .iter()
+                .cloned()
+                .map(ObjectDesc::GhostBlock),
+        )
+        .chain(
+            macroblock
+                .free_blocks
+                .iter()
+                .cloned()
+                .map(ObjectDesc::FreeBlock),
+        )
+        .chain(macroblock.items.iter().cloned().map(ObjectDesc::Item))
+}
+
+/// Smallest and largest block coordinates covered by the objects and offzones of the given
+/// macroblock, or `None` if it is empty.
+///
+/// Objects off the grid count as covering the coordinate their position lies in.
+fn bounds(macroblock: &MapDesc) -> Option<(Vec3<i32>, Vec3<i32>)> {
+    let coords = objects(macroblock)
+        .map(|object| {
+            let position = object.position();
+
+            Vec3 {
+                x: (position.x / BLOCK_SIZE.x).floor() as i32,
+                y: (position.y / BLOCK_SIZE.y).floor() as i32 + BLOCK_COORD_Y_OFFSET as i32,
+                z: (position.z / BLOCK_SIZE.z).floor() as i32,
+            }
+        })
+        .chain(
+            macroblock
+                .offzones
+                .iter()
+                .flat_map(|offzone| [offzone.min, offzone.max])
+                .map(|coord| Vec3 {
+                    x: coord.x as i32,
+                    y: coord.y as i32,
+                    z: coord.z as i32,
+                }),
+        );
+
+    coords.fold(None, |bounds, coord| {
+        let (min, max) = bounds.unwrap_or((coord, coord));
+
+        Some((
+            Vec3 {
+                x: min.x.min(coord.x),
+                y: min.y.min(coord.y),
+                z: min.z.min(coord.z),
+            },
+            Vec3 {
+                x: max.x.max(coord.x),
+                y: max.y.max(coord.y),
+                z: max.z.max(coord.z),
+            },
+        ))
+    })
+}
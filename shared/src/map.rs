@@ -0,0 +1,499 @@
+//! Map state that edits can be applied to.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    mem,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diff, offzone, CustomBlockDesc, CustomItemDesc, DependencyDesc, Edit, EmbeddedFileDesc, Hash,
+    MapDesc, ModelId, ObjectDesc, ObjectId, OffzoneDesc,
+};
+
+/// Reason an edit does not apply to a map.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum EditRejection {
+    CustomObjectExists,
+    ObjectExists,
+    /// The custom block or item of the placed object is not in the map.
+    MissingCustomModel,
+    /// A custom skin file of the placed object is not in the map.
+    MissingCustomSkin,
+    /// An embedded file the added custom block or item depends on is not in the map.
+    MissingDependency,
+    ObjectNotFound,
+    /// The minimum of the offzone box is greater than its maximum.
+    InvalidOffzone,
+    /// The added offzone box is covered by offzones already.
+    OffzoneExists,
+    /// The removed offzone box does not overlap any offzones.
+    OffzoneNotFound,
+}
+
+impl Display for EditRejection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::CustomObjectExists => write!(f, "custom object is already in the map"),
+            Self::ObjectExists => write!(f, "object is already in the map"),
+            Self::MissingCustomModel => write!(f, "custom model of the object is not in the map"),
+            Self::MissingCustomSkin => write!(f, "custom skin of the object is not in the map"),
+            Self::MissingDependency => {
+                write!(f, "dependency of the custom object is not in the map")
+            }
+            Self::ObjectNotFound => write!(f, "object is not in the map"),
+            Self::InvalidOffzone => write!(f, "offzone box is empty"),
+            Self::OffzoneExists => write!(f, "offzone is already in the map"),
+            Self::OffzoneNotFound => write!(f, "offzone is not in the map"),
+        }
+    }
+}
+
+/// A map indexed for applying edits, such as the authoritative map of the server.
+#[derive(Clone)]
+pub struct MapState {
+    custom_blocks: HashMap<Hash, CustomBlockDesc>,
+    custom_items: HashMap<Hash, CustomItemDesc>,
+    custom_skins: HashMap<Hash, EmbeddedFileDesc>,
+    embedded_files: HashMap<Hash, EmbeddedFileDesc>,
+    objects: HashMap<ObjectId, ObjectDesc>,
+    /// Ids of the items attached to every object that has any.
+    attachments: HashMap<ObjectId, HashSet<ObjectId>>,
+    /// Canonical offzone boxes, see [`offzone`].
+    offzones: Vec<OffzoneDesc>,
+    /// Changes to undo at the end of a [`MapState::dry_run`], latest last.
+    undo_log: Option<Vec<Undo>>,
+}
+
+/// Reversal of a single change to a [`MapState`].
+#[derive(Clone)]
+enum Undo {
+    RemoveCustomBlock(Hash),
+    RemoveCustomItem(Hash),
+    RemoveCustomSkin(Hash),
+    RemoveEmbeddedFile(Hash),
+    RemoveObject(ObjectId),
+    InsertObject(ObjectDesc),
+    SetOffzones(Vec<OffzoneDesc>),
+}
+
+impl PartialEq for MapState {
+    fn eq(&self, other: &Self) -> bool {
+        // The attachments follow from the objects.
+        self.custom_blocks == other.custom_blocks
+            && self.custom_items == other.custom_items
+            && self.custom_skins == other.custom_skins
+            && self.embedded_files == other.embedded_files
+            && self.objects == other.objects
+            && self.offzones == other.offzones
+    }
+}
+
+impl Eq for MapState {}
+
+impl MapState {
+    pub fn from_desc(map_desc: MapDesc) -> Self {
+        let custom_blocks = map_desc
+            .custom_blocks
+            .into_iter()
+            .map(|custom_block| (custom_block.hash(), custom_block))
+            .collect();
+
+        let custom_items = map_desc
+            .custom_items
+            .into_iter()
+            .map(|custom_item| (custom_item.hash(), custom_item))
+            .collect();
+
+        let custom_skins = map_desc
+            .custom_skins
+            .into_iter()
+            .map(|custom_skin| (custom_skin.hash(), custom_skin))
+            .collect();
+
+        let embedded_files = map_desc
+            .embedded_files
+            .into_iter()
+            .map(|embedded_file| (embedded_file.hash(), embedded_file))
+            .collect();
+
+        let objects: Vec<_> = map_desc
+            .blocks
+            .into_iter()
+            .map(ObjectDesc::Block)
+            .chain(
+                map_desc
+                    .ghost_blocks
+                    .into_iter()
+                    .map(ObjectDesc::GhostBlock),
+            )
+            .chain(map_desc.free_blocks.into_iter().map(ObjectDesc::FreeBlock))
+            .chain(map_desc.items.into_iter().map(ObjectDesc::Item))
+            .map(|object| (object.id(), object))
+            .collect();
+
+        let mut map = Self {
+            custom_blocks,
+            custom_items,
+            custom_skins,
+            embedded_files,
+            objects: HashMap::new(),
+            attachments: HashMap::new(),
+            offzones: offzone::normalize(&map_desc.offzones),
+            undo_log: None,
+        };
+
+        for (id, object) in objects {
+            map.insert_object(id, object);
+        }
+
+        map
+    }
+
+    pub fn to_desc(&self) -> MapDesc {
+        let mut map_desc = MapDesc {
+            custom_blocks: self.custom_blocks.values().cloned().collect(),
+            custom_items: self.custom_items.values().cloned().collect(),
+            custom_skins: self.custom_skins.values().cloned().collect(),
+            embedded_files: self.embedded_files.values().cloned().collect(),
+            blocks: vec![],
+            ghost_blocks: vec![],
+            free_blocks: vec![],
+            items: vec![],
+            offzones: self.offzones.clone(),
+        };
+
+        for object in self.objects.values() {
+            match object.clone() {
+                ObjectDesc::Block(block) => map_desc.blocks.push(block),
+                ObjectDesc::GhostBlock(ghost_block) => map_desc.ghost_blocks.push(ghost_block),
+                ObjectDesc::FreeBlock(free_block) => map_desc.free_blocks.push(free_block),
+                ObjectDesc::Item(item) => map_desc.items.push(item),
+            }
+        }
+
+        map_desc
+    }
+
+    pub fn custom_block(&self, hash: &Hash) -> Option<&CustomBlockDesc> {
+        self.custom_blocks.get(hash)
+    }
+
+    pub fn custom_item(&self, hash: &Hash) -> Option<&CustomItemDesc> {
+        self.custom_items.get(hash)
+    }
+
+    pub fn custom_skin(&self, hash: &Hash) -> Option<&EmbeddedFileDesc> {
+        self.custom_skins.get(hash)
+    }
+
+    pub fn embedded_file(&self, hash: &Hash) -> Option<&EmbeddedFileDesc> {
+        self.embedded_files.get(hash)
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = &ObjectDesc> {
+        self.objects.values()
+    }
+
+    pub fn object(&self, id: ObjectId) -> Option<&ObjectDesc> {
+        self.objects.get(&id)
+    }
+
+    /// Items attached to the object with the given id.
+    pub fn attached_items(&self, id: ObjectId) -> impl Iterator<Item = &ObjectDesc> {
+        self.attachments
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter_map(|item_id| self.objects.get(item_id))
+    }
+
+    pub fn offzones(&self) -> &[OffzoneDesc] {
+        &self.offzones
+    }
+
+    /// Check whether the given edit would change this map.
+    pub fn can_apply(&self, edit: &Edit) -> bool {
+        self.rejection(edit).is_none()
+    }
+
+    /// Reason the given edit would not change this map, if any.
+    pub fn rejection(&self, edit: &Edit) -> Option<EditRejection> {
+        match edit {
+            Edit::AddCustomBlock(custom_block)
+                if self.custom_blocks.contains_key(&custom_block.hash()) =>
+            {
+                Some(EditRejection::CustomObjectExists)
+            }
+            Edit::AddCustomBlock(custom_block) => (!self
+                .has_dependencies(&custom_block.dependencies))
+            .then_some(EditRejection::MissingDependency),
+            Edit::AddCustomItem(custom_item)
+                if self.custom_items.contains_key(&custom_item.hash()) =>
+            {
+                Some(EditRejection::CustomObjectExists)
+            }
+            Edit::AddCustomItem(custom_item) => (!self.has_dependencies(&custom_item.dependencies))
+                .then_some(EditRejection::MissingDependency),
+            Edit::AddCustomSkin(custom_skin) => self
+                .custom_skins
+                .contains_key(&custom_skin.hash())
+                .then_some(EditRejection::CustomObjectExists),
+            Edit::AddEmbeddedFile(embedded_file) => self
+                .embedded_files
+                .contains_key(&embedded_file.hash())
+                .then_some(EditRejection::CustomObjectExists),
+            Edit::Place(object) => self.placement_rejection(object),
+            Edit::Remove(object) => {
+                (!self.objects.contains_key(&object.id())).then_some(EditRejection::ObjectNotFound)
+            }
+            Edit::Modify { old, .. } if !self.objects.contains_key(&old.id()) => {
+                Some(EditRejection::ObjectNotFound)
+            }
+            Edit::Modify { new, .. } => self.placement_rejection(new),
+            Edit::AddOffzone(offzone) | Edit::RemoveOffzone(offzone)
+                if offzone.min.x > offzone.max.x
+                    || offzone.min.y > offzone.max.y
+                    || offzone.min.z > offzone.max.z =>
+            {
+                Some(EditRejection::InvalidOffzone)
+            }
+            // Only the boxes around the edited one are looked at, which keeps this cheap compared
+            // to applying the edit.
+            Edit::AddOffzone(offzone) => {
+                offzone::covers(&self.offzones, offzone).then_some(EditRejection::OffzoneExists)
+            }
+            Edit::RemoveOffzone(offzone) => (!offzone::overlaps_any(&self.offzones, offzone))
+                .then_some(EditRejection::OffzoneNotFound),
+        }
+    }
+
+    /// Apply the given edit, returning whether it changed this map.
+    pub fn apply(&mut self, edit: Edit) -> bool {
+        if !self.can_apply(&edit) {
+            return false;
+        }
+
+        match edit {
+            Edit::AddCustomBlock(custom_block) => {
+                let hash = custom_block.hash();
+
+                self.custom_blocks.insert(hash, custom_block);
+                self.record(Undo::RemoveCustomBlock(hash));
+            }
+            Edit::AddCustomItem(custom_item) => {
+                let hash = custom_item.hash();
+
+                self.custom_items.insert(hash, custom_item);
+                self.record(Undo::RemoveCustomItem(hash));
+            }
+            Edit::AddCustomSkin(custom_skin) => {
+                let hash = custom_skin.hash();
+
+                self.custom_skins.insert(hash, custom_skin);
+                self.record(Undo::RemoveCustomSkin(hash));
+            }
+            Edit::AddEmbeddedFile(embedded_file) => {
+                let hash = embedded_file.hash();
+
+                self.embedded_files.insert(hash, embedded_file);
+                self.record(Undo::RemoveEmbeddedFile(hash));
+            }
+            Edit::Place(object) => {
+                self.insert_object(object.id(), object);
+            }
+            Edit::Remove(object) => {
+                self.remove_object(object.id());
+            }
+            Edit::Modify { old, new } => {
+                let new_id = new.id();
+                let items: Vec<_> = self.attached_items(old.id()).cloned().collect();
+
+                for item in &items {
+                    self.remove_object(item.id());
+                }
+
+                self.remove_object(old.id());
+                self.insert_object(new_id, *new);
+
+                for mut item in items {
+                    item.reattach(new_id);
+                    self.insert_object(item.id(), item);
+                }
+            }
+            Edit::AddOffzone(offzone) => {
+                let offzones = offzone::union(&self.offzones, &[offzone]);
+
+                self.set_offzones(offzones);
+            }
+            Edit::RemoveOffzone(offzone) => {
+                let offzones = offzone::difference(&self.offzones, &[offzone]);
+
+                self.set_offzones(offzones);
+            }
+        }
+
+        true
+    }
+
+    /// Run `f` on this map and undo every edit it applied afterwards.
+    ///
+    /// This checks whether a sequence of edits applies without copying the map, which may be large
+    /// because of its custom objects.
+    pub fn dry_run<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let outer_undo_log = self.undo_log.replace(vec![]);
+
+        let result = f(self);
+
+        // Undoing is not recorded, as the changes of `f` are not part of any outer dry run.
+        let undo_log = self.undo_log.take().unwrap_or_default();
+
+        for undo in undo_log.into_iter().rev() {
+            match undo {
+                Undo::RemoveCustomBlock(hash) => {
+                    self.custom_blocks.remove(&hash);
+                }
+                Undo::RemoveCustomItem(hash) => {
+                    self.custom_items.remove(&hash);
+                }
+                Undo::RemoveCustomSkin(hash) => {
+                    self.custom_skins.remove(&hash);
+                }
+                Undo::RemoveEmbeddedFile(hash) => {
+                    self.embedded_files.remove(&hash);
+                }
+                Undo::RemoveObject(id) => self.remove_object(id),
+                Undo::InsertObject(object) => self.insert_object(object.id(), object),
+                Undo::SetOffzones(offzones) => self.set_offzones(offzones),
+            }
+        }
+
+        self.undo_log = outer_undo_log;
+
+        result
+    }
+
+    /// Edits that turn this map into the given map.
+    ///
+    /// Custom objects, skins and embedded files are never removed, only added when the given map
+    /// uses ones this map lacks. Objects are matched up the same way as by [`diff::MapDiff`], so
+    /// that changed objects keep their attached items.
+    pub fn edits_to(&self, target: &MapState) -> Vec<Edit> {
+        let mut edits = vec![];
+
+        for (hash, embedded_file) in &target.embedded_files {
+            if !self.embedded_files.contains_key(hash) {
+                edits.push(Edit::AddEmbeddedFile(embedded_file.clone()));
+            }
+        }
+
+        for (hash, custom_block) in &target.custom_blocks {
+            if !self.custom_blocks.contains_key(hash) {
+                edits.push(Edit::AddCustomBlock(custom_block.clone()));
+            }
+        }
+
+        for (hash, custom_item) in &target.custom_items {
+            if !self.custom_items.contains_key(hash) {
+                edits.push(Edit::AddCustomItem(custom_item.clone()));
+            }
+        }
+
+        for (hash, custom_skin) in &target.custom_skins {
+            if !self.custom_skins.contains_key(hash) {
+                edits.push(Edit::AddCustomSkin(custom_skin.clone()));
+            }
+        }
+
+        let (removed, added, modified) = diff::object_diff(&self.objects, &target.objects);
+
+        edits.extend(diff::object_edits(&removed, &added, &modified));
+
+        edits.extend(
+            offzone::difference(&self.offzones, &target.offzones)
+                .into_iter()
+                .map(Edit::RemoveOffzone),
+        );
+        edits.extend(
+            offzone::difference(&target.offzones, &self.offzones)
+                .into_iter()
+                .map(Edit::AddOffzone),
+        );
+
+        edits
+    }
+
+    fn insert_object(&mut self, id: ObjectId, object: ObjectDesc) {
+        if let Some(attached_to) = object.attached_to() {
+            self.attachments.entry(attached_to).or_default().insert(id);
+        }
+
+        self.objects.insert(id, object);
+        self.record(Undo::RemoveObject(id));
+    }
+
+    fn remove_object(&mut self, id: ObjectId) {
+        let Some(object) = self.objects.remove(&id) else {
+            return;
+        };
+
+        self.record(Undo::InsertObject(object.clone()));
+
+        if let Some(attached_to) = object.attached_to() {
+            if let Some(items) = self.attachments.get_mut(&attached_to) {
+                items.remove(&id);
+
+                if items.is_empty() {
+                    self.attachments.remove(&attached_to);
+                }
+            }
+        }
+    }
+
+    fn set_offzones(&mut self, offzones: Vec<OffzoneDesc>) {
+        let offzones = mem::replace(&mut self.offzones, offzones);
+
+        self.record(Undo::SetOffzones(offzones));
+    }
+
+    /// Remember how to undo a change if this map is part of a dry run.
+    fn record(&mut self, undo: Undo) {
+        if let Some(undo_log) = &mut self.undo_log {
+            undo_log.push(undo);
+        }
+    }
+
+    /// Reason the given object cannot be placed, if any.
+    fn placement_rejection(&self, object: &ObjectDesc) -> Option<EditRejection> {
+        if self.objects.contains_key(&object.id()) {
+            Some(EditRejection::ObjectExists)
+        } else if !self.has_model(object) {
+            Some(EditRejection::MissingCustomModel)
+        } else if !object
+            .custom_skins()
+            .all(|hash| self.custom_skins.contains_key(&hash))
+        {
+            Some(EditRejection::MissingCustomSkin)
+        } else {
+            None
+        }
+    }
+
+    fn has_model(&self, object: &ObjectDesc) -> bool {
+        match object.model_id() {
+            ModelId::Game { .. } => true,
+            ModelId::Custom { hash } => match object {
+                ObjectDesc::Item(_) => self.custom_items.contains_key(hash),
+                _ => self.custom_blocks.contains_key(hash),
+            },
+        }
+    }
+
+    fn has_dependencies(&self, dependencies: &[DependencyDesc]) -> bool {
+        dependencies
+            .iter()
+            .all(|dependency| self.embedded_files.contains_key(&dependency.hash))
+    }
+}
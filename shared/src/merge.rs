@@ -0,0 +1,563 @@
+//! Three-way merge of maps.
+
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+};
+
+use serde::Serialize;
+
+use crate::{diff::MapDiff, map::MapState, offzone, Hash, MapDesc, ObjectDesc, ObjectId};
+
+/// Result of merging two maps that were both changed from the same base map.
+pub struct Merge {
+    /// The merged map, in which conflicts are resolved in favour of our side.
+    pub map_desc: MapDesc,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/// Changes of both sides that could not be combined.
+#[derive(Clone, Serialize)]
+pub struct MergeConflict {
+    pub kind: ConflictKind,
+    /// The object as it was in the base map, if it was in there.
+    pub base: Option<ObjectDesc>,
+    /// The object as changed by our side, or `None` if our side removed it.
+    pub ours: Option<ObjectDesc>,
+    /// The object as changed by their side, or `None` if their side removed it.
+    pub theirs: Option<ObjectDesc>,
+    /// Items attached to the version of the object that was dropped, which were dropped with it.
+    pub removed_items: Vec<ObjectDesc>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum ConflictKind {
+    /// Both sides changed the same object, but not in the same way.
+    ModifiedDifferently,
+    /// One side changed an object the other side removed.
+    ModifiedAndRemoved,
+    /// Both sides put a different block at the same grid coordinate.
+    SameCoord,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Ours,
+    Theirs,
+}
+
+/// Changes of one side to the objects of the base map.
+struct SideChanges {
+    /// New version of every changed base object, or `None` if it was removed.
+    changed: HashMap<ObjectId, Option<ObjectDesc>>,
+    added: Vec<ObjectDesc>,
+}
+
+impl SideChanges {
+    fn new(diff: MapDiff) -> Self {
+        let mut changed = HashMap::new();
+
+        for object in diff.removed {
+            changed.insert(object.id(), None);
+        }
+
+        for modification in diff.modified {
+            changed.insert(modification.old.id(), Some(modification.new));
+        }
+
+        Self {
+            changed,
+            added: diff.added,
+        }
+    }
+}
+
+/// Merge the changes `ours` and `theirs` made to `base`.
+///
+/// Custom objects, skins and embedded files are unified by hash, keeping every one either side
+/// still has.
+pub fn merge(base: &MapDesc, ours: &MapDesc, theirs: &MapDesc) -> Merge {
+    let our_changes = SideChanges::new(MapDiff::new(base, ours));
+    let their_changes = SideChanges::new(MapDiff::new(base, theirs));
+
+    let base_map = MapState::from_desc(base.clone());
+
+    let mut objects: HashMap<ObjectId, ObjectDesc> = base_map
+        .objects()
+        .map(|object| (object.id(), object.clone()))
+        .collect();
+
+    // Objects introduced by either side, to detect both sides using the same grid coordinate.
+    let mut origins = HashMap::new();
+    let mut conflicts = vec![];
+
+    // Merged version of every changed object by the ids of its other versions, to attach items to.
+    let mut merged_ids = HashMap::new();
+
+    let changed_ids: HashSet<_> = our_changes
+        .changed
+        .keys()
+        .chain(their_changes.changed.keys())
+        .copied()
+        .collect();
+
+    let mut changed_ids: Vec<_> = changed_ids.into_iter().collect();
+    changed_ids.sort_by_key(|id| *id.as_bytes());
+
+    for id in changed_ids {
+        let ours = our_changes.changed.get(&id);
+        let theirs = their_changes.changed.get(&id);
+
+        let (outcome, side) = match (ours, theirs) {
+            (Some(ours), None) => (ours, Side::Ours),
+            (None, Some(theirs)) => (theirs, Side::Theirs),
+            (Some(ours), Some(theirs)) => {
+                let is_same =
+                    ours.as_ref().map(ObjectDesc::id) == theirs.as_ref().map(ObjectDesc::id);
+
+                if !is_same {
+                    let kind = if ours.is_some() && theirs.is_some() {
+                        ConflictKind::ModifiedDifferently
+                    } else {
+                        ConflictKind::ModifiedAndRemoved
+                    };
+
+                    conflicts.push(MergeConflict {
+                        kind,
+                        base: base_map.object(id).cloned(),
+                        ours: ours.clone(),
+                        theirs: theirs.clone(),
+                        removed_items: vec![],
+                    });
+                }
+
+                (ours, Side::Ours)
+            }
+            (None, None) => unreachable!("id is changed by at least one side"),
+        };
+
+        objects.remove(&id);
+
+        if let Some(object) = outcome {
+            let merged_id = object.id();
+
+            let versions = [ours, theirs]
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(ObjectDesc::id);
+
+            for version in iter::once(id).chain(versions) {
+                if version != merged_id {
+                    merged_ids.insert(version, merged_id);
+                }
+            }
+
+            origins.insert(merged_id, side);
+            objects.insert(merged_id, object.clone());
+        }
+    }
+
+    for (side, added) in [
+        (Side::Ours, &our_changes.added),
+        (Side::Theirs, &their_changes.added),
+    ] {
+        for object in added {
+            // Objects added identically by both sides are merged into one.
+            origins.entry(object.id()).or_insert(side);
+            objects.insert(object.id(), object.clone());
+        }
+    }
+
+    reattach_to_merged(&mut objects, &mut origins, &merged_ids);
+
+    conflicts.extend(resolve_same_coords(&mut objects, &origins, &base_map));
+
+    remove_orphaned_items(&mut objects, &origins, &mut conflicts, &base_map);
+
+    let mut map_desc = MapDesc {
+        custom_blocks: unify(&ours.custom_blocks, &theirs.custom_blocks, |c| c.hash()),
+        custom_items: unify(&ours.custom_items, &theirs.custom_items, |c| c.hash()),
+        custom_skins: unify(&ours.custom_skins, &theirs.custom_skins, |c| c.hash()),
+        embedded_files: unify(&ours.embedded_files, &theirs.embedded_files, |f| f.hash()),
+        blocks: vec![],
+        ghost_blocks: vec![],
+        free_blocks: vec![],
+        items: vec![],
+        // A coordinate stays offzone if neither side removed it, and becomes offzone if either
+        // side added it, so offzones never conflict.
+        offzones: offzone::combine(
+            &[&base.offzones, &ours.offzones, &theirs.offzones],
+            |covered| match covered {
+                [true, ours, theirs] => *ours && *theirs,
+                [false, ours, theirs] => *ours || *theirs,
+                _ => unreachable!("three sets of offzones are combined"),
+            },
+        ),
+    };
+
+    for object in objects.into_values() {
+        match object {
+            ObjectDesc::Block(block) => map_desc.blocks.push(block),
+            ObjectDesc::GhostBlock(ghost_block) => map_desc.ghost_blocks.push(ghost_block),
+            ObjectDesc::FreeBlock(free_block) => map_desc.free_blocks.push(free_block),
+            ObjectDesc::Item(item) => map_desc.items.push(item),
+        }
+    }
+
+    Merge {
+        map_desc,
+        conflicts,
+    }
+}
+
+/// Attach items to the merged version of the object they are attached to, as either side may have
+/// attached them to a version of it that was not merged, such as items one side put on a block
+/// that the other side recoloured.
+fn reattach_to_merged(
+    objects: &mut HashMap<ObjectId, ObjectDesc>,
+    origins: &mut HashMap<ObjectId, Side>,
+    merged_ids: &HashMap<ObjectId, ObjectId>,
+) {
+    let reattached: Vec<_> = objects
+        .iter()
+        .filter_map(|(id, object)| {
+            let merged_id = merged_ids.get(&object.attached_to()?)?;
+
+            Some((*id, *merged_id))
+        })
+        .collect();
+
+    for (id, merged_id) in reattached {
+        let mut item = objects
+            .remove(&id)
+            .expect("reattached items are in the map");
+        item.reattach(merged_id);
+
+        if let Some(side) = origins.remove(&id) {
+            origins.insert(item.id(), side);
+        }
+
+        objects.insert(item.id(), item);
+    }
+}
+
+/// Remove the blocks their side put at a grid coordinate where our side put a different block.
+fn resolve_same_coords(
+    objects: &mut HashMap<ObjectId, ObjectDesc>,
+    origins: &HashMap<ObjectId, Side>,
+    base_map: &MapState,
+) -> Vec<MergeConflict> {
+    let blocks_from = |side| {
+        objects
+            .iter()
+            .filter(move |(id, _)| origins.get(id) == Some(&side))
+            .filter_map(|(id, object)| match object {
+                ObjectDesc::Block(block) => Some((block.coord, (*id, object.clone()))),
+                _ => None,
+            })
+    };
+
+    let our_blocks: HashMap<_, _> = blocks_from(Side::Ours).collect();
+
+    let mut conflicting: Vec<_> = blocks_from(Side::Theirs)
+        .filter_map(|(coord, (id, theirs))| {
+            let (_, ours) = our_blocks.get(&coord)?;
+
+            Some((coord, id, ours.clone(), theirs))
+        })
+        .collect();
+
+    conflicting.sort_by_key(|(_, id, _, _)| *id.as_bytes());
+
+    conflicting
+        .into_iter()
+        .map(|(coord, id, ours, theirs)| {
+            objects.remove(&id);
+
+            let base = base_map
+                .objects()
+                .find(|object| matches!(object, ObjectDesc::Block(block) if block.coord == coord))
+                .cloned();
+
+            MergeConflict {
+                kind: ConflictKind::SameCoord,
+                base,
+                ours: Some(ours),
+                theirs: Some(theirs),
+                removed_items: vec![],
+            }
+        })
+        .collect()
+}
+
+/// Remove the items attached to objects that are not in the merged map, adding each to the
+/// conflict about the object.
+///
+/// Items one side put on an object the other side removed without a conflict, as the first side
+/// did not change the object itself, make up a new conflict.
+fn remove_orphaned_items(
+    objects: &mut HashMap<ObjectId, ObjectDesc>,
+    origins: &HashMap<ObjectId, Side>,
+    conflicts: &mut Vec<MergeConflict>,
+    base_map: &MapState,
+) {
+    // Items can be attached to other items, which are only orphaned once those are removed.
+    loop {
+        let mut orphaned: Vec<_> = objects
+            .values()
+            .filter(|object| {
+                object
+                    .attached_to()
+                    .is_some_and(|id| !objects.contains_key(&id))
+            })
+            .cloned()
+            .collect();
+
+        if orphaned.is_empty() {
+            break;
+        }
+
+        orphaned.sort_by_key(|item| *item.id().as_bytes());
+
+        for item in orphaned {
+            objects.remove(&item.id());
+
+            let object_id = item.attached_to().expect("orphaned items are attached");
+
+            let conflict = conflicts.iter_mut().find(|conflict| {
+                [&conflict.base, &conflict.ours, &conflict.theirs]
+                    .into_iter()
+                    .flatten()
+                    .chain(&conflict.removed_items)
+                    .any(|object| object.id() == object_id)
+            });
+
+            match conflict {
+                Some(conflict) => conflict.removed_items.push(item),
+                None => {
+                    let base = base_map.object(object_id).cloned();
+
+                    let (ours, theirs) = match origins.get(&item.id()) {
+                        Some(Side::Ours) => (base.clone(), None),
+                        _ => (None, base.clone()),
+                    };
+
+                    conflicts.push(MergeConflict {
+                        kind: ConflictKind::ModifiedAndRemoved,
+                        base,
+                        ours,
+                        theirs,
+                        removed_items: vec![item],
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Custom objects of both sides, each once.
+fn unify<T: Clone>(ours: &[T], theirs: &[T], hash: fn(&T) -> Hash) -> Vec<T> {
+    let mut hashes: HashSet<Hash> = HashSet::new();
+
+    ours.iter()
+        .chain(theirs)
+        .filter(|custom_object| hashes.insert(hash(custom_object)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{block, item_on, map, offzone, recoloured};
+
+    use super::*;
+
+    #[test]
+    fn changes_of_both_sides_are_combined() {
+        let mut base = map([block(1, 1), block(5, 5)]);
+        let mut ours = map([block(1, 1), recoloured(block(5, 5)), block(9, 9)]);
+        let mut theirs = map([block(1, 2), item_on(&block(1, 2)), block(5, 5)]);
+
+        base.offzones = vec![offzone((0, 0, 0), (3, 0, 0))];
+        ours.offzones = vec![offzone((0, 0, 0), (1, 0, 0))];
+        theirs.offzones = vec![offzone((0, 0, 0), (3, 0, 0)), offzone((8, 0, 0), (8, 0, 0))];
+
+        let merge = merge(&base, &ours, &theirs);
+
+        assert!(merge.conflicts.is_empty());
+
+        let mut expected = map([
+            block(1, 2),
+            item_on(&block(1, 2)),
+            recoloured(block(5, 5)),
+            block(9, 9),
+        ]);
+
+        expected.offzones = vec![offzone((0, 0, 0), (1, 0, 0)), offzone((8, 0, 0), (8, 0, 0))];
+
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(expected));
+    }
+
+    #[test]
+    fn items_on_a_block_the_other_side_modified_are_attached_to_it() {
+        let base = map([block(1, 1), block(5, 5)]);
+        let ours = map([recoloured(block(1, 1)), block(5, 5)]);
+        let theirs = map([block(1, 1), item_on(&block(1, 1)), block(5, 5)]);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        let expected = map([
+            recoloured(block(1, 1)),
+            item_on(&recoloured(block(1, 1))),
+            block(5, 5),
+        ]);
+
+        assert!(merge.conflicts.is_empty());
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(expected));
+    }
+
+    #[test]
+    fn merging_with_an_unchanged_side_takes_the_other_side() {
+        let base = map([block(1, 1), block(5, 5)]);
+        let theirs = map([block(1, 1), block(6, 5), item_on(&block(6, 5))]);
+
+        let merge = merge(&base, &base, &theirs);
+
+        assert!(merge.conflicts.is_empty());
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(theirs));
+    }
+
+    fn conflicts(merge: &Merge) -> Vec<ConflictKind> {
+        merge
+            .conflicts
+            .iter()
+            .map(|conflict| conflict.kind)
+            .collect()
+    }
+
+    #[test]
+    fn identical_changes_do_not_conflict() {
+        let base = map([block(1, 1)]);
+        let changed = map([recoloured(block(1, 1)), block(2, 2)]);
+
+        let merge = merge(&base, &changed, &changed);
+
+        assert!(merge.conflicts.is_empty());
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(changed));
+    }
+
+    #[test]
+    fn objects_changed_differently_keep_our_change() {
+        let base = map([block(1, 1)]);
+        let ours = map([block(1, 2)]);
+        let theirs = map([recoloured(block(1, 1))]);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        assert_eq!(conflicts(&merge), [ConflictKind::ModifiedDifferently]);
+
+        let conflict = &merge.conflicts[0];
+
+        assert!(conflict.base == Some(block(1, 1)));
+        assert!(conflict.ours == Some(block(1, 2)));
+        assert!(conflict.theirs == Some(recoloured(block(1, 1))));
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(ours));
+    }
+
+    #[test]
+    fn objects_changed_and_removed_keep_our_side() {
+        let base = map([block(1, 1), block(5, 5)]);
+        let ours = map([recoloured(block(1, 1))]);
+        let theirs = map([block(5, 5)]);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        // Our removal of the other block is not a conflict, as their side did not change it.
+        assert_eq!(conflicts(&merge), [ConflictKind::ModifiedAndRemoved]);
+        assert!(merge.conflicts[0].theirs.is_none());
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(ours));
+    }
+
+    #[test]
+    fn different_blocks_at_the_same_coord_keep_ours() {
+        let base = map([block(1, 1)]);
+        let ours = map([block(1, 1), block(3, 3)]);
+        let theirs = map([block(1, 1), recoloured(block(3, 3)), block(7, 7)]);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        assert_eq!(conflicts(&merge), [ConflictKind::SameCoord]);
+
+        let conflict = &merge.conflicts[0];
+
+        assert!(conflict.base.is_none());
+        assert!(conflict.ours == Some(block(3, 3)));
+        assert!(conflict.theirs == Some(recoloured(block(3, 3))));
+
+        let expected = map([block(1, 1), block(3, 3), block(7, 7)]);
+
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(expected));
+    }
+
+    #[test]
+    fn items_on_their_block_at_the_same_coord_are_removed_with_it() {
+        let base = map([block(1, 1)]);
+        let ours = map([block(1, 1), block(3, 3)]);
+        let theirs = map([
+            block(1, 1),
+            recoloured(block(3, 3)),
+            item_on(&recoloured(block(3, 3))),
+        ]);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        assert_eq!(conflicts(&merge), [ConflictKind::SameCoord]);
+        assert!(merge.conflicts[0].removed_items == [item_on(&recoloured(block(3, 3)))]);
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(ours));
+    }
+
+    #[test]
+    fn items_on_blocks_our_side_removed_are_removed_with_them() {
+        let base = map([block(1, 1), block(5, 5)]);
+        let ours = MapDesc::default();
+        let theirs = map([
+            recoloured(block(1, 1)),
+            item_on(&recoloured(block(1, 1))),
+            block(5, 5),
+            item_on(&block(5, 5)),
+        ]);
+
+        let merge = merge(&base, &ours, &theirs);
+
+        assert_eq!(
+            conflicts(&merge),
+            [
+                ConflictKind::ModifiedAndRemoved,
+                ConflictKind::ModifiedAndRemoved
+            ]
+        );
+
+        // Their side recoloured one block.
+        let recoloured_block = merge
+            .conflicts
+            .iter()
+            .find(|conflict| conflict.theirs == Some(recoloured(block(1, 1))))
+            .unwrap();
+
+        assert!(recoloured_block.removed_items == [item_on(&recoloured(block(1, 1)))]);
+
+        // Their side only put an item on the other block.
+        let untouched_block = merge
+            .conflicts
+            .iter()
+            .find(|conflict| conflict.base == Some(block(5, 5)))
+            .unwrap();
+
+        assert!(untouched_block.ours.is_none());
+        assert!(untouched_block.theirs == Some(block(5, 5)));
+        assert!(untouched_block.removed_items == [item_on(&block(5, 5))]);
+
+        assert!(MapState::from_desc(merge.map_desc) == MapState::from_desc(ours));
+    }
+}
@@ -0,0 +1,241 @@
+//! Offzones, which are kept as a canonical set of disjoint boxes.
+//!
+//! Users draw offzones as arbitrary, possibly overlapping boxes, and remove parts of them the same
+//! way. What matters is only which block coordinates end up covered, so after every change the
+//! covered coordinates are split into boxes again in a way that depends on nothing else. This
+//! makes maps with the same offzones equal, however the boxes were drawn.
+
+use std::ops::Range;
+
+use gamebox::Vec3;
+
+use crate::OffzoneDesc;
+
+/// Boxes covering the coordinates covered by either `a` or `b`.
+pub fn union(a: &[OffzoneDesc], b: &[OffzoneDesc]) -> Vec<OffzoneDesc> {
+    combine(&[a, b], |covered| covered[0] || covered[1])
+}
+
+/// Boxes covering the coordinates covered by `a`, but not by `b`.
+pub fn difference(a: &[OffzoneDesc], b: &[OffzoneDesc]) -> Vec<OffzoneDesc> {
+    combine(&[a, b], |covered| covered[0] && !covered[1])
+}
+
+/// Canonical boxes covering exactly the coordinates covered by the given boxes.
+pub fn normalize(offzones: &[OffzoneDesc]) -> Vec<OffzoneDesc> {
+    combine(&[offzones], |covered| covered[0])
+}
+
+/// Whether the given boxes cover every coordinate of the given box.
+pub fn covers(offzones: &[OffzoneDesc], offzone: &OffzoneDesc) -> bool {
+    let overlapping: Vec<_> = offzones
+        .iter()
+        .filter(|other| overlaps(other, offzone))
+        .copied()
+        .collect();
+
+    difference(&[*offzone], &overlapping).is_empty()
+}
+
+/// Whether any of the given boxes covers a coordinate of the given box.
+pub fn overlaps_any(offzones: &[OffzoneDesc], offzone: &OffzoneDesc) -> bool {
+    offzones.iter().any(|other| overlaps(other, offzone))
+}
+
+/// Canonical boxes covering the coordinates for which `is_covered` holds, given which of the sets
+/// of boxes cover them.
+///
+/// Coordinates are grouped into cells by the bounds of all boxes, within which coverage cannot
+/// change. Cells are then merged greedily, in the order x, z, y, starting at the lowest cell that
+/// is covered, but not part of a box yet. As this merges cells the same way it would merge single
+/// coordinates, the result only depends on the covered coordinates, not on the boxes they came
+/// from.
+///
+/// Every box is drawn into the cells it covers, so for sets of disjoint boxes, such as canonical
+/// ones, this takes time proportional to the number of cells.
+pub fn combine(sets: &[&[OffzoneDesc]], is_covered: impl Fn(&[bool]) -> bool) -> Vec<OffzoneDesc> {
+    let boxes = || sets.iter().flat_map(|set| set.iter());
+
+    let xs = bounds(boxes().map(|offzone| (offzone.min.x, offzone.max.x)));
+    let ys = bounds(boxes().map(|offzone| (offzone.min.y, offzone.max.y)));
+    let zs = bounds(boxes().map(|offzone| (offzone.min.z, offzone.max.z)));
+
+    let (nx, ny, nz) = (
+        xs.len().saturating_sub(1),
+        ys.len().saturating_sub(1),
+        zs.len().saturating_sub(1),
+    );
+
+    let index = |x: usize, y: usize, z: usize| (y * nz + z) * nx + x;
+
+    // Which of the sets cover every cell, one run of flags per cell.
+    let mut in_sets = vec![false; nx * ny * nz * sets.len()];
+
+    for (set_index, set) in sets.iter().enumerate() {
+        for offzone in set.iter().filter(|offzone| is_valid(offzone)) {
+            let x_cells = cells(&xs, offzone.min.x, offzone.max.x);
+            let y_cells = cells(&ys, offzone.min.y, offzone.max.y);
+            let z_cells = cells(&zs, offzone.min.z, offzone.max.z);
+
+            for y in y_cells {
+                for z in z_cells.clone() {
+                    for x in x_cells.clone() {
+                        in_sets[index(x, y, z) * sets.len() + set_index] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut covered: Vec<bool> = in_sets.chunks(sets.len().max(1)).map(&is_covered).collect();
+
+    let mut offzones = vec![];
+
+    for y0 in 0..ny {
+        for z0 in 0..nz {
+            for x0 in 0..nx {
+                if !covered[index(x0, y0, z0)] {
+                    continue;
+                }
+
+                let mut x1 = x0 + 1;
+
+                while x1 < nx && covered[index(x1, y0, z0)] {
+                    x1 += 1;
+                }
+
+                let mut z1 = z0 + 1;
+
+                while z1 < nz && (x0..x1).all(|x| covered[index(x, y0, z1)]) {
+                    z1 += 1;
+                }
+
+                let mut y1 = y0 + 1;
+
+                while y1 < ny && (z0..z1).all(|z| (x0..x1).all(|x| covered[index(x, y1, z)])) {
+                    y1 += 1;
+                }
+
+                for y in y0..y1 {
+                    for z in z0..z1 {
+                        for x in x0..x1 {
+                            covered[index(x, y, z)] = false;
+                        }
+                    }
+                }
+
+                offzones.push(OffzoneDesc {
+                    min: Vec3 {
+                        x: xs[x0] as u8,
+                        y: ys[y0] as u8,
+                        z: zs[z0] as u8,
+                    },
+                    max: Vec3 {
+                        x: (xs[x1] - 1) as u8,
+                        y: (ys[y1] - 1) as u8,
+                        z: (zs[z1] - 1) as u8,
+                    },
+                });
+            }
+        }
+    }
+
+    offzones
+}
+
+/// Sorted start coordinates of all cells along an axis, followed by the end of the last cell.
+fn bounds(ranges: impl Iterator<Item = (u8, u8)>) -> Vec<u16> {
+    let mut bounds: Vec<u16> = ranges
+        .filter(|(min, max)| min <= max)
+        .flat_map(|(min, max)| [min as u16, max as u16 + 1])
+        .collect();
+
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    bounds
+}
+
+/// Indices of the cells along an axis with the given bounds that lie within the given range.
+fn cells(bounds: &[u16], min: u8, max: u8) -> Range<usize> {
+    let start = bounds.partition_point(|&bound| bound < min as u16);
+    let end = bounds.partition_point(|&bound| bound <= max as u16);
+
+    start..end
+}
+
+fn overlaps(a: &OffzoneDesc, b: &OffzoneDesc) -> bool {
+    a.min.x <= b.max.x
+        && b.min.x <= a.max.x
+        && a.min.y <= b.max.y
+        && b.min.y <= a.max.y
+        && a.min.z <= b.max.z
+        && b.min.z <= a.max.z
+}
+
+fn is_valid(offzone: &OffzoneDesc) -> bool {
+    offzone.min.x <= offzone.max.x
+        && offzone.min.y <= offzone.max.y
+        && offzone.min.z <= offzone.max.z
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::offzone;
+
+    use super::*;
+
+    #[test]
+    fn boxes_drawn_differently_are_the_same_offzones() {
+        // An L shape, drawn as two boxes either way around and as overlapping columns.
+        let drawings = [
+            vec![offzone((0, 0, 0), (3, 1, 0)), offzone((0, 0, 1), (0, 1, 3))],
+            vec![offzone((0, 0, 0), (0, 1, 3)), offzone((1, 0, 0), (3, 1, 0))],
+            vec![
+                offzone((2, 1, 0), (3, 1, 0)),
+                offzone((0, 0, 0), (2, 1, 0)),
+                offzone((0, 0, 2), (0, 1, 3)),
+                offzone((0, 0, 0), (0, 1, 2)),
+                offzone((3, 0, 0), (3, 0, 0)),
+            ],
+        ];
+
+        let canonical = normalize(&drawings[0]);
+
+        for drawing in &drawings {
+            assert_eq!(normalize(drawing), canonical);
+
+            // Adding the boxes one by one in reverse gives the same boxes as well.
+            let added = drawing
+                .iter()
+                .rev()
+                .fold(vec![], |offzones, offzone| union(&offzones, &[*offzone]));
+
+            assert_eq!(added, canonical);
+        }
+    }
+
+    #[test]
+    fn removing_a_part_splits_the_boxes_canonically() {
+        let offzones = normalize(&[offzone((0, 0, 0), (3, 0, 3))]);
+        let hole = [offzone((1, 0, 1), (2, 0, 2))];
+
+        let expected = normalize(&[
+            offzone((0, 0, 0), (0, 0, 3)),
+            offzone((3, 0, 0), (3, 0, 3)),
+            offzone((1, 0, 0), (2, 0, 0)),
+            offzone((1, 0, 3), (2, 0, 3)),
+        ]);
+
+        assert_eq!(difference(&offzones, &hole), expected);
+        assert_eq!(
+            difference(&offzones, &hole),
+            [
+                offzone((0, 0, 0), (3, 0, 0)),
+                offzone((0, 0, 1), (0, 0, 3)),
+                offzone((3, 0, 1), (3, 0, 3)),
+                offzone((1, 0, 3), (2, 0, 3)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,537 @@
+//! Portable sets of edits that can be applied to other maps.
+
+use std::{
+    collections::HashMap,
+    f32::consts::FRAC_PI_2,
+    fmt::{self, Display, Formatter},
+    ops::Neg,
+};
+
+use gamebox::{engines::game::map::Direction, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diff::MapDiff,
+    map::{EditRejection, MapState},
+    Edit, ModelId, NotNan, ObjectDesc, ObjectId, OffzoneDesc, BLOCK_SIZE,
+};
+
+/// A recorded set of edits.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Patch {
+    pub edits: Vec<Edit>,
+}
+
+impl Patch {
+    /// Patch that turns the old map of the given diff into the new map.
+    pub fn from_diff(diff: &MapDiff) -> Self {
+        Self {
+            edits: diff.edits(),
+        }
+    }
+
+    /// Apply this patch to the given map, moving every object by the given transform first.
+    ///
+    /// Edits that fail are skipped, the rest is applied regardless.
+    pub fn apply(&self, map: &mut MapState, transform: &Transform) -> PatchOutcome {
+        let mut outcome = PatchOutcome::default();
+
+        // Objects are identified by their description, so attachments to transformed objects
+        // have to refer to the transformed ids.
+        let mut transformed_ids = HashMap::new();
+
+        for (index, edit) in self.edits.iter().enumerate() {
+            let transformed = transform.edit(edit).map(|mut transformed| {
+                let mut track = |object: &ObjectDesc, new: &mut ObjectDesc| {
+                    reattach(new, &transformed_ids);
+                    transformed_ids.insert(object.id(), new.id());
+                };
+
+                match (edit, &mut transformed) {
+                    (
+                        Edit::Place(object) | Edit::Remove(object),
+                        Edit::Place(new) | Edit::Remove(new),
+                    ) => track(object, new),
+                    (
+                        Edit::Modify { old, new },
+                        Edit::Modify {
+                            old: transformed_old,
+                            new: transformed_new,
+                        },
+                    ) => {
+                        track(old, transformed_old.as_mut());
+                        track(new, transformed_new.as_mut());
+                    }
+                    _ => {}
+                }
+
+                transformed
+            });
+
+            let reason = match transformed {
+                Some(edit) => match map.rejection(&edit) {
+                    Some(rejection) => PatchFailureReason::Rejected(rejection),
+                    None => {
+                        map.apply(edit.clone());
+                        outcome.applied.push((index, edit));
+                        continue;
+                    }
+                },
+                None => PatchFailureReason::OutOfRange,
+            };
+
+            outcome.failures.push(PatchFailure {
+                index,
+                edit: edit.clone(),
+                reason,
+            });
+        }
+
+        outcome
+    }
+}
+
+/// Attach the given item to the transformed object it was attached to before transforming it.
+fn reattach(object: &mut ObjectDesc, transformed_ids: &HashMap<ObjectId, ObjectId>) {
+    if let Some(&object_id) = object.attached_to().and_then(|id| transformed_ids.get(&id)) {
+        object.reattach(object_id);
+    }
+}
+
+#[derive(Default)]
+pub struct PatchOutcome {
+    /// Transformed edits that were applied, with their index in the patch.
+    pub applied: Vec<(usize, Edit)>,
+    pub failures: Vec<PatchFailure>,
+}
+
+/// An edit of a patch that could not be applied.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PatchFailure {
+    /// Index of the edit in the patch.
+    pub index: usize,
+    /// The edit as recorded in the patch, before transforming it.
+    pub edit: Edit,
+    pub reason: PatchFailureReason,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum PatchFailureReason {
+    /// The transformed object lies outside of the block grid.
+    OutOfRange,
+    Rejected(EditRejection),
+    /// The server did not accept the edit, for example because another user changed the map in
+    /// the meantime.
+    NotAccepted,
+}
+
+impl Display for PatchFailureReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::OutOfRange => write!(f, "object would be outside of the block grid"),
+            Self::Rejected(rejection) => write!(f, "{rejection}"),
+            Self::NotAccepted => write!(f, "edit was not accepted by the server"),
+        }
+    }
+}
+
+/// Sizes in block coordinates of blocks larger than a single coordinate when facing north, by the
+/// id of their game block or the hex hash of their custom block.
+///
+/// Block descriptions do not include their size, so blocks not listed are taken to cover a single
+/// coordinate.
+pub type BlockSizes = HashMap<String, Vec3<u8>>;
+
+/// Movement of the objects of a patch.
+///
+/// Objects are first rotated around the pivot and then moved by the offset. Blocks are anchored at
+/// the smallest coordinate they cover, so the anchor of a rotated block is the smallest coordinate
+/// of its rotated footprint.
+#[derive(Clone)]
+pub struct Transform {
+    /// Offset in block coordinates.
+    pub offset: Vec3<i32>,
+    /// Number of quarter turns around the vertical axis, in the order north, east, south, west.
+    pub quarter_turns: u8,
+    /// Block coordinate whose smallest corner to rotate around, which is the point positions
+    /// rotate around as well.
+    pub pivot: Vec3<i32>,
+    pub block_sizes: BlockSizes,
+}
+
+impl Transform {
+    pub fn is_identity(&self) -> bool {
+        self.offset == Vec3 { x: 0, y: 0, z: 0 } && self.quarter_turns.is_multiple_of(4)
+    }
+
+    /// The given edit with its object transformed, or `None` if that is outside of the block grid.
+    pub fn edit(&self, edit: &Edit) -> Option<Edit> {
+        match edit {
+            Edit::Place(object) => Some(Edit::Place(self.object(object)?)),
+            Edit::Remove(object) => Some(Edit::Remove(self.object(object)?)),
+            Edit::Modify { old, new } => Some(Edit::Modify {
+                old: Box::new(self.object(old)?),
+                new: Box::new(self.object(new)?),
+            }),
+            Edit::AddOffzone(offzone) => Some(Edit::AddOffzone(self.offzone(offzone)?)),
+            Edit::RemoveOffzone(offzone) => Some(Edit::RemoveOffzone(self.offzone(offzone)?)),
+            Edit::AddCustomBlock(_)
+            | Edit::AddCustomItem(_)
+            | Edit::AddCustomSkin(_)
+            | Edit::AddEmbeddedFile(_) => Some(edit.clone()),
+        }
+    }
+
+    /// The given offzone box with both corners transformed, or `None` if that is outside of the
+    /// block grid.
+    pub fn offzone(&self, offzone: &OffzoneDesc) -> Option<OffzoneDesc> {
+        let a = self.coord(offzone.min)?;
+        let b = self.coord(offzone.max)?;
+
+        Some(OffzoneDesc {
+            min: Vec3 {
+                x: a.x.min(b.x),
+                y: a.y.min(b.y),
+                z: a.z.min(b.z),
+            },
+            max: Vec3 {
+                x: a.x.max(b.x),
+                y: a.y.max(b.y),
+                z: a.z.max(b.z),
+            },
+        })
+    }
+
+    pub fn object(&self, object: &ObjectDesc) -> Option<ObjectDesc> {
+        if self.is_identity() {
+            return Some(object.clone());
+        }
+
+        let mut object = object.clone();
+
+        match &mut object {
+            ObjectDesc::Block(block) => {
+                let size = self.footprint(&block.block_info_id, block.dir);
+
+                block.coord = self.block_coord(block.coord, size)?;
+                block.dir = self.direction(block.dir);
+            }
+            ObjectDesc::GhostBlock(ghost_block) => {
+                let size = self.footprint(&ghost_block.block_info_id, ghost_block.dir);
+
+                ghost_block.coord = self.block_coord(ghost_block.coord, size)?;
+                ghost_block.dir = self.direction(ghost_block.dir);
+            }
+            ObjectDesc::FreeBlock(free_block) => {
+                free_block.position = self.position(free_block.position)?;
+                free_block.yaw = self.yaw(free_block.yaw)?;
+            }
+            ObjectDesc::Item(item) => {
+                item.position = self.position(item.position)?;
+                item.yaw = self.yaw(item.yaw)?;
+            }
+        }
+
+        Some(object)
+    }
+
+    /// Horizontal size of a block with the given model facing in the given direction.
+    fn footprint(&self, model_id: &ModelId, dir: Direction) -> (i32, i32) {
+        let key = match model_id {
+            ModelId::Game { id } => id.clone(),
+            ModelId::Custom { hash } => hash.to_hex().to_string(),
+        };
+
+        let size = self
+            .block_sizes
+            .get(&key)
+            .map_or((1, 1), |size| (size.x as i32, size.z as i32));
+
+        match dir {
+            Direction::North | Direction::South => size,
+            Direction::East | Direction::West => (size.1, size.0),
+        }
+    }
+
+    fn coord(&self, coord: Vec3<u8>) -> Option<Vec3<u8>> {
+        self.block_coord(coord, (1, 1))
+    }
+
+    /// Anchor of the footprint of the given size at the given anchor, after transforming it.
+    fn block_coord(&self, coord: Vec3<u8>, (width, depth): (i32, i32)) -> Option<Vec3<u8>> {
+        let x = coord.x as i32 - self.pivot.x;
+        let z = coord.z as i32 - self.pivot.z;
+
+        // Turning the corners of the footprint around the corner of the pivot keeps it on the grid.
+        let (x0, z0) = self.rotate(x, z);
+        let (x1, z1) = self.rotate(x + width, z + depth);
+
+        Some(Vec3 {
+            x: u8::try_from(self.pivot.x + x0.min(x1) + self.offset.x).ok()?,
+            y: u8::try_from(coord.y as i32 + self.offset.y).ok()?,
+            z: u8::try_from(self.pivot.z + z0.min(z1) + self.offset.z).ok()?,
+        })
+    }
+
+    fn position(&self, position: Vec3<NotNan<f32>>) -> Option<Vec3<NotNan<f32>>> {
+        let pivot_x = self.pivot.x as f32 * BLOCK_SIZE.x;
+        let pivot_z = self.pivot.z as f32 * BLOCK_SIZE.z;
+
+        let (x, z) = self.rotate(
+            position.x.into_inner() - pivot_x,
+            position.z.into_inner() - pivot_z,
+        );
+
+        Some(Vec3 {
+            x: NotNan::new(pivot_x + x + self.offset.x as f32 * BLOCK_SIZE.x).ok()?,
+            y: NotNan::new(position.y.into_inner() + self.offset.y as f32 * BLOCK_SIZE.y).ok()?,
+            z: NotNan::new(pivot_z + z + self.offset.z as f32 * BLOCK_SIZE.z).ok()?,
+        })
+    }
+
+    fn rotate<T: Copy + Neg<Output = T>>(&self, mut x: T, mut z: T) -> (T, T) {
+        for _ in 0..self.quarter_turns % 4 {
+            (x, z) = (z, -x);
+        }
+
+        (x, z)
+    }
+
+    fn direction(&self, mut dir: Direction) -> Direction {
+        for _ in 0..self.quarter_turns % 4 {
+            dir = match dir {
+                Direction::North => Direction::East,
+                Direction::East => Direction::South,
+                Direction::South => Direction::West,
+                Direction::West => Direction::North,
+            };
+        }
+
+        dir
+    }
+
+    fn yaw(&self, yaw: NotNan<f32>) -> Option<NotNan<f32>> {
+        NotNan::new(yaw.into_inner() + (self.quarter_turns % 4) as f32 * FRAC_PI_2).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{block, item_on, map, recoloured},
+        MapDesc,
+    };
+
+    use super::*;
+
+    fn transform(offset: (i32, i32), quarter_turns: u8, pivot: (i32, i32)) -> Transform {
+        Transform {
+            offset: Vec3 {
+                x: offset.0,
+                y: 0,
+                z: offset.1,
+            },
+            quarter_turns,
+            pivot: Vec3 {
+                x: pivot.0,
+                y: 0,
+                z: pivot.1,
+            },
+            block_sizes: BlockSizes::new(),
+        }
+    }
+
+    fn offset(x: i32, z: i32) -> Transform {
+        transform((x, z), 0, (0, 0))
+    }
+
+    #[test]
+    fn patch_from_diff_turns_the_old_map_into_the_new_map() {
+        let old = map([block(1, 1), block(5, 5)]);
+        let new = map([block(2, 1), item_on(&block(2, 1)), block(7, 7)]);
+
+        let mut map = MapState::from_desc(old.clone());
+        let outcome = Patch::from_diff(&MapDiff::new(&old, &new)).apply(&mut map, &offset(0, 0));
+
+        assert!(outcome.failures.is_empty());
+        assert!(map == MapState::from_desc(new));
+    }
+
+    #[test]
+    fn attached_items_follow_their_transformed_object() {
+        let patch = Patch {
+            edits: vec![Edit::Place(block(1, 1)), Edit::Place(item_on(&block(1, 1)))],
+        };
+
+        let mut patched = MapState::from_desc(MapDesc::default());
+        let outcome = patch.apply(&mut patched, &offset(3, 2));
+
+        assert!(outcome.failures.is_empty());
+        assert!(patched == MapState::from_desc(map([block(4, 3), item_on(&block(4, 3))])));
+    }
+
+    #[test]
+    fn modified_objects_keep_their_transformed_items() {
+        let patch = Patch {
+            edits: vec![
+                Edit::Place(block(1, 1)),
+                Edit::Place(item_on(&block(1, 1))),
+                Edit::Modify {
+                    old: Box::new(block(1, 1)),
+                    new: Box::new(recoloured(block(1, 1))),
+                },
+            ],
+        };
+
+        let mut patched = MapState::from_desc(MapDesc::default());
+        let outcome = patch.apply(&mut patched, &offset(3, 2));
+
+        let expected = map([recoloured(block(4, 3)), item_on(&recoloured(block(4, 3)))]);
+
+        assert!(outcome.failures.is_empty());
+        assert!(patched == MapState::from_desc(expected));
+    }
+
+    #[test]
+    fn failing_edits_are_reported_and_skipped() {
+        let patch = Patch {
+            edits: vec![
+                Edit::Remove(block(3, 3)),
+                Edit::Place(block(1, 1)),
+                Edit::Place(block(2, 2)),
+            ],
+        };
+
+        let mut map = MapState::from_desc(map([block(0, 2)]));
+        let outcome = patch.apply(&mut map, &offset(-2, 0));
+
+        let failures: Vec<_> = outcome
+            .failures
+            .iter()
+            .map(|failure| (failure.index, failure.reason))
+            .collect();
+
+        assert_eq!(
+            failures,
+            [
+                (
+                    0,
+                    PatchFailureReason::Rejected(EditRejection::ObjectNotFound)
+                ),
+                (1, PatchFailureReason::OutOfRange),
+                (2, PatchFailureReason::Rejected(EditRejection::ObjectExists)),
+            ]
+        );
+        assert!(outcome.applied.is_empty());
+    }
+
+    /// Item attached to the given block, at the centre of the coordinate with the given offset to
+    /// the anchor of the block.
+    fn item_in(block: &ObjectDesc, x: f32, z: f32) -> ObjectDesc {
+        let mut item = item_on(block);
+
+        if let ObjectDesc::Item(item) = &mut item {
+            item.position.x += (x + 0.5) * BLOCK_SIZE.x;
+            item.position.z += (z + 0.5) * BLOCK_SIZE.z;
+        }
+
+        item
+    }
+
+    /// Whether the item of the given map lies within the footprint of its block.
+    fn item_is_on_block(map: &MapState, block_size: (u8, u8)) -> bool {
+        let map_desc = map.to_desc();
+
+        let ([block], [item]) = (&map_desc.blocks[..], &map_desc.items[..]) else {
+            panic!("map has a single block and item");
+        };
+
+        let (width, depth) = match block.dir {
+            Direction::North | Direction::South => block_size,
+            Direction::East | Direction::West => (block_size.1, block_size.0),
+        };
+
+        let block = ObjectDesc::Block(block.clone());
+        let item = ObjectDesc::Item(item.clone());
+
+        let min = block.position();
+        let position = item.position();
+
+        item.attached_to() == Some(block.id())
+            && (min.x..min.x + width as f32 * BLOCK_SIZE.x).contains(&position.x)
+            && (min.z..min.z + depth as f32 * BLOCK_SIZE.z).contains(&position.z)
+    }
+
+    #[test]
+    fn blocks_and_their_items_turn_together() {
+        let block = block(4, 6);
+        let patch = Patch {
+            edits: vec![
+                Edit::Place(block.clone()),
+                Edit::Place(item_in(&block, 0.0, 0.0)),
+            ],
+        };
+
+        for pivot in [(0, 0), (4, 6), (7, 3)] {
+            for quarter_turns in 1..=4 {
+                let mut turned = MapState::from_desc(MapDesc::default());
+                let outcome = patch.apply(&mut turned, &transform((8, 8), quarter_turns, pivot));
+
+                assert!(outcome.failures.is_empty());
+                assert!(item_is_on_block(&turned, (1, 1)));
+            }
+        }
+
+        let mut turned = MapState::from_desc(MapDesc::default());
+        patch.apply(&mut turned, &transform((0, 0), 4, (7, 3)));
+
+        assert!(
+            turned
+                == MapState::from_desc(map(patch.edits.iter().map(|edit| match edit {
+                    Edit::Place(object) => object.clone(),
+                    _ => unreachable!(),
+                })))
+        );
+    }
+
+    #[test]
+    fn block_footprints_turn_by_their_size() {
+        let block = block(4, 6);
+        let patch = Patch {
+            edits: vec![
+                Edit::Place(block.clone()),
+                Edit::Place(item_in(&block, 2.0, 1.0)),
+            ],
+        };
+
+        let mut transform = transform((8, 8), 0, (5, 5));
+
+        transform
+            .block_sizes
+            .insert("RoadTechStraight".to_owned(), Vec3 { x: 3, y: 1, z: 2 });
+
+        for quarter_turns in 1..=4 {
+            transform.quarter_turns = quarter_turns;
+
+            let mut turned = MapState::from_desc(MapDesc::default());
+            let outcome = patch.apply(&mut turned, &transform);
+
+            assert!(outcome.failures.is_empty());
+            assert!(item_is_on_block(&turned, (3, 2)));
+        }
+    }
+
+    #[test]
+    fn offzones_turn_around_the_pivot_corner() {
+        let offzone = OffzoneDesc {
+            min: Vec3 { x: 2, y: 0, z: 2 },
+            max: Vec3 { x: 3, y: 0, z: 2 },
+        };
+
+        let turned = transform((0, 0), 1, (2, 2)).offzone(&offzone).unwrap();
+
+        // The two coordinates right of the pivot corner end up in front of it.
+        assert_eq!((turned.min.x, turned.min.z), (2, 0));
+        assert_eq!((turned.max.x, turned.max.z), (2, 1));
+    }
+}
@@ -0,0 +1,322 @@
+//! Recordings of the edits made to a map, which can be played back as a timelapse.
+//!
+//! A replay file starts with a magic number followed by records, each of which is a little-endian
+//! `u32` payload length and the payload. The first record is the [`ReplayHeader`], all others are
+//! [`ReplayRecord`]s. A record that is cut off marks the end of the file, so a recording that
+//! stopped abruptly can still be played.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{deserialize, serialize, Edit, MapDesc, MapParamsDesc, SerdeError};
+
+/// Start of every replay file, ending in the version of its layout.
+const MAGIC: &[u8; 8] = b"TMSEREP2";
+
+/// Map at the start of a recording.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub map_params_desc: MapParamsDesc,
+    pub map_desc: MapDesc,
+    /// Milliseconds since the Unix epoch at which the recording started.
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ReplayRecord {
+    /// Introduces a user, which is referred to by the number of users introduced before it.
+    Author(String),
+    Edit {
+        /// Milliseconds since the previous edit, or the start of the recording.
+        delay: u64,
+        author: u32,
+        edit: Edit,
+    },
+}
+
+/// Writer of a replay file.
+///
+/// User names are written once and referred to by index afterwards, which keeps recordings of long
+/// sessions small.
+pub struct ReplayWriter<W> {
+    writer: W,
+    authors: HashMap<String, u32>,
+    last_timestamp: u64,
+}
+
+impl<W: Write> ReplayWriter<W> {
+    /// Start a recording of the map in the given header.
+    pub fn new(mut writer: W, header: &ReplayHeader) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        write_record(&mut writer, header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            authors: HashMap::new(),
+            last_timestamp: header.timestamp,
+        })
+    }
+
+    /// Record an edit made by the given user at the given time in milliseconds since the Unix
+    /// epoch.
+    pub fn record(&mut self, timestamp: u64, author: &str, edit: &Edit) -> io::Result<()> {
+        let author = match self.authors.get(author) {
+            Some(&index) => index,
+            None => {
+                let index = self.authors.len() as u32;
+
+                write_record(&mut self.writer, &ReplayRecord::Author(author.to_owned()))?;
+                self.authors.insert(author.to_owned(), index);
+
+                index
+            }
+        };
+
+        write_record(
+            &mut self.writer,
+            &ReplayRecord::Edit {
+                delay: timestamp.saturating_sub(self.last_timestamp),
+                author,
+                edit: edit.clone(),
+            },
+        )?;
+
+        self.writer.flush()?;
+
+        self.last_timestamp = timestamp.max(self.last_timestamp);
+
+        Ok(())
+    }
+}
+
+/// A recorded edit.
+#[derive(Clone)]
+pub struct ReplayEdit {
+    /// Time since the previous edit, or the start of the recording.
+    pub delay: Duration,
+    pub author: String,
+    pub edit: Edit,
+}
+
+/// Contents of a replay file.
+#[derive(Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub edits: Vec<ReplayEdit>,
+}
+
+impl Replay {
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut payloads = bytes
+            .strip_prefix(MAGIC)
+            .map(decode_records)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a replay file"))?
+            .into_iter();
+
+        let header: ReplayHeader = payloads
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "replay has no header"))
+            .and_then(|payload| deserialize(payload).map_err(invalid_data))?;
+
+        let mut authors = vec![];
+        let mut edits = vec![];
+
+        for payload in payloads {
+            match deserialize(payload).map_err(invalid_data)? {
+                ReplayRecord::Author(author) => authors.push(author),
+                ReplayRecord::Edit {
+                    delay,
+                    author,
+                    edit,
+                } => {
+                    let author = authors.get(author as usize).cloned().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "unknown author")
+                    })?;
+
+                    edits.push(ReplayEdit {
+                        delay: Duration::from_millis(delay),
+                        author,
+                        edit,
+                    });
+                }
+            }
+        }
+
+        Ok(Self { header, edits })
+    }
+
+    /// Recorded time from the start of the recording to the last edit.
+    pub fn duration(&self) -> Duration {
+        self.edits.iter().map(|edit| edit.delay).sum()
+    }
+}
+
+/// How fast to play back a replay.
+#[derive(Clone, Copy)]
+pub struct PlaybackSpeed {
+    /// Factor by which playback is faster than the recording.
+    pub speed: f64,
+    /// Longest time to wait between two edits, so that breaks during the recording are skipped.
+    pub max_delay: Duration,
+}
+
+impl PlaybackSpeed {
+    /// Time to wait before playing back an edit with the given recorded delay.
+    pub fn delay(&self, recorded: Duration) -> Duration {
+        // Delays too long to represent are capped like any other long delay.
+        Duration::try_from_secs_f64(recorded.as_secs_f64() / self.speed)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+fn write_record(writer: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    let payload = serialize(value).map_err(invalid_data)?;
+
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)
+}
+
+/// Decode all consecutive complete records.
+fn decode_records(mut bytes: &[u8]) -> Vec<&[u8]> {
+    let mut records = vec![];
+
+    while let Some((len, rest)) = bytes.split_first_chunk::<4>() {
+        let Some((payload, rest)) = rest.split_at_checked(u32::from_le_bytes(*len) as usize) else {
+            break;
+        };
+
+        records.push(payload);
+        bytes = rest;
+    }
+
+    records
+}
+
+fn invalid_data(error: SerdeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{block, map},
+        MedalTimesDesc, Mood,
+    };
+
+    use super::*;
+
+    /// Replay of three edits by two users, with the lengths of the file after each record.
+    fn recording() -> (Vec<u8>, Vec<usize>) {
+        let header = ReplayHeader {
+            map_params_desc: MapParamsDesc {
+                mood: Mood::Night,
+                medal_times: MedalTimesDesc::default(),
+                validated: false,
+            },
+            map_desc: map([block(1, 1)]),
+            timestamp: 1000,
+        };
+
+        let mut replay_writer = ReplayWriter::new(vec![], &header).unwrap();
+        let mut lens = vec![replay_writer.writer.len()];
+
+        for (timestamp, author, edit) in [
+            (1500, "a", Edit::Place(block(2, 2))),
+            (1500, "b", Edit::Remove(block(1, 1))),
+            (4000, "a", Edit::Remove(block(2, 2))),
+        ] {
+            replay_writer.record(timestamp, author, &edit).unwrap();
+            lens.push(replay_writer.writer.len());
+        }
+
+        (replay_writer.writer, lens)
+    }
+
+    fn summary(replay: &Replay) -> Vec<(u64, &str, bool)> {
+        replay
+            .edits
+            .iter()
+            .map(|edit| {
+                let is_place = matches!(edit.edit, Edit::Place(_));
+
+                (
+                    edit.delay.as_millis() as u64,
+                    edit.author.as_str(),
+                    is_place,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recordings_read_back_as_recorded() {
+        let (bytes, _) = recording();
+        let replay = Replay::from_bytes(&bytes).unwrap();
+
+        assert_eq!(replay.header.timestamp, 1000);
+        assert_eq!(replay.header.map_desc.blocks.len(), 1);
+        assert_eq!(
+            summary(&replay),
+            [(500, "a", true), (0, "b", false), (2500, "a", false)]
+        );
+        assert!(matches!(&replay.edits[2].edit, Edit::Remove(object) if *object == block(2, 2)));
+        assert_eq!(replay.duration(), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn truncated_records_end_the_recording() {
+        let (bytes, lens) = recording();
+
+        // The last edit is cut off in the middle of its payload.
+        let replay = Replay::from_bytes(&bytes[..lens[3] - 1]).unwrap();
+
+        assert_eq!(summary(&replay), [(500, "a", true), (0, "b", false)]);
+
+        // Only half of the length of the record after the header made it.
+        let replay = Replay::from_bytes(&bytes[..lens[0] + 2]).unwrap();
+
+        assert!(replay.edits.is_empty());
+    }
+
+    #[test]
+    fn files_without_a_header_are_refused() {
+        let (bytes, lens) = recording();
+
+        assert!(Replay::from_bytes(&bytes[..lens[0] - 1]).is_err());
+        assert!(Replay::from_bytes(b"TMSEROOM").is_err());
+    }
+
+    #[test]
+    fn tiny_speeds_wait_the_longest_delay() {
+        let speed = PlaybackSpeed {
+            speed: f64::MIN_POSITIVE,
+            max_delay: Duration::from_secs(2),
+        };
+
+        assert_eq!(speed.delay(Duration::from_secs(1)), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn playback_is_sped_up_and_breaks_are_skipped() {
+        let speed = PlaybackSpeed {
+            speed: 4.0,
+            max_delay: Duration::from_secs(2),
+        };
+
+        assert_eq!(speed.delay(Duration::from_secs(4)), Duration::from_secs(1));
+        assert_eq!(speed.delay(Duration::from_secs(60)), Duration::from_secs(2));
+        assert_eq!(speed.delay(Duration::ZERO), Duration::ZERO);
+    }
+}
@@ -0,0 +1,96 @@
+//! Objects and maps shared by unit tests.
+
+use gamebox::{
+    engines::game::map::{Direction, ElemColor, PhaseOffset},
+    Vec3,
+};
+
+use crate::{
+    map::MapState, AttachmentDesc, BlockDesc, Edit, ItemDesc, MapDesc, ModelId, NotNan, ObjectDesc,
+    OffzoneDesc,
+};
+
+pub fn block(x: u8, z: u8) -> ObjectDesc {
+    ObjectDesc::Block(BlockDesc {
+        block_info_id: ModelId::Game {
+            id: "RoadTechStraight".to_owned(),
+        },
+        coord: Vec3 { x, y: 9, z },
+        dir: Direction::North,
+        is_air_variant: false,
+        variant_index: 0,
+        subvariant_index: 0,
+        elem_color: ElemColor::Default,
+        waypoint: None,
+        skin: None,
+    })
+}
+
+/// Item standing at the position of the given object, attached to it.
+pub fn item_on(object: &ObjectDesc) -> ObjectDesc {
+    let position = object.position();
+    let position = Vec3 {
+        x: NotNan::new(position.x).unwrap(),
+        y: NotNan::new(position.y).unwrap(),
+        z: NotNan::new(position.z).unwrap(),
+    };
+
+    ObjectDesc::Item(ItemDesc {
+        item_model_id: ModelId::Game {
+            id: "Flag".to_owned(),
+        },
+        position,
+        yaw: NotNan::default(),
+        pitch: NotNan::default(),
+        roll: NotNan::default(),
+        pivot_position: Vec3 {
+            x: NotNan::default(),
+            y: NotNan::default(),
+            z: NotNan::default(),
+        },
+        elem_color: ElemColor::Default,
+        anim_offset: PhaseOffset::None,
+        waypoint: None,
+        skin: None,
+        attachment: Some(AttachmentDesc {
+            object_id: object.id(),
+        }),
+    })
+}
+
+pub fn offzone(min: (u8, u8, u8), max: (u8, u8, u8)) -> OffzoneDesc {
+    OffzoneDesc {
+        min: Vec3 {
+            x: min.0,
+            y: min.1,
+            z: min.2,
+        },
+        max: Vec3 {
+            x: max.0,
+            y: max.1,
+            z: max.2,
+        },
+    }
+}
+
+/// Map of the given objects, placed in order.
+pub fn map(objects: impl IntoIterator<Item = ObjectDesc>) -> MapDesc {
+    let mut map = MapState::from_desc(MapDesc::default());
+
+    for object in objects {
+        assert!(map.apply(Edit::Place(object)), "object is placeable");
+    }
+
+    map.to_desc()
+}
+
+pub fn recoloured(mut object: ObjectDesc) -> ObjectDesc {
+    match &mut object {
+        ObjectDesc::Block(block) => block.elem_color = ElemColor::Red,
+        ObjectDesc::GhostBlock(ghost_block) => ghost_block.elem_color = ElemColor::Red,
+        ObjectDesc::FreeBlock(free_block) => free_block.elem_color = ElemColor::Red,
+        ObjectDesc::Item(item) => item.elem_color = ElemColor::Red,
+    }
+
+    object
+}